@@ -0,0 +1,147 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use bluenote_bluetooth::{
+    error::Error,
+    sync::{
+        auth::{PairingKey, PairingKeys},
+        server::SyncService,
+    },
+    Result,
+};
+use serde_json::Value;
+
+/// 同期の初回 (`now` を指定しなかった場合) に使う時刻
+const DEFAULT_NOW: &str = "Sat, 01 Jan 2000 00:00:00 GMT";
+
+/// モックの同期相手が持つデータ
+///
+/// ```json
+/// {
+///   "uuid": "自身のデバイス ID",
+///   "companions": ["同期を許可するデバイス ID", ...],
+///   "pairingKeys": { "デバイス ID": "ペアリングの鍵 (16 進数、省略可)", ... },
+///   "revoked": ["信頼を取り消したデバイス ID (省略可)", ...],
+///   "now": "同期の終了時刻として返す時刻 (省略可)",
+///   "threads": [{ "id": "...", "name": "...", ... }],
+///   "notes": [{ "id": "...", "threadId": "...", "parentId": null, ... }]
+/// }
+/// ```
+///
+/// スレッドとメモはアプリの JSON と同じ形式で、そのまま返す
+/// 同期するたびに、初めて同期する相手としてすべての更新を返す
+pub struct Fixture {
+    pub uuid: String,
+    pub companions: Vec<String>,
+    pub pairing_keys: PairingKeys,
+    revoked: Vec<String>,
+    now: String,
+    threads: Vec<Value>,
+    notes: Vec<Value>,
+}
+
+impl Fixture {
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        let value: Value = serde_json::from_str(&json)
+            .map_err(|e| Error::InvalidArgument(format!("{}: {}", path.display(), e)))?;
+
+        let string = |key: &str| value[key].as_str().map(|v| v.to_owned());
+        let array = |key: &str| value[key].as_array().cloned().unwrap_or_default();
+
+        let uuid = string("uuid")
+            .ok_or_else(|| Error::InvalidArgument("`uuid` is required".to_owned()))?;
+        let strings = |key: &str| {
+            array(key)
+                .iter()
+                .filter_map(|v| v.as_str().map(|v| v.to_owned()))
+                .collect()
+        };
+        let companions = strings("companions");
+        let pairing_keys = value["pairingKeys"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter_map(|(uuid, key)| Some((uuid, key.as_str()?)))
+            .map(|(uuid, key)| Ok((uuid.clone(), PairingKey::from_hex(key)?)))
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            uuid,
+            companions,
+            pairing_keys,
+            revoked: strings("revoked"),
+            now: string("now").unwrap_or_else(|| DEFAULT_NOW.to_owned()),
+            threads: array("threads"),
+            notes: array("notes"),
+        })
+    }
+
+    /// 条件に合うメモの JSON
+    fn notes_json(&self, filter: impl Fn(&Value) -> bool) -> String {
+        let notes: Vec<&Value> = self.notes.iter().filter(|note| filter(note)).collect();
+        serde_json::to_string(&notes).unwrap()
+    }
+}
+
+fn is_deleted(note: &Value) -> bool {
+    note["deleted"].as_bool().unwrap_or(false)
+}
+
+#[async_trait(?Send)]
+impl SyncService for Fixture {
+    async fn is_sync_allowed(&self, uuid: &str) -> Result<bool> {
+        Ok(self.companions.iter().any(|v| v == uuid))
+    }
+
+    async fn get_my_uuid(&self) -> Result<String> {
+        Ok(self.uuid.clone())
+    }
+
+    async fn is_revoked(&self, uuid: &str) -> Result<bool> {
+        Ok(self.revoked.iter().any(|v| v == uuid))
+    }
+
+    async fn get_pairing_key(&self, uuid: &str) -> Result<Option<PairingKey>> {
+        Ok(self.pairing_keys.get(uuid).cloned())
+    }
+
+    async fn now(&self) -> Result<String> {
+        Ok(self.now.clone())
+    }
+
+    async fn get_thread_updates(&self, _uuid: &str, _updated_end: &str) -> Result<String> {
+        Ok(serde_json::to_string(&self.threads).unwrap())
+    }
+
+    async fn get_all_notes_in_thread(&self, thread_id: &str) -> Result<String> {
+        Ok(self.notes_json(|note| !is_deleted(note) && note["threadId"] == thread_id))
+    }
+
+    async fn get_all_notes_in_tree(&self, parent_id: &str) -> Result<String> {
+        Ok(self.notes_json(|note| !is_deleted(note) && note["parentId"] == parent_id))
+    }
+
+    async fn get_note_updates_in_thread(
+        &self,
+        _uuid: &str,
+        thread_id: &str,
+        _updated_end: &str,
+    ) -> Result<String> {
+        Ok(self.notes_json(|note| note["threadId"] == thread_id && note["parentId"].is_null()))
+    }
+
+    async fn get_note_updates_in_tree(
+        &self,
+        _uuid: &str,
+        parent_id: &str,
+        _updated_end: &str,
+    ) -> Result<String> {
+        Ok(self.notes_json(|note| note["parentId"] == parent_id))
+    }
+
+    async fn update_synced_at(&self, uuid: &str, updated_end: &str) -> Result<()> {
+        println!("Synced with {} at {}", uuid, updated_end);
+        Ok(())
+    }
+}
@@ -0,0 +1,306 @@
+//! Bluenote の同期相手 (Android アプリ) の代わりをするモック
+//!
+//! JSON のフィクスチャのデータを使って、同期サーバもしくは同期クライアントとして動く
+//! デスクトップアプリが表示したワンタイムトークンのコードを使って、TCP で初期設定することもできる
+//! スマホなしでデスクトップアプリの同期を再現するために使う
+
+mod fixture;
+
+use std::{collections::VecDeque, path::PathBuf, process::ExitCode};
+
+use bluenote_bluetooth::{
+    error::Error,
+    init::{self, Hello},
+    sync::{
+        client::ClientSession,
+        handshake::Capabilities,
+        server::{serve_transport, SyncService},
+        session::SESSIONS,
+        tcp,
+        transport::Transport,
+        Request,
+    },
+    Result,
+};
+use fixture::Fixture;
+use serde_json::Value;
+
+const USAGE: &str = "\
+Usage: bluenote-peer <server|client|pair> --fixture <path> (--tcp <address> | --unix <path>) [options]
+
+Roles:
+  server    Accept sync connections and answer requests from the fixture
+  client    Connect to a sync server, fetch all updates and print them as JSON
+  pair      Connect to a TCP init server with a pairing code and print the pairing key as JSON
+
+Options:
+  --fixture <path>   JSON fixture of the peer's device, threads and notes
+  --tcp <address>    Listen on / connect to a TCP address (host:port)
+  --unix <path>      Listen on / connect to a Unix domain socket
+  --output <path>    (client) Write the fetched data to a file instead of stdout
+  --fail             (client) Report the sync as failed when finishing
+  --plain            Offer no optional features (uncompressed JSON, like older peers)
+  --code <code>      (pair) Pairing code shown by the desktop app";
+
+/// 同期サーバとして動くか、クライアントとして動くか、初期設定をするか
+enum Role {
+    Server,
+    Client,
+    Pair,
+}
+
+/// 接続先 (待ち受け先)
+enum Address {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+struct Options {
+    role: Role,
+    address: Address,
+    fixture: PathBuf,
+    output: Option<PathBuf>,
+    fail: bool,
+    capabilities: Capabilities,
+    code: Option<String>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> std::result::Result<Options, String> {
+    let role = match args.next().as_deref() {
+        Some("server") => Role::Server,
+        Some("client") => Role::Client,
+        Some("pair") => Role::Pair,
+        Some(role) => return Err(format!("Unknown role: {}", role)),
+        None => return Err("Role is required".to_owned()),
+    };
+
+    let mut address = None;
+    let mut fixture = None;
+    let mut output = None;
+    let mut fail = false;
+    let mut capabilities = Capabilities::ALL;
+    let mut code = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value", arg));
+
+        match arg.as_str() {
+            "--tcp" => address = Some(Address::Tcp(value()?)),
+            #[cfg(unix)]
+            "--unix" => address = Some(Address::Unix(value()?.into())),
+            "--fixture" => fixture = Some(PathBuf::from(value()?)),
+            "--output" => output = Some(PathBuf::from(value()?)),
+            "--fail" => fail = true,
+            "--plain" => capabilities = Capabilities::empty(),
+            "--code" => code = Some(value()?),
+            _ => return Err(format!("Unknown option: {}", arg)),
+        }
+    }
+
+    if matches!(role, Role::Pair) && code.is_none() {
+        return Err("--code is required for pair".to_owned());
+    }
+
+    Ok(Options {
+        role,
+        address: address.ok_or("--tcp or --unix is required")?,
+        fixture: fixture.ok_or("--fixture is required")?,
+        output,
+        fail,
+        capabilities,
+        code,
+    })
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    match runtime.block_on(run(options)) {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(options: Options) -> Result<()> {
+    let fixture = Fixture::load(&options.fixture)?;
+    let capabilities = options.capabilities;
+
+    match (options.role, options.address) {
+        (Role::Pair, Address::Tcp(address)) => {
+            pair(
+                &address,
+                &fixture,
+                options.code.as_deref().unwrap_or_default(),
+            )
+            .await
+        }
+        #[cfg(unix)]
+        (Role::Pair, Address::Unix(_)) => Err(Error::InvalidArgument(
+            "pair supports --tcp only".to_owned(),
+        )),
+        (Role::Server, Address::Tcp(address)) => {
+            let listener = tcp::listen(&address).await?;
+
+            loop {
+                let (stream, _) = listener.accept().await?;
+                serve(stream, &fixture, capabilities).await;
+            }
+        }
+        #[cfg(unix)]
+        (Role::Server, Address::Unix(path)) => {
+            let listener = bluenote_bluetooth::sync::unix::listen(&path)?;
+
+            loop {
+                let (stream, _) = listener.accept().await?;
+                serve(stream, &fixture, capabilities).await;
+            }
+        }
+        (Role::Client, Address::Tcp(address)) => {
+            let stream = tcp::connect(&address).await?;
+            sync(
+                stream,
+                &fixture,
+                &options.output,
+                !options.fail,
+                capabilities,
+            )
+            .await
+        }
+        #[cfg(unix)]
+        (Role::Client, Address::Unix(path)) => {
+            let stream = bluenote_bluetooth::sync::unix::connect(&path).await?;
+            sync(
+                stream,
+                &fixture,
+                &options.output,
+                !options.fail,
+                capabilities,
+            )
+            .await
+        }
+    }
+}
+
+/// 同期サーバとして、接続してきた相手と同期する
+/// 結果を確認しやすいように、一度に一つの接続だけを処理する
+async fn serve<T, S>(transport: T, sync_service: &S, capabilities: Capabilities)
+where
+    T: Transport,
+    S: SyncService,
+{
+    let (session, _close_requested) = SESSIONS.register(transport.peer_name());
+
+    match serve_transport(transport, sync_service, &session, capabilities).await {
+        Ok(_) => println!("Connection closed."),
+        Err(e) => println!("Sync failed: {}", e),
+    }
+}
+
+/// 同期クライアントとして、相手の更新をすべて取得して出力する
+async fn sync<T>(
+    transport: T,
+    fixture: &Fixture,
+    output: &Option<PathBuf>,
+    success: bool,
+    capabilities: Capabilities,
+) -> Result<()>
+where
+    T: Transport,
+{
+    let session = ClientSession::begin(
+        transport,
+        &fixture.uuid,
+        &fixture.companions,
+        &fixture.pairing_keys,
+        capabilities,
+        None,
+    )
+    .await?;
+
+    let fetched = fetch_updates(&session).await;
+    session.end(success && fetched.is_ok()).await?;
+
+    let (threads, notes) = fetched?;
+    let json = serde_json::to_string_pretty(&serde_json::json!({
+        "threads": threads,
+        "notes": notes,
+    }))
+    .unwrap();
+
+    match output {
+        Some(path) => std::fs::write(path, json)?,
+        None => println!("{}", json),
+    }
+
+    Ok(())
+}
+
+/// デスクトップアプリの初期設定の受付に接続し、共有した鍵を出力する
+/// 出力した鍵をフィクスチャの `pairingKeys` に書けば、認証して同期できる
+async fn pair(address: &str, fixture: &Fixture, code: &str) -> Result<()> {
+    let my_hello = Hello::local("bluenote-peer", env!("CARGO_PKG_VERSION"));
+    let paired = init::tcp::connect(address, &fixture.uuid, &my_hello, code).await?;
+
+    let json = serde_json::to_string_pretty(&serde_json::json!({
+        "uuid": paired.uuid,
+        "name": paired.hello.map(|hello| hello.display_name),
+        "fingerprint": paired.pairing_key.as_ref().map(|key| key.fingerprint()),
+        "pairingKey": paired.pairing_key.as_ref().map(|key| key.to_hex()),
+    }))
+    .unwrap();
+    println!("{}", json);
+
+    Ok(())
+}
+
+/// スレッドの更新分と、その中のメモの更新分を (ツリーをたどって) すべて取得する
+async fn fetch_updates(session: &ClientSession) -> Result<(Vec<Value>, Vec<Value>)> {
+    let threads = parse(session.request(&Request::ThreadUpdates).await?)?;
+    let mut notes = Vec::new();
+
+    for thread in &threads {
+        let request = Request::NoteUpdatesInThread {
+            thread_id: id_of(thread)?,
+        };
+        let mut queue: VecDeque<Value> = parse(session.request(&request).await?)?.into();
+
+        while let Some(note) = queue.pop_front() {
+            let request = Request::NoteUpdatesInTree {
+                parent_id: id_of(&note)?,
+            };
+            queue.extend(parse(session.request(&request).await?)?);
+            notes.push(note);
+        }
+    }
+
+    Ok((threads, notes))
+}
+
+fn parse(json: String) -> Result<Vec<Value>> {
+    serde_json::from_str(&json).map_err(|e| Error::ProtocolViolation(format!("{}", e)))
+}
+
+fn id_of(value: &Value) -> Result<String> {
+    match value["id"].as_str() {
+        Some(id) => Ok(id.to_owned()),
+        None => Err(Error::ProtocolViolation(format!(
+            "`id` not found: {}",
+            value
+        ))),
+    }
+}
@@ -1,58 +1,212 @@
+use napi::{Env, JsObject, JsUnknown};
+
 /// Bluenote Error
+///
+/// JavaScript には `code` (と種類ごとの追加の情報) をプロパティにもつ Error オブジェクトとして渡す
 #[derive(Debug)]
 pub enum Error {
-    WindowsError(windows::core::Error),
-    IOError(tokio::io::Error),
-    TimeoutError(tokio::time::error::Elapsed),
-    SyncError(String),
+    #[cfg(windows)]
+    Windows(windows::core::Error),
+    Io(tokio::io::Error),
+    /// 同期相手が見つからない、もしくは接続できない
+    PeerNotFound(String),
+    /// 同期を拒否された (相手に拒否された場合と、こちらで同期が有効でない場合)
+    SyncRejected(String),
+    /// 相手がペアリングした (鍵を共有した) デバイスであることを確認できなかった
+    AuthenticationFailed(String),
+    /// 信頼を取り消したデバイスとは同期しない
+    DeviceRevoked(String),
+    /// 同期プロトコルのバージョンが相手と異なる
+    VersionMismatch {
+        ours: u8,
+        theirs: u8,
+    },
+    /// 処理がタイムアウトした
+    /// `phase` はタイムアウトした処理の名前
+    Timeout {
+        phase: &'static str,
+    },
+    /// 相手から不正なデータが送られてきた
+    ProtocolViolation(String),
+    /// 相手がリクエストの処理に失敗した (接続は続いている)
+    /// `code` は相手側でのエラーコード
+    RequestFailed {
+        code: String,
+        message: String,
+        retryable: bool,
+    },
+    /// 接続が切れた
+    Disconnected,
+    /// リクエストが取り消された (`AbortSignal` や、相手からの取り消し)
+    Cancelled,
+    /// 引数が不正
+    InvalidArgument(String),
+    /// その他のエラー
+    Other(String),
 }
 
+impl Error {
+    /// JavaScript に渡すエラーコード
+    pub fn code(&self) -> &'static str {
+        match self {
+            #[cfg(windows)]
+            Self::Windows(_) => "WINDOWS",
+            Self::Io(_) => "IO",
+            Self::PeerNotFound(_) => "PEER_NOT_FOUND",
+            Self::SyncRejected(_) => "SYNC_REJECTED",
+            Self::AuthenticationFailed(_) => "AUTHENTICATION_FAILED",
+            Self::DeviceRevoked(_) => "DEVICE_REVOKED",
+            Self::VersionMismatch { .. } => "VERSION_MISMATCH",
+            Self::Timeout { .. } => "TIMEOUT",
+            Self::ProtocolViolation(_) => "PROTOCOL_VIOLATION",
+            Self::RequestFailed { .. } => "REQUEST_FAILED",
+            Self::Disconnected => "DISCONNECTED",
+            Self::Cancelled => "CANCELLED",
+            Self::InvalidArgument(_) => "INVALID_ARGUMENT",
+            Self::Other(_) => "OTHER",
+        }
+    }
+
+    /// 時間をおいてやり直せば成功する見込みがあるか
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::PeerNotFound(_) | Self::Timeout { .. } | Self::Disconnected => true,
+            Self::RequestFailed { retryable, .. } => *retryable,
+            _ => false,
+        }
+    }
+
+    /// 通信路が切れたことによるエラーか (同期を再開すれば続けられる)
+    /// 復号の失敗や大きすぎるフレームなど、不正なデータによるエラー (`InvalidData` など) は含めない
+    pub fn is_connection_lost(&self) -> bool {
+        use tokio::io::ErrorKind;
+
+        match self {
+            Self::Disconnected | Self::Timeout { phase: "idle" } => true,
+            Self::Io(e) => matches!(
+                e.kind(),
+                ErrorKind::UnexpectedEof
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+            ),
+            _ => false,
+        }
+    }
+
+    /// JavaScript の Error オブジェクトを作成し、`napi::Error` に包んで返す
+    /// `Task::resolve` でこれを返すと、作成した Error オブジェクトで reject される
+    pub fn into_js_error(self, env: Env) -> napi::Error {
+        match self.create_js_error(env) {
+            Ok(error) => napi::Error::from(error),
+            Err(e) => e,
+        }
+    }
+
+    /// JavaScript の Error オブジェクトを作成する (コールバックの引数に渡すときに使う)
+    pub(crate) fn create_js_error(&self, env: Env) -> napi::Result<JsUnknown> {
+        let mut error: JsObject = env.create_error(napi::Error::from_reason(self.to_string()))?;
+
+        error.set_named_property("code", env.create_string(self.code())?)?;
+        error.set_named_property("retryable", env.get_boolean(self.is_retryable())?)?;
+
+        match self {
+            Self::VersionMismatch { ours, theirs } => {
+                error.set_named_property("ours", env.create_uint32(*ours as u32)?)?;
+                error.set_named_property("theirs", env.create_uint32(*theirs as u32)?)?;
+            }
+            Self::Timeout { phase } => {
+                error.set_named_property("phase", env.create_string(phase)?)?;
+            }
+            Self::RequestFailed { code, .. } => {
+                error.set_named_property("remoteCode", env.create_string(code)?)?;
+            }
+            _ => {}
+        }
+
+        Ok(error.into_unknown())
+    }
+}
+
+#[cfg(windows)]
 impl From<windows::core::Error> for Error {
     fn from(e: windows::core::Error) -> Self {
-        Self::WindowsError(e)
+        Self::Windows(e)
     }
 }
 
 impl From<tokio::io::Error> for Error {
     fn from(e: tokio::io::Error) -> Self {
-        Self::IOError(e)
-    }
-}
+        use tokio::io::ErrorKind;
 
-impl From<tokio::time::error::Elapsed> for Error {
-    fn from(e: tokio::time::error::Elapsed) -> Self {
-        Self::TimeoutError(e)
+        match e.kind() {
+            ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe => Self::Disconnected,
+            _ => Self::Io(e),
+        }
     }
 }
 
 impl From<std::str::Utf8Error> for Error {
     fn from(e: std::str::Utf8Error) -> Self {
-        Self::SyncError(format!("{}", e))
+        Self::ProtocolViolation(format!("{}", e))
     }
 }
 
 // napi::Error への変換
+// Env がない場所で使われるので、`code` は付かない
 impl From<Error> for napi::Error {
     fn from(e: Error) -> Self {
-        napi::Error::new(napi::Status::GenericFailure, e)
-    }
-}
-
-impl From<Error> for napi::JsError {
-    fn from(e: Error) -> Self {
-        e.into()
+        napi::Error::new(napi::Status::GenericFailure, e.to_string())
     }
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::WindowsError(_) => write!(f, "WindowsError"),
-            Self::IOError(_) => write!(f, "IOError"),
-            Self::TimeoutError(_) => write!(f, "Timeout Error"),
-            Self::SyncError(_) => write!(f, "SyncError"),
+            #[cfg(windows)]
+            Self::Windows(e) => write!(f, "Windows error: {}", e.message()),
+            Self::Io(e) => write!(f, "I/O error: {}", e),
+            Self::PeerNotFound(message) => write!(f, "Peer not found: {}", message),
+            Self::SyncRejected(message) => write!(f, "Sync rejected: {}", message),
+            Self::AuthenticationFailed(uuid) => write!(f, "Authentication failed: {}", uuid),
+            Self::DeviceRevoked(uuid) => write!(f, "Device revoked: {}", uuid),
+            Self::VersionMismatch { ours, theirs } => write!(
+                f,
+                "Incompatible sync protocol version: ours = {}, theirs = {}",
+                ours, theirs
+            ),
+            Self::Timeout { phase } => write!(f, "Timed out: {}", phase),
+            Self::ProtocolViolation(message) => write!(f, "Protocol violation: {}", message),
+            Self::RequestFailed { code, message, .. } => {
+                write!(f, "Request failed on the companion: {} ({})", message, code)
+            }
+            Self::Disconnected => write!(f, "Disconnected"),
+            Self::Cancelled => write!(f, "Cancelled"),
+            Self::InvalidArgument(message) => write!(f, "Invalid argument: {}", message),
+            Self::Other(message) => write!(f, "{}", message),
         }
     }
 }
 
 impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::ErrorKind;
+
+    #[test]
+    fn only_transport_failures_are_connection_lost() {
+        let io = |kind: ErrorKind| Error::Io(tokio::io::Error::new(kind, "test"));
+
+        assert!(Error::Disconnected.is_connection_lost());
+        assert!(Error::Timeout { phase: "idle" }.is_connection_lost());
+        assert!(io(ErrorKind::ConnectionReset).is_connection_lost());
+        assert!(!io(ErrorKind::InvalidData).is_connection_lost());
+        assert!(!Error::Timeout { phase: "close" }.is_connection_lost());
+        assert!(!Error::ProtocolViolation("Frame too large".to_owned()).is_connection_lost());
+    }
+}
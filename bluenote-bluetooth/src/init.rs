@@ -1,53 +1,228 @@
-pub mod client;
-pub mod server;
+pub mod tcp;
+pub mod token;
 
-use windows::{
-    core::HSTRING,
-    Storage::Streams::{ByteOrder, DataReader, DataWriter, IInputStream, IOutputStream},
+use std::sync::Mutex;
+
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use rand_core::OsRng;
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::{
+    error::Error,
+    sync::{
+        auth::PairingKey,
+        handshake::{Capabilities, PROTOCOL_VERSION},
+        read_uuid,
+    },
+    trust::TrustedDevice,
+    Result,
 };
 
-async fn exchange_uuid(
-    my_uuid: &str,
-    input_stream: &IInputStream,
-    output_stream: &IOutputStream,
-) -> windows::core::Result<String> {
-    let writer = DataWriter::CreateDataWriter(output_stream)?;
-    let reader = DataReader::CreateDataReader(input_stream)?;
+/// 相手から受け付ける自己紹介の最大の長さ (バイト)
+const MAX_HELLO_LEN: u32 = 4096;
+
+/// 初期設定の受付で、相手とペアリングしたときに呼ぶコールバック
+/// ペアリングに失敗したときは、エラーを渡す (JavaScript には `code` つきの Error を渡すので `Fatal`)
+pub static ON_UUID_EXCHANGED: Mutex<
+    Option<ThreadsafeFunction<Result<UuidExchanged>, ErrorStrategy::Fatal>>,
+> = Mutex::new(None);
+
+/// `ON_UUID_EXCHANGED` に渡す (相手のデバイス名, UUID, ペアリングの鍵の指紋, 相手の自己紹介)
+pub type UuidExchanged = (String, String, Option<String>, Option<Hello>);
+
+/// 初期設定で交換する、デバイスの自己紹介
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    /// ユーザに表示する名前
+    pub display_name: String,
+    /// `windows` / `android` など
+    pub platform: String,
+    pub app_version: String,
+    /// 対応している同期プロトコルのバージョン
+    pub protocol_versions: Vec<u8>,
+    /// 同期で使える機能のフラグ (`Capabilities`)
+    pub capabilities: u32,
+}
+
+impl Hello {
+    /// このデバイスの自己紹介
+    pub fn local(display_name: &str, app_version: &str) -> Self {
+        Self {
+            display_name: display_name.to_owned(),
+            platform: std::env::consts::OS.to_owned(),
+            app_version: app_version.to_owned(),
+            protocol_versions: vec![PROTOCOL_VERSION],
+            capabilities: Capabilities::ALL.bits(),
+        }
+    }
+
+    fn to_json(&self) -> String {
+        json!({
+            "displayName": self.display_name,
+            "platform": self.platform,
+            "appVersion": self.app_version,
+            "protocolVersions": self.protocol_versions,
+            "capabilities": self.capabilities,
+        })
+        .to_string()
+    }
+
+    /// 知らない項目は無視し、足りない項目は空として読む (新しいバージョンの相手も受け付ける)
+    fn from_json(json: &[u8]) -> Result<Self> {
+        let value: Value = serde_json::from_slice(json)
+            .map_err(|e| Error::ProtocolViolation(format!("Invalid hello: {}", e)))?;
+        let string = |key: &str| value[key].as_str().unwrap_or_default().to_owned();
+
+        Ok(Self {
+            display_name: string("displayName"),
+            platform: string("platform"),
+            app_version: string("appVersion"),
+            protocol_versions: value["protocolVersions"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|v| v.as_u64()?.try_into().ok())
+                .collect(),
+            capabilities: value["capabilities"]
+                .as_u64()
+                .and_then(|v| v.try_into().ok())
+                .unwrap_or(0),
+        })
+    }
+}
+
+/// 初期設定で交換した相手の情報
+pub struct Paired {
+    /// 相手のデバイスの UUID
+    pub uuid: String,
+    /// 同期のときに相手を認証するための鍵
+    /// 鍵の交換に対応していない相手の場合は `None`
+    pub pairing_key: Option<PairingKey>,
+    /// 相手の自己紹介
+    /// 自己紹介に対応していない相手の場合は `None`
+    pub hello: Option<Hello>,
+}
+
+impl Paired {
+    /// 信頼するデバイスの一覧に保存する情報
+    /// 名前は相手の自己紹介を優先し、なければ `fallback_name` (Bluetooth のデバイス名など) を使う
+    pub fn to_trusted_device(&self, fallback_name: &str) -> TrustedDevice {
+        let hello = self.hello.as_ref();
+        let name = hello
+            .map(|hello| hello.display_name.as_str())
+            .filter(|name| !name.is_empty())
+            .unwrap_or(fallback_name);
+
+        TrustedDevice {
+            platform: hello.map(|hello| hello.platform.clone()),
+            app_version: hello.map(|hello| hello.app_version.clone()),
+            ..TrustedDevice::new(&self.uuid, name, self.pairing_key.clone())
+        }
+    }
+}
+
+/// 受付で相手とペアリングしたことを、信頼するデバイスの一覧に保存してアプリに知らせる
+/// `device_name` は相手の自己紹介がない場合の名前 (Bluetooth のデバイス名や、TCP のアドレス)
+pub(crate) fn notify_paired(device_name: String, paired: Paired) -> Result<()> {
+    crate::trust::remember(paired.to_trusted_device(&device_name))?;
+
+    if let Some(on_uuid_exchanged) = ON_UUID_EXCHANGED.lock().unwrap().as_ref() {
+        let fingerprint = paired.pairing_key.as_ref().map(PairingKey::fingerprint);
+
+        on_uuid_exchanged.call(
+            Ok((device_name, paired.uuid, fingerprint, paired.hello)),
+            ThreadsafeFunctionCallMode::NonBlocking,
+        );
+    }
+
+    Ok(())
+}
 
-    writer.SetByteOrder(ByteOrder::LittleEndian)?;
-    reader.SetByteOrder(ByteOrder::LittleEndian)?;
+/// 受付でのペアリングに失敗したことを、アプリに知らせる
+pub(crate) fn notify_pairing_failed(e: Error) {
+    println!("Pairing failed: {}", e);
 
-    let (send_result, uuid): (windows::core::Result<()>, windows::core::Result<String>) = futures::join!(
+    if let Some(on_uuid_exchanged) = ON_UUID_EXCHANGED.lock().unwrap().as_ref() {
+        on_uuid_exchanged.call(Err(e), ThreadsafeFunctionCallMode::NonBlocking);
+    }
+}
+
+/// 初期設定で鍵を交換する方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairingMethod<'a> {
+    /// X25519 で鍵を交換する (相手の確認は Bluetooth のペアリングに任せる)
+    Plain,
+    /// 相手が発行したワンタイムトークンのコード (`token::normalize_code` 済み) を使う
+    Code(&'a str),
+    /// 自分が発行中のトークン (`token::issue`) を使う
+    /// トークンは相手の UUID を受信して鍵の交換を始めるときに取り出すので、何も送ってこない接続では使われない
+    /// トークンを発行していなければ、`required` なら拒否し、そうでなければ `Plain` と同じにする
+    IssuedToken { required: bool },
+}
+
+impl PairingMethod<'_> {
+    /// 鍵の交換に使うコードを決める (`None` なら `Plain`)
+    fn code(self) -> Result<Option<String>> {
+        match self {
+            Self::Plain => Ok(None),
+            Self::Code(code) => Ok(Some(code.to_owned())),
+            Self::IssuedToken { required } => match token::take()? {
+                Some(code) => Ok(Some(code)),
+                None if required => Err(Error::AuthenticationFailed(
+                    "No pairing token issued".to_owned(),
+                )),
+                None => Ok(None),
+            },
+        }
+    }
+}
+
+/// 同期設定のため、互いのデバイスの UUID を交換する
+/// 続けて鍵を交換し、同期で相手を認証するための鍵を共有する
+/// 最後に自己紹介 (`my_hello`) を交換する
+///
+/// ワンタイムトークンのコードを使う場合 (`method`) は、コードを使って鍵を交換し、
+/// 同じコードを持つ相手だけとペアリングする (鍵の交換に対応していない相手も拒否する)
+pub async fn exchange_uuid<R, W>(
+    my_uuid: &str,
+    my_hello: &Hello,
+    method: PairingMethod<'_>,
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<Paired>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (send_result, uuid): (Result<()>, Result<String>) = futures::join!(
         async {
             // 自身のデバイスIDを送信
-            writer.WriteString(&HSTRING::from(my_uuid))?;
-            writer.StoreAsync()?.await?;
-            writer.FlushAsync()?.await?;
+            writer.write_all(my_uuid.as_bytes()).await?;
+            writer.flush().await?;
 
             Ok(())
         },
         async {
             // 相手のデバイスの UUID を受信
-            reader.LoadAsync(36)?.await?;
-            Ok(reader.ReadString(36)?.to_string())
+            read_uuid(reader).await
         }
     );
 
     send_result?;
     let uuid = uuid?;
 
-    let (send_result, receive_result): (windows::core::Result<()>, windows::core::Result<()>) = futures::join!(
+    let (send_result, receive_result): (Result<()>, Result<()>) = futures::join!(
         async {
             // ACK 送信
-            writer.WriteByte(0)?;
-            writer.StoreAsync()?.await?;
-            writer.FlushAsync()?.await?;
+            writer.write_u8(0).await?;
+            writer.flush().await?;
             Ok(())
         },
         async {
             // ACK 受信
-            reader.LoadAsync(1)?.await?;
-            reader.ReadByte()?;
+            reader.read_u8().await?;
             Ok(())
         }
     );
@@ -55,5 +230,333 @@ async fn exchange_uuid(
     send_result?;
     receive_result?;
 
-    Ok(uuid)
+    let pairing_key = match method.code()? {
+        Some(code) => Some(
+            token::exchange_pairing_key_with_code(&code, my_uuid, &uuid, reader, writer).await?,
+        ),
+        None => exchange_pairing_key(reader, writer).await?,
+    };
+
+    if pairing_key.is_none() {
+        println!("Warning: the companion does not support pairing keys.");
+        return Ok(Paired {
+            uuid,
+            pairing_key,
+            hello: None,
+        });
+    }
+
+    let hello = exchange_hello(my_hello, reader, writer).await?;
+
+    if hello.is_none() {
+        println!("Warning: the companion does not support hello.");
+    }
+
+    Ok(Paired {
+        uuid,
+        pairing_key,
+        hello,
+    })
+}
+
+/// 自己紹介 (4 バイトの長さ + JSON) を交換する
+/// 以前のバージョンの相手は鍵の交換の後に切断するので、受信できなければ `None` を返す
+async fn exchange_hello<R, W>(
+    my_hello: &Hello,
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<Option<Hello>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (send_result, their_hello): (Result<()>, Result<Vec<u8>>) = futures::join!(
+        async {
+            // 自身の自己紹介を送信
+            let json = my_hello.to_json();
+            writer.write_u32_le(json.len() as u32).await?;
+            writer.write_all(json.as_bytes()).await?;
+            writer.flush().await?;
+
+            Ok(())
+        },
+        async {
+            // 相手の自己紹介を受信
+            let len = reader.read_u32_le().await?;
+
+            if len > MAX_HELLO_LEN {
+                return Err(Error::ProtocolViolation(format!(
+                    "Hello too large: {} bytes",
+                    len
+                )));
+            }
+
+            let mut json = vec![0; len as usize];
+            reader.read_exact(&mut json).await?;
+
+            Ok(json)
+        }
+    );
+
+    let their_hello = match their_hello {
+        Ok(json) => json,
+        Err(Error::Disconnected) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    send_result?;
+
+    Hello::from_json(&their_hello).map(Some)
+}
+
+/// X25519 で鍵を交換し、ペアリングの鍵を作る
+/// 以前のバージョンの相手は ACK の後に切断するので、公開鍵を受信できなければ `None` を返す
+async fn exchange_pairing_key<R, W>(reader: &mut R, writer: &mut W) -> Result<Option<PairingKey>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let my_public_key = PublicKey::from(&secret);
+
+    let (send_result, their_public_key): (Result<()>, Result<[u8; 32]>) = futures::join!(
+        async {
+            // 自身の公開鍵を送信
+            writer.write_all(my_public_key.as_bytes()).await?;
+            writer.flush().await?;
+
+            Ok(())
+        },
+        async {
+            // 相手の公開鍵を受信
+            let mut public_key = [0; 32];
+            reader.read_exact(&mut public_key).await?;
+
+            Ok(public_key)
+        }
+    );
+
+    let their_public_key = match their_public_key {
+        Ok(public_key) => public_key,
+        Err(Error::Disconnected) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    send_result?;
+
+    let shared_secret = secret.diffie_hellman(&PublicKey::from(their_public_key));
+
+    // 相手の公開鍵が不正 (小さな部分群の点など) で、共有した値が推測できる場合は使わない
+    if !shared_secret.was_contributory() {
+        return Err(Error::ProtocolViolation(
+            "Invalid public key for pairing".to_owned(),
+        ));
+    }
+
+    Ok(Some(PairingKey::derive(
+        shared_secret.as_bytes(),
+        [*my_public_key.as_bytes(), their_public_key],
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID_A: &str = "11111111-1111-4111-8111-111111111111";
+    const UUID_B: &str = "22222222-2222-4222-8222-222222222222";
+
+    fn hello(display_name: &str) -> Hello {
+        Hello::local(display_name, "1.0.0")
+    }
+
+    #[tokio::test]
+    async fn both_sides_get_the_same_pairing_key() {
+        let (a, b) = tokio::io::duplex(256);
+        let (mut a_reader, mut a_writer) = tokio::io::split(a);
+        let (mut b_reader, mut b_writer) = tokio::io::split(b);
+
+        let (desktop, pixel) = (hello("Desktop"), hello("Pixel"));
+
+        let (a, b) = tokio::join!(
+            exchange_uuid(
+                UUID_A,
+                &desktop,
+                PairingMethod::Plain,
+                &mut a_reader,
+                &mut a_writer
+            ),
+            exchange_uuid(
+                UUID_B,
+                &pixel,
+                PairingMethod::Plain,
+                &mut b_reader,
+                &mut b_writer
+            )
+        );
+        let (a, b) = (a.unwrap(), b.unwrap());
+
+        assert_eq!(a.uuid, UUID_B);
+        assert_eq!(b.uuid, UUID_A);
+        assert!(a.pairing_key.is_some());
+        assert_eq!(a.pairing_key, b.pairing_key);
+        assert_eq!(a.hello, Some(pixel));
+        assert_eq!(b.hello, Some(desktop));
+    }
+
+    #[tokio::test]
+    async fn companion_without_hello_is_paired_with_a_key() {
+        let (a, b) = tokio::io::duplex(256);
+        let (mut a_reader, mut a_writer) = tokio::io::split(a);
+        let (mut b_reader, mut b_writer) = tokio::io::split(b);
+
+        // 鍵の交換までに対応したバージョン: 鍵を交換したら切断する
+        let without_hello = async move {
+            b_writer.write_all(UUID_B.as_bytes()).await?;
+            b_writer.write_u8(0).await?;
+            let mut received = [0; 37];
+            b_reader.read_exact(&mut received).await?;
+            exchange_pairing_key(&mut b_reader, &mut b_writer).await?;
+
+            Ok::<_, Error>(())
+        };
+
+        let desktop = hello("Desktop");
+        let (paired, without_hello) = tokio::join!(
+            exchange_uuid(
+                UUID_A,
+                &desktop,
+                PairingMethod::Plain,
+                &mut a_reader,
+                &mut a_writer
+            ),
+            without_hello
+        );
+        without_hello.unwrap();
+        let paired = paired.unwrap();
+
+        assert!(paired.pairing_key.is_some());
+        assert_eq!(paired.hello, None);
+        assert_eq!(
+            paired.to_trusted_device("Pixel (Bluetooth)").name,
+            "Pixel (Bluetooth)"
+        );
+    }
+
+    #[test]
+    fn hello_from_a_newer_version_is_accepted() {
+        let hello = Hello::from_json(
+            br#"{"displayName":"Pixel","platform":"android","protocolVersions":[2,3],"newField":1}"#,
+        )
+        .unwrap();
+
+        assert_eq!(hello.display_name, "Pixel");
+        assert_eq!(hello.app_version, "");
+        assert_eq!(hello.protocol_versions, vec![2, 3]);
+        assert!(Hello::from_json(b"not json").is_err());
+    }
+
+    #[tokio::test]
+    async fn companion_without_pairing_keys_is_paired_without_a_key() {
+        let (a, b) = tokio::io::duplex(256);
+        let (mut a_reader, mut a_writer) = tokio::io::split(a);
+        let (mut b_reader, mut b_writer) = tokio::io::split(b);
+
+        // 以前のバージョン: UUID と ACK を交換したら切断する
+        let legacy = async move {
+            b_writer.write_all(UUID_B.as_bytes()).await?;
+            b_writer.write_u8(0).await?;
+            let mut received = [0; 37];
+            b_reader.read_exact(&mut received).await?;
+
+            Ok::<_, std::io::Error>(())
+        };
+
+        let desktop = hello("Desktop");
+        let (paired, legacy) = tokio::join!(
+            exchange_uuid(
+                UUID_A,
+                &desktop,
+                PairingMethod::Plain,
+                &mut a_reader,
+                &mut a_writer
+            ),
+            legacy
+        );
+        legacy.unwrap();
+        let paired = paired.unwrap();
+
+        assert_eq!(paired.uuid, UUID_B);
+        assert!(paired.pairing_key.is_none());
+        assert_eq!(paired.hello, None);
+    }
+
+    #[tokio::test]
+    async fn companion_with_the_code_is_paired() {
+        let (a, b) = tokio::io::duplex(256);
+        let (mut a_reader, mut a_writer) = tokio::io::split(a);
+        let (mut b_reader, mut b_writer) = tokio::io::split(b);
+
+        let (desktop, pixel) = (hello("Desktop"), hello("Pixel"));
+
+        let (a, b) = tokio::join!(
+            exchange_uuid(
+                UUID_A,
+                &desktop,
+                PairingMethod::Code("12345678"),
+                &mut a_reader,
+                &mut a_writer
+            ),
+            exchange_uuid(
+                UUID_B,
+                &pixel,
+                PairingMethod::Code("12345678"),
+                &mut b_reader,
+                &mut b_writer
+            )
+        );
+        let (a, b) = (a.unwrap(), b.unwrap());
+
+        assert!(a.pairing_key.is_some());
+        assert_eq!(a.pairing_key, b.pairing_key);
+        assert_eq!(a.hello, Some(pixel));
+    }
+
+    #[tokio::test]
+    async fn companion_without_the_code_is_rejected() {
+        let (a, b) = tokio::io::duplex(256);
+        let (mut a_reader, mut a_writer) = tokio::io::split(a);
+        let (mut b_reader, mut b_writer) = tokio::io::split(b);
+
+        // コードを知らない相手: 通常の鍵の交換をしようとする
+        // どちらも失敗したら切断する
+        let desktop = hello("Desktop");
+        let pixel = hello("Pixel");
+        let (paired, _) = tokio::join!(
+            async {
+                let result = exchange_uuid(
+                    UUID_A,
+                    &desktop,
+                    PairingMethod::Code("12345678"),
+                    &mut a_reader,
+                    &mut a_writer,
+                )
+                .await;
+                a_writer.shutdown().await.unwrap();
+                result
+            },
+            async {
+                let result = exchange_uuid(
+                    UUID_B,
+                    &pixel,
+                    PairingMethod::Plain,
+                    &mut b_reader,
+                    &mut b_writer,
+                )
+                .await;
+                b_writer.shutdown().await.unwrap();
+                result
+            }
+        );
+
+        assert!(matches!(paired, Err(Error::AuthenticationFailed(uuid)) if uuid == UUID_B));
+    }
 }
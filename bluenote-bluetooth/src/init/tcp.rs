@@ -0,0 +1,130 @@
+use std::{sync::Mutex, time::Duration};
+
+use tokio::{net::TcpListener, task::JoinHandle};
+
+use crate::{
+    error::Error,
+    sync::{tcp, transport::Transport},
+    Result, RUNTIME,
+};
+
+use super::{
+    exchange_uuid, notify_paired, notify_pairing_failed, token, Hello, Paired, PairingMethod,
+};
+
+/// TCP の接続を受け付けるタスク
+static TCP_INIT_SERVER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+
+/// 受け付けた接続で、ペアリングが終わるまで待つ時間
+/// 何も送ってこない接続を、いつまでも残さないようにする
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// TCP で初期設定の受付を開始する
+/// Bluetooth のペアリングがないので、ワンタイムトークン (`token::issue`) のコードを持つ相手だけを受け付ける
+/// すでに受け付けている場合は、新しいアドレス・UUID・自己紹介で受付をやり直す
+pub async fn start(address: &str, my_uuid: String, my_hello: Hello) -> Result<()> {
+    let running = TCP_INIT_SERVER.lock().unwrap().take();
+
+    if let Some(acceptor) = running {
+        // 同じアドレスで受け付け直せるよう、リスナーが drop されるまで待つ
+        acceptor.abort();
+        let _ = acceptor.await;
+    }
+
+    let listener = tcp::listen(address).await?;
+    let acceptor = RUNTIME.spawn(accept(listener, my_uuid, my_hello));
+
+    *TCP_INIT_SERVER.lock().unwrap() = Some(acceptor);
+
+    Ok(())
+}
+
+/// TCP での初期設定の受付を停止する
+pub fn stop() {
+    if let Some(acceptor) = TCP_INIT_SERVER.lock().unwrap().take() {
+        acceptor.abort();
+    }
+}
+
+/// 接続を受け付け続ける
+/// 接続ごとにタスクを作るので、止まった接続があっても他の接続を受け付けられる
+/// (トークンは鍵の交換を始めた接続が使うので、同時に複数の接続があっても 1 つしか使えない)
+async fn accept(listener: TcpListener, my_uuid: String, my_hello: Hello) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, address)) => {
+                println!("Connected to: {}", address);
+                stream
+            }
+            Err(e) => {
+                println!("Warning: failed to accept connection: {}", e);
+                continue;
+            }
+        };
+
+        let (my_uuid, my_hello) = (my_uuid.clone(), my_hello.clone());
+
+        tokio::spawn(async move {
+            let device_name = stream.peer_name();
+
+            let result = async {
+                let paired =
+                    tokio::time::timeout(PAIRING_TIMEOUT, serve(stream, &my_uuid, &my_hello))
+                        .await
+                        .map_err(|_| Error::Timeout { phase: "pairing" })??;
+
+                println!("UUID: {}", paired.uuid);
+
+                notify_paired(device_name, paired)
+            };
+
+            if let Err(e) = result.await {
+                notify_pairing_failed(e);
+            }
+        });
+    }
+}
+
+/// 受け付けた相手と、発行中のトークンを使って UUID を交換する
+/// トークンを発行していなければ、鍵の交換を始めるときに拒否する
+async fn serve<T>(transport: T, my_uuid: &str, my_hello: &Hello) -> Result<Paired>
+where
+    T: Transport,
+{
+    let (mut reader, mut writer) = transport.into_split();
+
+    exchange_uuid(
+        my_uuid,
+        my_hello,
+        PairingMethod::IssuedToken { required: true },
+        &mut reader,
+        &mut writer,
+    )
+    .await
+}
+
+/// TCP で初期設定の受付に接続し、相手が表示したトークンのコードを使って UUID を交換する
+pub async fn connect(address: &str, my_uuid: &str, my_hello: &Hello, code: &str) -> Result<Paired> {
+    let code = token::normalize_code(code)?;
+    let stream = tcp::connect(address).await?;
+    let device_name = stream.peer_name();
+
+    let paired = {
+        let (mut reader, mut writer) = stream.into_split();
+        exchange_uuid(
+            my_uuid,
+            my_hello,
+            PairingMethod::Code(&code),
+            &mut reader,
+            &mut writer,
+        )
+        .await?
+    };
+
+    println!("Connection closed.");
+    println!("UUID: {}", paired.uuid);
+
+    crate::trust::remember(paired.to_trusted_device(&device_name))?;
+
+    Ok(paired)
+}
@@ -0,0 +1,227 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use curve25519_dalek::{
+    ristretto::CompressedRistretto, traits::IsIdentity, RistrettoPoint, Scalar,
+};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha512;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    error::Error,
+    sync::auth::{exchange, PairingKey},
+    Result,
+};
+
+/// 発行中のワンタイムトークン
+/// 初期設定の受付で、最初に鍵の交換を始めた相手が使い、使ったら (成功しても失敗しても) 捨てる
+static PAIRING_TOKEN: Mutex<Option<PairingToken>> = Mutex::new(None);
+
+/// トークンの有効期間
+const TOKEN_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+/// コードの桁数
+const CODE_DIGITS: usize = 8;
+
+/// Bluetooth のペアリングの代わりに、画面に表示して相手に入力してもらうワンタイムトークン
+///
+/// コードは短いが、コードから作った生成元で鍵を交換する (CPace) ので、
+/// 通信を盗聴・改ざんしてもコードを総当たりで調べることはできない (接続 1 回につき 1 つしか試せない)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairingToken {
+    /// 8 桁の数字
+    pub code: String,
+    pub expires_at: SystemTime,
+}
+
+impl PairingToken {
+    fn generate() -> Self {
+        let code = OsRng.next_u64() % 10u64.pow(CODE_DIGITS as u32);
+
+        Self {
+            code: format!("{:0width$}", code, width = CODE_DIGITS),
+            expires_at: SystemTime::now() + TOKEN_LIFETIME,
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        SystemTime::now() >= self.expires_at
+    }
+}
+
+/// 新しいトークンを発行する (発行中のトークンは無効になる)
+pub fn issue() -> PairingToken {
+    let token = PairingToken::generate();
+    *PAIRING_TOKEN.lock().unwrap() = Some(token.clone());
+
+    token
+}
+
+/// 発行中のトークンを無効にする
+pub fn cancel() {
+    *PAIRING_TOKEN.lock().unwrap() = None;
+}
+
+/// 発行中のトークンのコードを取り出す (もう一度は使えない)
+/// トークンを発行していなければ `None`、有効期限が切れていればエラーを返す
+pub(crate) fn take() -> Result<Option<String>> {
+    match PAIRING_TOKEN.lock().unwrap().take() {
+        Some(token) if token.is_expired() => Err(Error::AuthenticationFailed(
+            "Pairing token expired".to_owned(),
+        )),
+        Some(token) => Ok(Some(token.code)),
+        None => Ok(None),
+    }
+}
+
+/// ユーザが入力したコードから、区切りの空白やハイフンを取り除く
+pub fn normalize_code(code: &str) -> Result<String> {
+    let code: String = code
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect();
+
+    if code.len() != CODE_DIGITS || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(Error::InvalidArgument(format!(
+            "Pairing code must be {} digits",
+            CODE_DIGITS
+        )));
+    }
+
+    Ok(code)
+}
+
+/// トークンのコードから作った生成元で鍵を交換し (CPace)、ペアリングの鍵を作る
+/// 互いに鍵の確認を送り合い、同じコードを持っていない相手は `Error::AuthenticationFailed` で拒否する
+pub(crate) async fn exchange_pairing_key_with_code<R, W>(
+    code: &str,
+    my_uuid: &str,
+    their_uuid: &str,
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<PairingKey>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let generator = generator(code, [my_uuid, their_uuid]);
+    let secret = Scalar::random(&mut OsRng);
+    let my_public_key = (secret * generator).compress().to_bytes();
+
+    let their_public_key = exchange(reader, writer, &my_public_key).await?;
+    // コードを使わずに鍵を交換しようとした相手 (X25519 の公開鍵を送ってきた相手) も、ここで拒否する
+    let their_point = CompressedRistretto(their_public_key)
+        .decompress()
+        .ok_or_else(|| Error::AuthenticationFailed(their_uuid.to_owned()))?;
+
+    let shared_secret = secret * their_point;
+
+    // 単位元を送ってきた相手とは、共有した値が推測できるので使わない
+    if shared_secret.is_identity() {
+        return Err(Error::ProtocolViolation(
+            "Invalid public key for pairing".to_owned(),
+        ));
+    }
+
+    let pairing_key = PairingKey::derive(
+        shared_secret.compress().as_bytes(),
+        [my_public_key, their_public_key],
+    );
+
+    let their_confirmation =
+        exchange(reader, writer, &pairing_key.confirmation(&my_public_key)).await?;
+
+    if !pairing_key.verify_confirmation(&their_public_key, &their_confirmation) {
+        return Err(Error::AuthenticationFailed(their_uuid.to_owned()));
+    }
+
+    Ok(pairing_key)
+}
+
+/// コードと両者の UUID から、鍵の交換に使う生成元を作る
+/// UUID はどちらの側でも同じになるよう、並べ替えてから使う
+fn generator(code: &str, mut uuids: [&str; 2]) -> RistrettoPoint {
+    uuids.sort();
+
+    let mut input = b"bluenote-pairing-code".to_vec();
+    input.extend_from_slice(code.as_bytes());
+    input.extend_from_slice(uuids[0].as_bytes());
+    input.extend_from_slice(uuids[1].as_bytes());
+
+    RistrettoPoint::hash_from_bytes::<Sha512>(&input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init::{exchange_uuid, Hello, PairingMethod};
+
+    const UUID_A: &str = "11111111-1111-4111-8111-111111111111";
+    const UUID_B: &str = "22222222-2222-4222-8222-222222222222";
+
+    async fn exchange_with_codes(a: &str, b: &str) -> (Result<PairingKey>, Result<PairingKey>) {
+        let (a_stream, b_stream) = tokio::io::duplex(256);
+        let (mut a_reader, mut a_writer) = tokio::io::split(a_stream);
+        let (mut b_reader, mut b_writer) = tokio::io::split(b_stream);
+
+        tokio::join!(
+            exchange_pairing_key_with_code(a, UUID_A, UUID_B, &mut a_reader, &mut a_writer),
+            exchange_pairing_key_with_code(b, UUID_B, UUID_A, &mut b_reader, &mut b_writer)
+        )
+    }
+
+    // トークンはプロセスで 1 つなので、トークンを使うテストはここにまとめる
+    #[tokio::test]
+    async fn token_is_used_only_once() {
+        let token = issue();
+
+        assert_eq!(token.code.len(), CODE_DIGITS);
+        assert_eq!(take().unwrap(), Some(token.code));
+        assert_eq!(take().unwrap(), None);
+
+        // 何も送ってこない接続では、トークンを使わない
+        let token = issue();
+        let (stream, _idle) = tokio::io::duplex(256);
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let hello = Hello::local("Desktop", "1.0.0");
+        let exchange = exchange_uuid(
+            UUID_A,
+            &hello,
+            PairingMethod::IssuedToken { required: true },
+            &mut reader,
+            &mut writer,
+        );
+
+        assert!(tokio::time::timeout(Duration::from_millis(50), exchange)
+            .await
+            .is_err());
+        assert_eq!(take().unwrap(), Some(token.code));
+    }
+
+    #[test]
+    fn code_is_normalized() {
+        assert_eq!(normalize_code("1234 5678").unwrap(), "12345678");
+        assert_eq!(normalize_code("1234-5678").unwrap(), "12345678");
+        assert!(normalize_code("1234567").is_err());
+        assert!(normalize_code("1234567a").is_err());
+    }
+
+    #[tokio::test]
+    async fn peers_with_the_same_code_share_a_key() {
+        let (a, b) = exchange_with_codes("12345678", "12345678").await;
+        let (a, b) = (a.unwrap(), b.unwrap());
+
+        assert_eq!(a, b);
+    }
+
+    #[tokio::test]
+    async fn peers_with_different_codes_reject_each_other() {
+        let (a, b) = exchange_with_codes("12345678", "87654321").await;
+
+        assert!(matches!(a, Err(Error::AuthenticationFailed(uuid)) if uuid == UUID_B));
+        assert!(matches!(b, Err(Error::AuthenticationFailed(uuid)) if uuid == UUID_A));
+    }
+}
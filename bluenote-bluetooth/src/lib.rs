@@ -1,28 +1,40 @@
-mod error;
-mod init;
-mod scanner;
-mod sync;
+pub mod error;
+pub mod init;
+pub mod sync;
+pub mod trust;
+#[cfg(windows)]
+mod winrt;
 
-use init::client::RequestParamPairing;
 use napi::threadsafe_function::{ThreadSafeCallContext, ThreadsafeFunction};
-use napi::{bindgen_prelude::*, JsString, JsUndefined};
+use napi::{bindgen_prelude::*, JsUndefined, JsUnknown};
 use napi_derive::napi;
 use once_cell::sync::Lazy;
-use std::sync::Mutex;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
 use sync::server::{
     RequestParamAllNotesInThread, RequestParamAllNotesInTree, RequestParamNoteUpdatesInThread,
     RequestParamNoteUpdatesInTree, RequestParamSyncPermission, RequestParamThreadUpdates,
     RequestParamUpdateSyncedAt,
 };
 use tokio::runtime::Runtime;
+#[cfg(windows)]
+use winrt::init_client::RequestParamPairing;
 
 /// Bluenote Result
-type Result<T> = std::result::Result<T, crate::error::Error>;
+pub type Result<T> = std::result::Result<T, crate::error::Error>;
 
 /// 同期の RFCOMM サービス UUID
+#[cfg(windows)]
 static UUID_BLUENOTE_RFCOMM: &str = "44734f5c-79b4-4cd1-a312-e96f5b39ff93";
 
 /// 同期初期化の RFCOMM サービス UUID
+#[cfg(windows)]
 static UUID_BLUENOTE_RFCOMM_INIT: &str = "c144d029-2a62-4cfc-b39c-ca6ce173cb3f";
 
 static RUNTIME: Lazy<Runtime> = Lazy::new(|| {
@@ -32,15 +44,23 @@ static RUNTIME: Lazy<Runtime> = Lazy::new(|| {
         .unwrap()
 });
 
-static BLUETOOTH_SCANNER: crate::scanner::BluetoothScanner =
-    crate::scanner::BluetoothScanner::new();
+#[cfg(windows)]
+static BLUETOOTH_SCANNER: crate::winrt::scanner::BluetoothScanner =
+    crate::winrt::scanner::BluetoothScanner::new();
 
 /// 指定したデバイスに RFCOMM で接続し、UUID を交換
-#[napi(ts_return_type = "Promise<string>")]
-pub fn init_client(windows_device_id: String, my_uuid: String) -> AsyncTask<InitClientTask> {
+/// `myInfo` は相手に送る自己紹介 (省略すると、相手は Bluetooth のデバイス名を使う)
+#[cfg(windows)]
+#[napi(ts_return_type = "Promise<PairedCompanion>")]
+pub fn init_client(
+    windows_device_id: String,
+    my_uuid: String,
+    my_info: Option<MyDeviceInfo>,
+) -> AsyncTask<InitClientTask> {
     AsyncTask::new(InitClientTask {
         windows_device_id,
         my_uuid,
+        my_hello: MyDeviceInfo::into_hello(my_info),
     })
 }
 
@@ -48,6 +68,7 @@ pub fn init_client(windows_device_id: String, my_uuid: String) -> AsyncTask<Init
 /// このコールバックが呼ばれたとき、ユーザに PIN を表示してペアリングの
 /// 許可・不許可の判定をしてもらい、`respond_to_bond_request` でその
 /// 結果を受け取る
+#[cfg(windows)]
 #[napi(ts_args_type = "callback: (err: null | Error, deviceName: string, pin: string) => void")]
 pub fn set_on_bond_requested(callback: JsFunction) -> napi::Result<()> {
     let tsfn = callback.create_threadsafe_function(
@@ -60,22 +81,26 @@ pub fn set_on_bond_requested(callback: JsFunction) -> napi::Result<()> {
         },
     )?;
 
-    let mut callback = crate::init::client::ON_PAIRING_REQUESTED.lock().unwrap();
+    let mut callback = crate::winrt::init_client::ON_PAIRING_REQUESTED
+        .lock()
+        .unwrap();
     *callback = Some(tsfn);
 
     Ok(())
 }
 
 /// ペアリングリクエストに対する応答を返す
+#[cfg(windows)]
 #[napi]
-pub fn respond_to_bond_request(accept: bool) -> Result<()> {
-    let mut tx = crate::init::client::ACCEPT_SENDER.lock().unwrap();
+pub fn respond_to_bond_request(env: Env, accept: bool) -> napi::Result<()> {
+    let mut tx = crate::winrt::init_client::ACCEPT_SENDER.lock().unwrap();
 
     if let Some(tx) = tx.take() {
         if let Err(_) = tx.send(accept) {
-            return Err(error::Error::SyncError(format!(
-                "Warning: failed to send pairing response. May be already timed out?"
-            )));
+            return Err(error::Error::Other(
+                "Warning: failed to send pairing response. May be already timed out?".to_owned(),
+            )
+            .into_js_error(env));
         }
     }
 
@@ -83,6 +108,7 @@ pub fn respond_to_bond_request(accept: bool) -> Result<()> {
 }
 
 /// デバイスのスキャンを開始する
+#[cfg(windows)]
 #[napi]
 pub fn start_bluetooth_scan() -> napi::Result<()> {
     match BLUETOOTH_SCANNER.start() {
@@ -92,6 +118,7 @@ pub fn start_bluetooth_scan() -> napi::Result<()> {
 }
 
 /// デバイスのスキャンを停止する
+#[cfg(windows)]
 #[napi]
 pub fn stop_bluetooth_scan() -> napi::Result<()> {
     match BLUETOOTH_SCANNER.stop() {
@@ -101,6 +128,7 @@ pub fn stop_bluetooth_scan() -> napi::Result<()> {
 }
 
 /// スキャンの状態が変化したときのコールバックを設定する
+#[cfg(windows)]
 #[napi(ts_args_type = "callback: (err: null | Error, isScanning: Boolean) => void")]
 pub fn set_on_scan_state_changed(callback: JsFunction) -> napi::Result<()> {
     let tsfn = callback.create_threadsafe_function(0, |ctx: ThreadSafeCallContext<bool>| {
@@ -117,6 +145,7 @@ pub fn set_on_scan_state_changed(callback: JsFunction) -> napi::Result<()> {
 }
 
 /// デバイスが見つかった時のコールバックを設定する
+#[cfg(windows)]
 #[napi(
     ts_args_type = "callback: (err: null | Error, deviceName: string, deviceId: string) => void"
 )]
@@ -138,19 +167,29 @@ pub fn set_on_bluetooth_device_found(callback: JsFunction) -> napi::Result<()> {
 }
 
 /// 同期設定の受付を開始する
+/// `myInfo` は相手に送る自己紹介 (省略すると、相手は Bluetooth のデバイス名を使う)
+#[cfg(windows)]
 #[napi]
-pub fn start_init_server(my_uuid: String) -> AsyncTask<InitServerStartTask> {
-    AsyncTask::new(InitServerStartTask { my_uuid })
+pub fn start_init_server(
+    my_uuid: String,
+    my_info: Option<MyDeviceInfo>,
+) -> AsyncTask<InitServerStartTask> {
+    AsyncTask::new(InitServerStartTask {
+        my_uuid,
+        my_hello: MyDeviceInfo::into_hello(my_info),
+    })
 }
 
 /// 同期設定の受付を停止する
+#[cfg(windows)]
 #[napi]
 pub fn stop_init_server() -> napi::Result<()> {
-    crate::init::server::stop()?;
+    crate::winrt::init_server::stop()?;
     Ok(())
 }
 
 /// 同期受付の状態が変化したときのコールバックを設定する
+#[cfg(windows)]
 #[napi(ts_args_type = "callback: (err: null | Error, isRunning: Boolean) => void")]
 pub fn set_on_init_server_state_changed(callback: JsFunction) -> napi::Result<()> {
     let tsfn = callback.create_threadsafe_function(0, |ctx: ThreadSafeCallContext<bool>| {
@@ -160,79 +199,417 @@ pub fn set_on_init_server_state_changed(callback: JsFunction) -> napi::Result<()
             .collect()
     })?;
 
-    let mut callback = crate::init::server::ON_STATE_CHANGED.lock().unwrap();
+    let mut callback = crate::winrt::init_server::ON_STATE_CHANGED.lock().unwrap();
     *callback = Some(tsfn);
 
     Ok(())
 }
 
 /// UUID の交換が終わった時に呼ばれるコールバックを設定する
+/// 相手と共有した鍵は信頼するデバイスの一覧 (`openTrustStore`) に保存し、
+/// `fingerprint` にはその指紋を渡す (鍵の交換に対応していない相手なら `null`)
+/// TCP で受け付けた場合、`deviceName` は相手のアドレス
+/// 受付でのペアリングに失敗した場合は、`err` (`code` つき) だけを渡す
 #[napi(
-    ts_args_type = "callback: (err: null | Error, deviceName: string, deviceUuid: string) => void"
+    ts_args_type = "callback: (err: null | Error, deviceName: string, deviceUuid: string, fingerprint: string | null, hello: CompanionHello | null) => void"
 )]
 pub fn set_on_uuid_exchanged(callback: JsFunction) -> napi::Result<()> {
     // wwww
     let tsfn = callback.create_threadsafe_function(
         0,
-        |ctx: ThreadSafeCallContext<(String, String)>| {
-            vec![ctx.value.0, ctx.value.1]
-                .iter()
-                .map(|x| ctx.env.create_string(&x))
-                .collect()
+        |ctx: ThreadSafeCallContext<crate::Result<init::UuidExchanged>>| {
+            let (device_name, uuid, fingerprint, hello) = match ctx.value {
+                Ok(paired) => paired,
+                Err(e) => return Ok(vec![e.create_js_error(ctx.env)?]),
+            };
+            let fingerprint = match fingerprint {
+                Some(fingerprint) => ctx.env.create_string(&fingerprint)?.into_unknown(),
+                None => ctx.env.get_null()?.into_unknown(),
+            };
+            let hello = match hello {
+                Some(hello) => CompanionHello::from(hello)
+                    .create_object(&ctx.env)?
+                    .into_unknown(),
+                None => ctx.env.get_null()?.into_unknown(),
+            };
+
+            Ok(vec![
+                ctx.env.get_null()?.into_unknown(),
+                ctx.env.create_string(&device_name)?.into_unknown(),
+                ctx.env.create_string(&uuid)?.into_unknown(),
+                fingerprint,
+                hello,
+            ])
         },
     )?;
 
-    let mut callback = crate::init::server::ON_UUID_EXCHANGED.lock().unwrap();
+    let mut callback = init::ON_UUID_EXCHANGED.lock().unwrap();
 
     *callback = Some(tsfn);
 
     Ok(())
 }
 
+/// 初期設定用のワンタイムトークンを発行する (発行中のトークンは無効になる)
+/// アプリはコードを QR コードや数字で表示し、相手に入力してもらう
+/// トークンを発行している間、初期設定の受付はこのコードを持つ相手だけを受け付ける
+/// トークンは、最初に鍵の交換を始めた接続で (成功しても失敗しても) 使い切る
+#[napi]
+pub fn create_pairing_token() -> PairingTokenInfo {
+    let token = init::token::issue();
+
+    PairingTokenInfo {
+        code: token.code,
+        expires_at: token
+            .expires_at
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as f64)
+            .unwrap_or(0.0),
+    }
+}
+
+/// 発行中のワンタイムトークンを無効にする
+#[napi]
+pub fn cancel_pairing_token() {
+    init::token::cancel();
+}
+
+/// 初期設定用のワンタイムトークン
+#[napi(object)]
+pub struct PairingTokenInfo {
+    /// 相手に入力してもらう 8 桁の数字
+    pub code: String,
+    /// 有効期限 (UNIX エポックからのミリ秒)
+    pub expires_at: f64,
+}
+
+/// TCP で初期設定の受付を開始する (例: `0.0.0.0:47801`)
+/// Bluetooth のペアリングがないので、`createPairingToken` で発行したトークンのコードを持つ相手だけを受け付ける
+/// ペアリングした相手は `setOnUuidExchanged` のコールバックで知らせる
+/// すでに受け付けている場合は、指定したアドレス・UUID・情報で受付をやり直す
+#[napi(ts_return_type = "Promise<void>")]
+pub fn start_tcp_init_server(
+    address: String,
+    my_uuid: String,
+    my_info: Option<MyDeviceInfo>,
+) -> AsyncTask<TcpInitServerStartTask> {
+    AsyncTask::new(TcpInitServerStartTask {
+        address,
+        my_uuid,
+        my_hello: MyDeviceInfo::into_hello(my_info),
+    })
+}
+
+pub struct TcpInitServerStartTask {
+    address: String,
+    my_uuid: String,
+    my_hello: init::Hello,
+}
+
+impl Task for TcpInitServerStartTask {
+    type Output = Result<()>;
+    type JsValue = JsUndefined;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let future = init::tcp::start(&self.address, self.my_uuid.clone(), self.my_hello.clone());
+        Ok(RUNTIME.block_on(future))
+    }
+
+    fn resolve(&mut self, env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        output.map_err(|e| e.into_js_error(env))?;
+        env.get_undefined()
+    }
+}
+
+/// TCP での初期設定の受付を停止する
+#[napi]
+pub fn stop_tcp_init_server() {
+    init::tcp::stop();
+}
+
+/// TCP で相手の初期設定の受付に接続し、相手が表示したコードを使って UUID を交換する
+/// napi のバインディングを無効にしたビルド (`peer`) ではオブジェクトを返せないので除く
+#[cfg(not(feature = "peer"))]
+#[napi(ts_return_type = "Promise<PairedCompanion>")]
+pub fn init_tcp_client(
+    address: String,
+    my_uuid: String,
+    code: String,
+    my_info: Option<MyDeviceInfo>,
+) -> AsyncTask<InitTcpClientTask> {
+    AsyncTask::new(InitTcpClientTask {
+        address,
+        my_uuid,
+        code,
+        my_hello: MyDeviceInfo::into_hello(my_info),
+    })
+}
+
+#[cfg(not(feature = "peer"))]
+pub struct InitTcpClientTask {
+    address: String,
+    my_uuid: String,
+    code: String,
+    my_hello: init::Hello,
+}
+
+#[cfg(not(feature = "peer"))]
+impl Task for InitTcpClientTask {
+    type Output = Result<init::Paired>;
+    type JsValue = PairedCompanion;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let future = init::tcp::connect(&self.address, &self.my_uuid, &self.my_hello, &self.code);
+        Ok(RUNTIME.block_on(future))
+    }
+
+    fn resolve(&mut self, env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        output
+            .map(PairedCompanion::from)
+            .map_err(|e| e.into_js_error(env))
+    }
+}
+
 /// 同期対象のデバイスのデバイスIDを列挙する
+#[cfg(windows)]
 #[napi(ts_return_type = "Promise<string[]>")]
 pub fn enumerate_sync_companions() -> AsyncTask<EnumerateSyncCompanionsTask> {
     AsyncTask::new(EnumerateSyncCompanionsTask {})
 }
 
 /// 同期サーバを起動する
+/// `tcpAddress` (例: `0.0.0.0:47800`) を指定すると、TCP でも接続を受け付ける
 #[napi(ts_return_type = "Promise<void>")]
-pub fn start_sync_server() -> AsyncTask<SyncServerStartTask> {
-    AsyncTask::new(SyncServerStartTask {})
+pub fn start_sync_server(tcp_address: Option<String>) -> AsyncTask<SyncServerStartTask> {
+    AsyncTask::new(SyncServerStartTask { tcp_address })
 }
 
-pub struct SyncServerStartTask {}
+pub struct SyncServerStartTask {
+    tcp_address: Option<String>,
+}
 
 impl Task for SyncServerStartTask {
-    type Output = ();
+    type Output = Result<()>;
     type JsValue = JsUndefined;
 
     fn compute(&mut self) -> napi::Result<Self::Output> {
-        RUNTIME.block_on(crate::sync::server::start())?;
-        Ok(())
+        Ok(RUNTIME.block_on(crate::sync::server::start(self.tcp_address.as_deref())))
     }
 
-    fn resolve(&mut self, env: Env, _: Self::Output) -> napi::Result<Self::JsValue> {
+    fn resolve(&mut self, env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        output.map_err(|e| e.into_js_error(env))?;
         env.get_undefined()
     }
 }
 
 /// 同期サーバを停止する
+/// 新しい接続の受け付けを止め、動いているセッションがすべて終わったら resolve する
+/// 30 秒待っても終わらないセッションは切断する
+/// `force` が `true` の場合は、動いているセッションを終わるのを待たずに切断する
+#[napi(ts_return_type = "Promise<void>")]
+pub fn stop_sync_server(force: Option<bool>) -> AsyncTask<SyncServerStopTask> {
+    AsyncTask::new(SyncServerStopTask {
+        force: force.unwrap_or(false),
+    })
+}
+
+pub struct SyncServerStopTask {
+    force: bool,
+}
+
+impl Task for SyncServerStopTask {
+    type Output = Result<()>;
+    type JsValue = JsUndefined;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        Ok(RUNTIME.block_on(crate::sync::server::stop(self.force)))
+    }
+
+    fn resolve(&mut self, env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        output.map_err(|e| e.into_js_error(env))?;
+        env.get_undefined()
+    }
+}
+
+/// 同期サーバで動いているセッションの情報
+#[napi(object)]
+pub struct SyncSessionInfo {
+    pub id: u32,
+    /// 相手のデバイスの UUID (交換前は `undefined`)
+    pub uuid: Option<String>,
+    /// 相手のデバイス名 (TCP の場合はアドレス)
+    pub device_name: String,
+    /// セッションの開始時刻 (UNIX エポックからのミリ秒)
+    pub started_at: f64,
+    /// `handshaking` | `serving` | `finishing` | `closing`
+    pub state: String,
+}
+
+/// 同期サーバで動いているセッションの一覧を取得する
+#[napi]
+pub fn list_sync_sessions() -> Vec<SyncSessionInfo> {
+    crate::sync::session::SESSIONS
+        .list()
+        .into_iter()
+        .map(|info| SyncSessionInfo {
+            id: info.id,
+            uuid: info.uuid,
+            device_name: info.device_name,
+            started_at: info
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as f64)
+                .unwrap_or(0.0),
+            state: info.state.as_str().to_owned(),
+        })
+        .collect()
+}
+
+/// 信頼するデバイス (ペアリングした相手) の情報
+#[napi(object)]
+pub struct TrustedDeviceInfo {
+    pub uuid: String,
+    pub name: String,
+    /// 相手と共有した鍵の指紋 (鍵の交換に対応していない相手や、取り消した相手なら `undefined`)
+    pub fingerprint: Option<String>,
+    /// 相手のプラットフォーム・アプリのバージョン (初期設定で相手が送ってこなかった場合は `undefined`)
+    pub platform: Option<String>,
+    pub app_version: Option<String>,
+    /// ペアリングした時刻 (UNIX エポックからのミリ秒)
+    pub paired_at: f64,
+    pub revoked: bool,
+}
+
+/// 信頼するデバイスの一覧を保存するファイルを開く
+/// 以降、ペアリングで共有した鍵はこのファイルに保存し、同期ではこの鍵を使う
+/// (ユーザのデータのディレクトリにあるファイルを指定すること)
+#[napi]
+pub fn open_trust_store(env: Env, path: String) -> napi::Result<()> {
+    let store =
+        trust::TrustStore::open(std::path::Path::new(&path)).map_err(|e| e.into_js_error(env))?;
+    *trust::TRUST_STORE.lock().unwrap() = Some(store);
+
+    Ok(())
+}
+
+/// 信頼するデバイスの一覧を取得する (取り消したデバイスも含む)
 #[napi]
-pub fn stop_sync_server() -> Result<()> {
-    crate::sync::server::stop()
+pub fn list_trusted_devices(env: Env) -> napi::Result<Vec<TrustedDeviceInfo>> {
+    let store = trust::TRUST_STORE.lock().unwrap();
+    let store = store.as_ref().ok_or_else(|| {
+        error::Error::Other("Trust store is not open.".to_owned()).into_js_error(env)
+    })?;
+
+    Ok(store
+        .list()
+        .into_iter()
+        .map(|device| TrustedDeviceInfo {
+            fingerprint: device
+                .pairing_key
+                .as_ref()
+                .map(sync::auth::PairingKey::fingerprint),
+            uuid: device.uuid,
+            name: device.name,
+            platform: device.platform,
+            app_version: device.app_version,
+            paired_at: device
+                .paired_at
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as f64)
+                .unwrap_or(0.0),
+            revoked: device.revoked,
+        })
+        .collect())
+}
+
+/// デバイスの信頼を取り消す
+/// 取り消したデバイスとは、再びペアリングするまで同期しない (同期サーバは `onSyncRequested` を呼ばずに拒否する)
+#[napi]
+pub fn revoke_device(env: Env, uuid: String) -> napi::Result<()> {
+    let mut store = trust::TRUST_STORE.lock().unwrap();
+    let store = store.as_mut().ok_or_else(|| {
+        error::Error::Other("Trust store is not open.".to_owned()).into_js_error(env)
+    })?;
+
+    store.revoke(&uuid).map_err(|e| e.into_js_error(env))
+}
+
+/// 同期サーバから呼ぶコールバックのタイムアウト (ミリ秒)
+/// 指定しなかったものは変更しない
+#[napi(object)]
+pub struct SyncCallbackTimeouts {
+    pub sync_requested: Option<u32>,
+    pub my_uuid: Option<u32>,
+    pub now: Option<u32>,
+    pub thread_updates: Option<u32>,
+    pub all_notes_in_thread: Option<u32>,
+    pub all_notes_in_tree: Option<u32>,
+    pub note_updates_in_thread: Option<u32>,
+    pub note_updates_in_tree: Option<u32>,
+    pub update_synced_at: Option<u32>,
+}
+
+/// 同期サーバから呼ぶコールバックのタイムアウトを設定する
+/// データを返すコールバックがタイムアウトした場合、そのリクエストだけを `TIMEOUT` のエラーのレスポンスで失敗させ、セッションは続ける
+/// (エラーのレスポンスに対応していない相手の場合や、データを返す以外のコールバックの場合は、そのセッションを失敗として切断する)
+#[napi]
+pub fn set_sync_callback_timeouts(timeouts: SyncCallbackTimeouts) {
+    fn set<TParam, TReturn>(
+        func: &NonBlockingThreadsafeFunctionWithReturn<TParam, TReturn>,
+        timeout_ms: Option<u32>,
+    ) {
+        if let Some(timeout_ms) = timeout_ms {
+            func.set_timeout(timeout_ms);
+        }
+    }
+
+    let service = &crate::sync::server::SYNC_SERVICE;
+
+    set(&service.on_sync_requested, timeouts.sync_requested);
+    set(&service.on_my_uuid_requested, timeouts.my_uuid);
+    set(&service.on_now_requested, timeouts.now);
+    set(
+        &service.on_thread_updates_requested,
+        timeouts.thread_updates,
+    );
+    set(
+        &service.on_all_notes_in_thread_requested,
+        timeouts.all_notes_in_thread,
+    );
+    set(
+        &service.on_all_notes_in_tree_requested,
+        timeouts.all_notes_in_tree,
+    );
+    set(
+        &service.on_note_updates_in_thread_requested,
+        timeouts.note_updates_in_thread,
+    );
+    set(
+        &service.on_note_updates_in_tree_requested,
+        timeouts.note_updates_in_tree,
+    );
+    set(
+        &service.on_update_synced_at_requested,
+        timeouts.update_synced_at,
+    );
+}
+
+/// 同期相手から受信するフレーム (レスポンス一つ分、もしくはそのチャンク) の最大サイズ (バイト) を設定する
+/// 既定値は 64 MiB で、超えるものを送ってきた相手とは切断する
+/// 送るフレームにも適用し、超えるレスポンスはそのリクエストを失敗させる
+#[napi]
+pub fn set_max_frame_size(env: Env, bytes: u32) -> napi::Result<()> {
+    sync::frame::set_max_frame_size(bytes).map_err(|e| e.into_js_error(env))
 }
 
 /// 同期がリクエストされたときのコールバックを設定する
-#[napi(ts_args_type = "callback: (err: null | Error, uuid: string) => void")]
+#[napi(ts_args_type = "callback: (err: null | Error, token: number, uuid: string) => void")]
 pub fn set_on_sync_requested(callback: JsFunction) -> napi::Result<()> {
     let tsfn = callback.create_threadsafe_function(
         0,
-        |ctx: ThreadSafeCallContext<RequestParamSyncPermission>| {
-            vec![ctx.value.uuid]
-                .iter()
-                .map(|x| ctx.env.create_string(&x))
-                .collect()
+        |ctx: ThreadSafeCallContext<(u32, RequestParamSyncPermission)>| {
+            let (token, param) = ctx.value;
+            callback_args(&ctx.env, token, &[&param.uuid])
         },
     )?;
 
@@ -244,19 +621,21 @@ pub fn set_on_sync_requested(callback: JsFunction) -> napi::Result<()> {
 }
 
 /// 同期リクエストに対する応答を返す
+/// `token` にはコールバックに渡されたものを指定する
 #[napi]
-pub fn respond_to_sync_request(allow: bool) {
+pub fn respond_to_sync_request(token: u32, allow: bool) {
     crate::sync::server::SYNC_SERVICE
         .on_sync_requested
-        .send_result(allow);
+        .send_result(token, allow);
 }
 
 /// 自身のデバイス ID がリクエストされたときのコールバックを設定する
-#[napi(ts_args_type = "callback: (err: null | Error) => void")]
+#[napi(ts_args_type = "callback: (err: null | Error, token: number) => void")]
 pub fn set_on_my_uuid_requested(callback: JsFunction) -> napi::Result<()> {
-    let tsfn = callback.create_threadsafe_function(0, |_: ThreadSafeCallContext<()>| {
-        Ok::<Vec<()>, napi::Error>(vec![])
-    })?;
+    let tsfn = callback
+        .create_threadsafe_function(0, |ctx: ThreadSafeCallContext<(u32, ())>| {
+            callback_args(&ctx.env, ctx.value.0, &[])
+        })?;
 
     crate::sync::server::SYNC_SERVICE
         .on_my_uuid_requested
@@ -265,12 +644,22 @@ pub fn set_on_my_uuid_requested(callback: JsFunction) -> napi::Result<()> {
     Ok(())
 }
 
+/// 自身のデバイス ID 取得リクエストに対する応答を返す
+/// `token` にはコールバックに渡されたものを指定する
+#[napi]
+pub fn respond_to_my_uuid_request(token: u32, my_uuid: String) {
+    crate::sync::server::SYNC_SERVICE
+        .on_my_uuid_requested
+        .send_result(token, my_uuid);
+}
+
 /// 現在時刻がリクエストされたときのコールバックを設定する
-#[napi(ts_args_type = "callback: (err: null | Error) => void")]
+#[napi(ts_args_type = "callback: (err: null | Error, token: number) => void")]
 pub fn set_on_now_requested(callback: JsFunction) -> napi::Result<()> {
-    let tsfn = callback.create_threadsafe_function(0, |_: ThreadSafeCallContext<()>| {
-        Ok::<Vec<()>, napi::Error>(vec![])
-    })?;
+    let tsfn = callback
+        .create_threadsafe_function(0, |ctx: ThreadSafeCallContext<(u32, ())>| {
+            callback_args(&ctx.env, ctx.value.0, &[])
+        })?;
 
     crate::sync::server::SYNC_SERVICE
         .on_now_requested
@@ -279,63 +668,52 @@ pub fn set_on_now_requested(callback: JsFunction) -> napi::Result<()> {
     Ok(())
 }
 
-/// 自身のデバイス ID 取得リクエストに対する応答を返す
-#[napi]
-pub fn respond_to_my_uuid_request(my_uuid: String) {
-    crate::sync::server::SYNC_SERVICE
-        .on_my_uuid_requested
-        .send_result(my_uuid);
-}
-
 /// 現在時刻リクエストに対する応答を返す
+/// `token` にはコールバックに渡されたものを指定する
 #[napi]
-pub fn respond_to_now_request(now: String) {
+pub fn respond_to_now_request(token: u32, now: String) {
     crate::sync::server::SYNC_SERVICE
         .on_now_requested
-        .send_result(now);
+        .send_result(token, now);
 }
 
 /// スレッドの更新差分がリクエストされたときのコールバックを設定する
-#[napi(ts_args_type = "callback: (err: null | Error, uuid: string, updatedEnd: string) => void")]
+#[napi(
+    ts_args_type = "callback: (err: null | Error, token: number, uuid: string, updatedEnd: string) => void"
+)]
 pub fn set_on_thread_updates_requested(callback: JsFunction) -> napi::Result<()> {
     let tsfn = callback.create_threadsafe_function(
         0,
-        |ctx: ThreadSafeCallContext<RequestParamThreadUpdates>| {
-            vec![ctx.value.uuid, ctx.value.updated_end]
-                .iter()
-                .map(|x| ctx.env.create_string(&x))
-                .collect()
+        |ctx: ThreadSafeCallContext<(u32, RequestParamThreadUpdates)>| {
+            let (token, param) = ctx.value;
+            callback_args(&ctx.env, token, &[&param.uuid, &param.updated_end])
         },
     )?;
 
-    let mut callback = crate::sync::server::SYNC_SERVICE
+    crate::sync::server::SYNC_SERVICE
         .on_thread_updates_requested
-        .func
-        .lock()
-        .unwrap();
-    *callback = Some(tsfn);
+        .set_callback(tsfn);
 
     Ok(())
 }
 
 /// スレッド更新差分リクエストに対する応答を返す
+/// `token` にはコールバックに渡されたものを指定する
 #[napi]
-pub fn respond_to_thread_updates_request(json: String) {
+pub fn respond_to_thread_updates_request(token: u32, json: String) {
     crate::sync::server::SYNC_SERVICE
         .on_thread_updates_requested
-        .send_result(json);
+        .send_result(token, json);
 }
 
 /// 指定スレッド内のメモの内容の送信をリクエストされたときのコールバックを設定する
-#[napi(ts_args_type = "callback: (err: null | Error, threadId: string) => void")]
+#[napi(ts_args_type = "callback: (err: null | Error, token: number, threadId: string) => void")]
 pub fn set_on_all_notes_in_thread_requested(callback: JsFunction) -> napi::Result<()> {
     let tsfn = callback.create_threadsafe_function(
         0,
-        |ctx: ThreadSafeCallContext<RequestParamAllNotesInThread>| {
-            vec![ctx.value.thread_id]
-                .iter()
-                .map(|x| ctx.env.create_string(&x))
-                .collect()
+        |ctx: ThreadSafeCallContext<(u32, RequestParamAllNotesInThread)>| {
+            let (token, param) = ctx.value;
+            callback_args(&ctx.env, token, &[&param.thread_id])
         },
     )?;
 
@@ -347,23 +725,22 @@ pub fn set_on_all_notes_in_thread_requested(callback: JsFunction) -> napi::Resul
 }
 
 /// 指定スレッド内のメモの内容の送信リクエストに対する応答を返す
+/// `token` にはコールバックに渡されたものを指定する
 #[napi]
-pub fn respond_to_all_notes_in_thread_request(json: String) {
+pub fn respond_to_all_notes_in_thread_request(token: u32, json: String) {
     crate::sync::server::SYNC_SERVICE
         .on_all_notes_in_thread_requested
-        .send_result(json);
+        .send_result(token, json);
 }
 
 /// 指定ツリー内のメモの内容の送信をリクエストされたときのコールバックを設定する
-#[napi(ts_args_type = "callback: (err: null | Error, parentId: string) => void")]
+#[napi(ts_args_type = "callback: (err: null | Error, token: number, parentId: string) => void")]
 pub fn set_on_all_notes_in_tree_requested(callback: JsFunction) -> napi::Result<()> {
     let tsfn = callback.create_threadsafe_function(
         0,
-        |ctx: ThreadSafeCallContext<RequestParamAllNotesInTree>| {
-            vec![ctx.value.parent_id]
-                .iter()
-                .map(|x| ctx.env.create_string(&x))
-                .collect()
+        |ctx: ThreadSafeCallContext<(u32, RequestParamAllNotesInTree)>| {
+            let (token, param) = ctx.value;
+            callback_args(&ctx.env, token, &[&param.parent_id])
         },
     )?;
 
@@ -375,25 +752,28 @@ pub fn set_on_all_notes_in_tree_requested(callback: JsFunction) -> napi::Result<
 }
 
 /// 指定ツリー内のメモの内容の送信リクエストに対する応答を返す
+/// `token` にはコールバックに渡されたものを指定する
 #[napi]
-pub fn respond_to_all_notes_in_tree_request(json: String) {
+pub fn respond_to_all_notes_in_tree_request(token: u32, json: String) {
     crate::sync::server::SYNC_SERVICE
         .on_all_notes_in_tree_requested
-        .send_result(json);
+        .send_result(token, json);
 }
 
 /// 指定スレッド内のメモの更新差分の送信をリクエストされたときのコールバックを設定する
 #[napi(
-    ts_args_type = "callback: (err: null | Error, uuid: string, threadId: string, updatedEnd: string) => void"
+    ts_args_type = "callback: (err: null | Error, token: number, uuid: string, threadId: string, updatedEnd: string) => void"
 )]
 pub fn set_on_note_updates_in_thread_requested(callback: JsFunction) -> napi::Result<()> {
     let tsfn = callback.create_threadsafe_function(
         0,
-        |ctx: ThreadSafeCallContext<RequestParamNoteUpdatesInThread>| {
-            vec![ctx.value.uuid, ctx.value.thread_id, ctx.value.updated_end]
-                .iter()
-                .map(|x| ctx.env.create_string(&x))
-                .collect()
+        |ctx: ThreadSafeCallContext<(u32, RequestParamNoteUpdatesInThread)>| {
+            let (token, param) = ctx.value;
+            callback_args(
+                &ctx.env,
+                token,
+                &[&param.uuid, &param.thread_id, &param.updated_end],
+            )
         },
     )?;
 
@@ -405,25 +785,28 @@ pub fn set_on_note_updates_in_thread_requested(callback: JsFunction) -> napi::Re
 }
 
 /// 指定スレッド内のメモの更新差分の送信リクエストに対する応答を返す
+/// `token` にはコールバックに渡されたものを指定する
 #[napi]
-pub fn respond_to_note_updates_in_thread_request(json: String) {
+pub fn respond_to_note_updates_in_thread_request(token: u32, json: String) {
     crate::sync::server::SYNC_SERVICE
         .on_note_updates_in_thread_requested
-        .send_result(json);
+        .send_result(token, json);
 }
 
 /// 指定ツリー内のメモの更新差分の送信をリクエストされたときのコールバックを設定する
 #[napi(
-    ts_args_type = "callback: (err: null | Error, uuid: string, parentId: string, updatedEnd: string) => void"
+    ts_args_type = "callback: (err: null | Error, token: number, uuid: string, parentId: string, updatedEnd: string) => void"
 )]
 pub fn set_on_note_updates_in_tree_requested(callback: JsFunction) -> napi::Result<()> {
     let tsfn = callback.create_threadsafe_function(
         0,
-        |ctx: ThreadSafeCallContext<RequestParamNoteUpdatesInTree>| {
-            vec![ctx.value.uuid, ctx.value.parent_id, ctx.value.updated_end]
-                .iter()
-                .map(|x| ctx.env.create_string(&x))
-                .collect()
+        |ctx: ThreadSafeCallContext<(u32, RequestParamNoteUpdatesInTree)>| {
+            let (token, param) = ctx.value;
+            callback_args(
+                &ctx.env,
+                token,
+                &[&param.uuid, &param.parent_id, &param.updated_end],
+            )
         },
     )?;
 
@@ -435,23 +818,24 @@ pub fn set_on_note_updates_in_tree_requested(callback: JsFunction) -> napi::Resu
 }
 
 /// 指定ツリー内のメモの更新差分の送信リクエストに対する応答を返す
+/// `token` にはコールバックに渡されたものを指定する
 #[napi]
-pub fn respond_to_note_updates_in_tree_request(json: String) {
+pub fn respond_to_note_updates_in_tree_request(token: u32, json: String) {
     crate::sync::server::SYNC_SERVICE
         .on_note_updates_in_tree_requested
-        .send_result(json);
+        .send_result(token, json);
 }
 
 /// 同期時刻の保存をリクエストされたときのコールバックを設定する
-#[napi(ts_args_type = "callback: (err: null | Error, uuid: string, updatedEnd: string) => void")]
+#[napi(
+    ts_args_type = "callback: (err: null | Error, token: number, uuid: string, updatedEnd: string) => void"
+)]
 pub fn set_on_update_synced_at_requested(callback: JsFunction) -> napi::Result<()> {
     let tsfn = callback.create_threadsafe_function(
         0,
-        |ctx: ThreadSafeCallContext<RequestParamUpdateSyncedAt>| {
-            vec![ctx.value.uuid, ctx.value.updated_end]
-                .iter()
-                .map(|x| ctx.env.create_string(&x))
-                .collect()
+        |ctx: ThreadSafeCallContext<(u32, RequestParamUpdateSyncedAt)>| {
+            let (token, param) = ctx.value;
+            callback_args(&ctx.env, token, &[&param.uuid, &param.updated_end])
         },
     )?;
 
@@ -463,64 +847,190 @@ pub fn set_on_update_synced_at_requested(callback: JsFunction) -> napi::Result<(
 }
 
 /// 同期時刻の保存リクエストに対する応答を返す
+/// `token` にはコールバックに渡されたものを指定する
 #[napi]
-pub fn respond_to_update_synced_at_request() {
+pub fn respond_to_update_synced_at_request(token: u32) {
     crate::sync::server::SYNC_SERVICE
         .on_update_synced_at_requested
-        .send_result(());
+        .send_result(token, ());
 }
 
+/// 同期サーバへのリクエストが、結果を返す前に中断されたときのコールバックを設定する
+/// クライアントがリクエストを取り消した場合などに呼ばれるので、`name` のコールバックの
+/// `token` の呼び出しで実行中の処理をやめてよい (応答を返しても無視される)
+#[napi(ts_args_type = "callback: (err: null | Error, name: string, token: number) => void")]
+pub fn set_on_sync_request_cancelled(callback: JsFunction) -> napi::Result<()> {
+    let tsfn = callback.create_threadsafe_function(
+        0,
+        |ctx: ThreadSafeCallContext<(&'static str, u32)>| {
+            let (name, token) = ctx.value;
+
+            Ok(vec![
+                ctx.env.create_string(name)?.into_unknown(),
+                ctx.env.create_uint32(token)?.into_unknown(),
+            ])
+        },
+    )?;
+
+    let mut callback = crate::sync::server::ON_REQUEST_CANCELLED.lock().unwrap();
+    *callback = Some(tsfn);
+
+    Ok(())
+}
+
+#[cfg(windows)]
 pub struct InitServerStartTask {
     my_uuid: String,
+    my_hello: init::Hello,
 }
 
+#[cfg(windows)]
 impl Task for InitServerStartTask {
-    type Output = ();
+    type Output = Result<()>;
     type JsValue = JsUndefined;
 
     fn compute(&mut self) -> napi::Result<Self::Output> {
-        RUNTIME.block_on(crate::init::server::start(self.my_uuid.to_owned()))?;
-        Ok(())
+        Ok(RUNTIME.block_on(crate::winrt::init_server::start(
+            self.my_uuid.to_owned(),
+            self.my_hello.clone(),
+        )))
     }
 
-    fn resolve(&mut self, env: Env, _: Self::Output) -> napi::Result<Self::JsValue> {
+    fn resolve(&mut self, env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        output.map_err(|e| e.into_js_error(env))?;
         env.get_undefined()
     }
 }
 
+#[cfg(windows)]
 pub struct InitClientTask {
     windows_device_id: String,
     my_uuid: String,
+    my_hello: init::Hello,
 }
 
+/// 初期設定で相手に送る、このデバイスの情報
+/// プラットフォーム・同期プロトコルのバージョン・機能はこのライブラリが補う
+#[napi(object)]
+pub struct MyDeviceInfo {
+    /// 相手に表示してもらう名前
+    pub display_name: String,
+    pub app_version: String,
+}
+
+impl MyDeviceInfo {
+    fn into_hello(info: Option<Self>) -> init::Hello {
+        match info {
+            Some(info) => init::Hello::local(&info.display_name, &info.app_version),
+            None => init::Hello::local("", ""),
+        }
+    }
+}
+
+/// 初期設定で相手が送ってきた自己紹介
+#[napi(object)]
+pub struct CompanionHello {
+    /// 相手のユーザに設定された名前 (空の場合は Bluetooth のデバイス名を使うこと)
+    pub display_name: String,
+    /// `windows` / `android` など
+    pub platform: String,
+    pub app_version: String,
+    /// 相手が対応している同期プロトコルのバージョン
+    pub protocol_versions: Vec<u32>,
+    /// 相手が同期で使える機能のフラグ (docs/protocol_v2.md)
+    pub capabilities: u32,
+}
+
+impl From<init::Hello> for CompanionHello {
+    fn from(hello: init::Hello) -> Self {
+        Self {
+            display_name: hello.display_name,
+            platform: hello.platform,
+            app_version: hello.app_version,
+            protocol_versions: hello.protocol_versions.into_iter().map(u32::from).collect(),
+            capabilities: hello.capabilities,
+        }
+    }
+}
+
+impl CompanionHello {
+    /// コールバックの引数に渡すオブジェクトを作る
+    fn create_object(self, env: &Env) -> napi::Result<napi::JsObject> {
+        let mut object = env.create_object()?;
+        let mut protocol_versions = env.create_array_with_length(self.protocol_versions.len())?;
+
+        for (i, version) in self.protocol_versions.into_iter().enumerate() {
+            protocol_versions.set_element(i as u32, env.create_uint32(version)?)?;
+        }
+
+        object.set_named_property("displayName", env.create_string(&self.display_name)?)?;
+        object.set_named_property("platform", env.create_string(&self.platform)?)?;
+        object.set_named_property("appVersion", env.create_string(&self.app_version)?)?;
+        object.set_named_property("protocolVersions", protocol_versions)?;
+        object.set_named_property("capabilities", env.create_uint32(self.capabilities)?)?;
+
+        Ok(object)
+    }
+}
+
+/// 初期設定で交換した、同期相手の情報
+#[napi(object)]
+pub struct PairedCompanion {
+    /// 相手のデバイスの UUID
+    pub uuid: String,
+    /// 相手と共有した鍵の指紋 (鍵の交換に対応していない相手なら `undefined`)
+    /// 鍵は信頼するデバイスの一覧 (`openTrustStore`) に保存する
+    pub fingerprint: Option<String>,
+    /// 相手の自己紹介 (自己紹介に対応していない相手なら `undefined`)
+    pub hello: Option<CompanionHello>,
+}
+
+impl From<init::Paired> for PairedCompanion {
+    fn from(paired: init::Paired) -> Self {
+        Self {
+            uuid: paired.uuid,
+            fingerprint: paired
+                .pairing_key
+                .as_ref()
+                .map(sync::auth::PairingKey::fingerprint),
+            hello: paired.hello.map(CompanionHello::from),
+        }
+    }
+}
+
+#[cfg(windows)]
 impl Task for InitClientTask {
-    type Output = String;
-    type JsValue = JsString;
+    type Output = Result<init::Paired>;
+    type JsValue = PairedCompanion;
 
     fn compute(&mut self) -> napi::Result<Self::Output> {
-        let future = crate::init::client::init(&self.windows_device_id, &self.my_uuid);
-        Ok(RUNTIME.block_on(future)?)
+        let future =
+            crate::winrt::init_client::init(&self.windows_device_id, &self.my_uuid, &self.my_hello);
+        Ok(RUNTIME.block_on(future))
     }
 
-    fn resolve(&mut self, env: Env, uuid: Self::Output) -> napi::Result<Self::JsValue> {
-        env.create_string(&uuid)
+    fn resolve(&mut self, env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        output
+            .map(PairedCompanion::from)
+            .map_err(|e| e.into_js_error(env))
     }
 }
 
+#[cfg(windows)]
 pub struct EnumerateSyncCompanionsTask {}
 
+#[cfg(windows)]
 impl Task for EnumerateSyncCompanionsTask {
-    type Output = Vec<String>;
+    type Output = Result<Vec<String>>;
     type JsValue = Array;
 
     fn compute(&mut self) -> napi::Result<Self::Output> {
-        match RUNTIME.block_on(crate::sync::client::enumerate_sync_companions()) {
-            Ok(v) => Ok(v),
-            Err(e) => Err(napi::Error::from_reason(e.message().to_string())),
-        }
+        let future = crate::winrt::rfcomm::enumerate_sync_companions();
+        Ok(RUNTIME.block_on(future).map_err(error::Error::from))
     }
 
     fn resolve(&mut self, env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        let output = output.map_err(|e| e.into_js_error(env))?;
         let mut array = env.create_array(output.len() as u32)?;
 
         for i in 0..output.len() {
@@ -531,73 +1041,146 @@ impl Task for EnumerateSyncCompanionsTask {
     }
 }
 
+/// コールバックの引数 (呼び出しの ID と、文字列の引数) を作る
+fn callback_args(env: &Env, token: u32, args: &[&str]) -> napi::Result<Vec<JsUnknown>> {
+    let mut values = vec![env.create_uint32(token)?.into_unknown()];
+
+    for arg in args {
+        values.push(env.create_string(arg)?.into_unknown());
+    }
+
+    Ok(values)
+}
+
 /// napi-rs の ThreadsafeFunction の戻り値を得たい！
+///
+/// 呼び出しごとに ID (token) を振ってコールバックに渡し、`send_result` で
+/// 同じ ID とともに返された結果を、その呼び出しの戻り値とする
+/// 複数の呼び出しを同時に待つことができ、結果はどの順番で返してもよい
+/// タイムアウトまでに結果が返されなければ `Error::Timeout` になる
 pub struct NonBlockingThreadsafeFunctionWithReturn<TParam, TResult>
 where
     TParam: 'static,
 {
-    func: Mutex<Option<ThreadsafeFunction<TParam>>>,
-    result_sender: Mutex<Option<tokio::sync::oneshot::Sender<TResult>>>,
+    /// コールバックの名前 (タイムアウトしたときの `phase` に使う)
+    name: &'static str,
+    func: Mutex<Option<ThreadsafeFunction<(u32, TParam)>>>,
+    result_senders: Mutex<BTreeMap<u32, tokio::sync::oneshot::Sender<TResult>>>,
+    next_token: AtomicU32,
+    /// タイムアウト (ミリ秒)
+    timeout_ms: AtomicU32,
 }
 
 impl<TParam, TReturn> NonBlockingThreadsafeFunctionWithReturn<TParam, TReturn> {
-    pub const fn new() -> Self {
+    pub const fn new(name: &'static str, timeout_ms: u32) -> Self {
         Self {
+            name,
             func: Mutex::new(None),
-            result_sender: Mutex::new(None),
+            result_senders: Mutex::new(BTreeMap::new()),
+            next_token: AtomicU32::new(0),
+            timeout_ms: AtomicU32::new(timeout_ms),
         }
     }
 
     pub async fn call(&self, param: TParam) -> Result<TReturn> {
-        // 応答の送受信チャンネルを作成
-        let rx: tokio::sync::oneshot::Receiver<TReturn>;
-        {
-            let mut sender = self.result_sender.lock().unwrap();
-
-            if sender.is_some() {
-                return Err(crate::error::Error::SyncError(format!(
-                    "There exists another call"
-                )));
-            }
-
-            let tx: tokio::sync::oneshot::Sender<TReturn>;
-
-            (tx, rx) = tokio::sync::oneshot::channel();
-            *sender = Some(tx);
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+
+        // コールバックがなければ、呼び出しを登録せずに失敗する
+        // (登録してしまうと、`PendingCall` が取り消しを JavaScript に知らせてしまう)
+        let (rx, _pending) = {
+            let func = self.func.lock().unwrap();
+            let Some(func) = &*func else {
+                return Err(crate::error::Error::Other(
+                    "Callback function is not found".to_owned(),
+                ));
+            };
+
+            // 応答の送受信チャンネルを作成
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            self.result_senders.lock().unwrap().insert(token, tx);
+
+            // タイムアウトや、セッションの切断で呼び出しが中断された場合も送信側を片付ける
+            let pending = PendingCall {
+                name: self.name,
+                result_senders: &self.result_senders,
+                token,
+            };
+
+            func.call(
+                Ok((token, param)),
+                napi::threadsafe_function::ThreadsafeFunctionCallMode::NonBlocking,
+            );
+
+            (rx, pending)
+        };
+
+        let timeout = Duration::from_millis(self.timeout_ms.load(Ordering::Relaxed) as u64);
+
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(_)) => Err(crate::error::Error::Other(
+                "Callback result was dropped".to_owned(),
+            )),
+            Err(_) => Err(crate::error::Error::Timeout { phase: self.name }),
         }
+    }
 
-        let func = self.func.lock().unwrap();
+    /// タイムアウトを変更する
+    /// 実行中の呼び出しには影響しない
+    pub fn set_timeout(&self, timeout_ms: u32) {
+        self.timeout_ms.store(timeout_ms, Ordering::Relaxed);
+    }
 
-        match &*func {
-            Some(func) => {
-                func.call(
-                    Ok(param),
-                    napi::threadsafe_function::ThreadsafeFunctionCallMode::NonBlocking,
-                );
+    pub fn send_result(&self, token: u32, result: TReturn) {
+        let tx = self.result_senders.lock().unwrap().remove(&token);
+
+        match tx {
+            Some(tx) => {
+                if tx.send(result).is_err() {
+                    // 例外出すのはなんか違う気がするんだよなー
+                    println!("Warning: failed to send response. May be already timed out?")
+                }
             }
             None => {
-                return Err(crate::error::Error::SyncError(format!(
-                    "Callback function is found"
-                )));
+                println!(
+                    "Warning: unknown token: {}. May be already timed out?",
+                    token
+                );
             }
         }
+    }
 
-        Ok(rx.await.unwrap())
+    pub fn set_callback(&self, tsfn: ThreadsafeFunction<(u32, TParam)>) {
+        let mut callback = self.func.lock().unwrap();
+        *callback = Some(tsfn);
     }
+}
 
-    pub fn send_result(&self, result: TReturn) {
-        let mut tx = self.result_sender.lock().unwrap();
+/// 結果待ちの呼び出し
+/// drop されたら、まだ結果が返されていない送信側を取り除く
+/// 結果を待たずに中断された (取り消し・タイムアウト・切断) 場合は、JavaScript にも知らせる
+struct PendingCall<'a, TResult> {
+    name: &'static str,
+    result_senders: &'a Mutex<BTreeMap<u32, tokio::sync::oneshot::Sender<TResult>>>,
+    token: u32,
+}
 
-        if let Some(tx) = tx.take() {
-            if let Err(_) = tx.send(result) {
-                // 例外出すのはなんか違う気がするんだよなー
-                println!("Warning: failed to send response. May be already timed out?")
+impl<'a, TResult> Drop for PendingCall<'a, TResult> {
+    fn drop(&mut self) {
+        let abandoned = self
+            .result_senders
+            .lock()
+            .unwrap()
+            .remove(&self.token)
+            .is_some();
+
+        if abandoned {
+            if let Some(callback) = &*crate::sync::server::ON_REQUEST_CANCELLED.lock().unwrap() {
+                callback.call(
+                    Ok((self.name, self.token)),
+                    napi::threadsafe_function::ThreadsafeFunctionCallMode::NonBlocking,
+                );
             }
         }
     }
-
-    pub fn set_callback(&self, tsfn: ThreadsafeFunction<TParam>) {
-        let mut callback = self.func.lock().unwrap();
-        *callback = Some(tsfn);
-    }
 }
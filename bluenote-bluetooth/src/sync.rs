@@ -1,7 +1,23 @@
-pub mod async_reader;
-pub mod async_writer;
+pub mod abort;
+pub mod auth;
 pub mod client;
+pub mod compression;
+pub mod encoding;
+pub mod frame;
+pub mod handshake;
+pub mod heartbeat;
+pub mod resume;
+pub mod secure;
 pub mod server;
+pub mod session;
+pub mod tcp;
+pub mod transport;
+#[cfg(unix)]
+pub mod unix;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{error::Error, Result};
 
 const REQUEST_THREAD_UPDATES: u8 = 0;
 const REQUEST_ALL_NOTES_IN_THREAD: u8 = 1;
@@ -12,3 +28,191 @@ const SYNC_ALLOWED: u8 = 5;
 const SYNC_REJECTED: u8 = 6;
 const SYNC_SUCCESS: u8 = 7;
 const SYNC_FAILED: u8 = 8;
+const PING: u8 = 9;
+const CANCEL: u8 = 10;
+
+/// ストリームから UUID 文字列を読み取る
+/// UUID の形式でなければ `Error::ProtocolViolation` を返す
+pub(crate) async fn read_uuid<R>(reader: &mut R) -> Result<String>
+where
+    R: AsyncRead + Unpin,
+{
+    let uuid = read_id(reader).await?;
+
+    if !is_valid_uuid(&uuid) {
+        return Err(Error::ProtocolViolation(format!(
+            "Invalid UUID: {:?}",
+            uuid
+        )));
+    }
+
+    Ok(uuid)
+}
+
+/// ストリームからレスポンスのリクエスト ID (UUID もしくは 36 バイトの空白) を読み取る
+pub(crate) async fn read_response_id<R>(reader: &mut R) -> Result<String>
+where
+    R: AsyncRead + Unpin,
+{
+    let id = read_id(reader).await?;
+
+    if !is_valid_uuid(&id) && id.bytes().any(|b| b != b' ') {
+        return Err(Error::ProtocolViolation(format!(
+            "Invalid response id: {:?}",
+            id
+        )));
+    }
+
+    Ok(id)
+}
+
+/// ストリームから 36 バイトの文字列を読み取る
+async fn read_id<R>(reader: &mut R) -> Result<String>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buffer = vec![0u8; 36];
+
+    reader.read_exact(&mut buffer[..]).await?;
+
+    String::from_utf8(buffer).map_err(|e| Error::ProtocolViolation(format!("{}", e)))
+}
+
+/// UUID の文字列 (例: `0f8fad5b-d9cb-469f-a165-70867728950e`) として正しいか
+pub(crate) fn is_valid_uuid(uuid: &str) -> bool {
+    uuid.len() == 36
+        && uuid.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        })
+}
+
+/// クライアントから送られるリクエスト
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Request {
+    ThreadUpdates,
+    AllNotesInThread {
+        thread_id: String,
+    },
+    AllNotesInTree {
+        parent_id: String,
+    },
+    NoteUpdatesInThread {
+        thread_id: String,
+    },
+    NoteUpdatesInTree {
+        parent_id: String,
+    },
+    SyncSuccess,
+    SyncFailed,
+    /// 接続が生きているかの確認 (`Capabilities::HEARTBEAT` を使う場合のみ)
+    Ping,
+    /// 処理中のリクエストの取り消し (`Capabilities::CANCEL` を使う場合のみ)
+    /// `request_id` は取り消すリクエストのレスポンスの ID
+    Cancel {
+        request_id: String,
+    },
+}
+
+impl Request {
+    /// ストリームからリクエストを一つ読み取る
+    /// 不明なリクエスト ID や不正な UUID の場合は、以降の区切りがわからなくなるので
+    /// `Error::ProtocolViolation` を返す
+    pub(crate) async fn read<R>(reader: &mut R) -> Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let request_id = reader.read_u8().await?;
+
+        let request = match request_id {
+            REQUEST_THREAD_UPDATES => Self::ThreadUpdates,
+            REQUEST_ALL_NOTES_IN_THREAD => Self::AllNotesInThread {
+                thread_id: read_uuid(reader).await?,
+            },
+            REQUEST_ALL_NOTES_IN_TREE => Self::AllNotesInTree {
+                parent_id: read_uuid(reader).await?,
+            },
+            REQUEST_NOTE_UPDATES_IN_THREAD => Self::NoteUpdatesInThread {
+                thread_id: read_uuid(reader).await?,
+            },
+            REQUEST_NOTE_UPDATES_IN_TREE => Self::NoteUpdatesInTree {
+                parent_id: read_uuid(reader).await?,
+            },
+            SYNC_SUCCESS => Self::SyncSuccess,
+            SYNC_FAILED => Self::SyncFailed,
+            PING => Self::Ping,
+            CANCEL => Self::Cancel {
+                request_id: read_response_id(reader).await?,
+            },
+            _ => {
+                return Err(Error::ProtocolViolation(format!(
+                    "Unknown request: {}",
+                    request_id
+                )));
+            }
+        };
+
+        Ok(request)
+    }
+
+    /// リクエストを書き込む
+    pub(crate) async fn write<W>(&self, writer: &mut W) -> tokio::io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let request_id = match self {
+            Self::ThreadUpdates => REQUEST_THREAD_UPDATES,
+            Self::AllNotesInThread { .. } => REQUEST_ALL_NOTES_IN_THREAD,
+            Self::AllNotesInTree { .. } => REQUEST_ALL_NOTES_IN_TREE,
+            Self::NoteUpdatesInThread { .. } => REQUEST_NOTE_UPDATES_IN_THREAD,
+            Self::NoteUpdatesInTree { .. } => REQUEST_NOTE_UPDATES_IN_TREE,
+            Self::SyncSuccess => SYNC_SUCCESS,
+            Self::SyncFailed => SYNC_FAILED,
+            Self::Ping => PING,
+            Self::Cancel { .. } => CANCEL,
+        };
+
+        writer.write_u8(request_id).await?;
+
+        if let Some(uuid) = self.uuid() {
+            writer.write_all(uuid.as_bytes()).await?;
+        }
+        if let Self::Cancel { request_id } = self {
+            writer.write_all(request_id.as_bytes()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// リクエストの対象の UUID
+    fn uuid(&self) -> Option<&str> {
+        match self {
+            Self::AllNotesInThread { thread_id } | Self::NoteUpdatesInThread { thread_id } => {
+                Some(thread_id)
+            }
+            Self::AllNotesInTree { parent_id } | Self::NoteUpdatesInTree { parent_id } => {
+                Some(parent_id)
+            }
+            _ => None,
+        }
+    }
+
+    /// 送信する前に、UUID が正しい形式か確認する
+    pub(crate) fn validate(&self) -> Result<()> {
+        match self.uuid() {
+            Some(uuid) if !is_valid_uuid(uuid) => {
+                Err(Error::InvalidArgument(format!("Invalid UUID: {}", uuid)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// レスポンスに付けるリクエストの ID
+    /// スレッドの更新差分リクエストの時は UUID の代わりに 36 バイトの空白を使う
+    pub(crate) fn response_id(&self) -> String {
+        match self.uuid() {
+            Some(uuid) => uuid.to_owned(),
+            None => " ".repeat(36),
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use napi::{Env, JsFunction, JsObject, Ref};
+use tokio::sync::watch;
+
+/// JavaScript の `AbortSignal` が中断されたことを、Rust 側で待つためのもの
+/// `Default` は中断されることのないもの
+#[derive(Clone, Default)]
+pub struct AbortWatcher(Option<watch::Receiver<bool>>);
+
+impl AbortWatcher {
+    /// 中断を通知する送信側と一緒に作る
+    /// `aborted` は最初から中断されているか
+    pub fn channel(aborted: bool) -> (watch::Sender<bool>, Self) {
+        let (tx, rx) = watch::channel(aborted);
+
+        (tx, Self(Some(rx)))
+    }
+
+    /// すでに中断されているか
+    pub fn is_aborted(&self) -> bool {
+        self.0.as_ref().is_some_and(|rx| *rx.borrow())
+    }
+
+    /// 中断されるまで待つ
+    /// 中断されないまま送信側がなくなった場合は、ずっと待ち続ける
+    pub async fn aborted(&self) {
+        if let Some(rx) = &self.0 {
+            if rx.clone().wait_for(|aborted| *aborted).await.is_ok() {
+                return;
+            }
+        }
+
+        std::future::pending().await
+    }
+}
+
+/// `AbortSignal` に登録したリスナー
+/// 処理が終わったら JavaScript のスレッドで `remove` して、登録を解除すること (`Task::finally` など)
+pub struct AbortListener {
+    signal: Ref<()>,
+    listener: Ref<()>,
+}
+
+impl AbortListener {
+    /// `signal` の `abort` イベントを `AbortWatcher` に伝えるリスナーを登録する
+    /// `signal` が指定されなかった場合は、中断されることのない `AbortWatcher` を返す
+    pub fn listen(
+        env: &Env,
+        signal: Option<JsObject>,
+    ) -> napi::Result<(AbortWatcher, Option<AbortListener>)> {
+        let signal = match signal {
+            Some(signal) => signal,
+            None => return Ok((AbortWatcher::default(), None)),
+        };
+
+        let (tx, watcher) = AbortWatcher::channel(signal.get_named_property("aborted")?);
+        let listener = env.create_function_from_closure("onAbort", move |ctx| {
+            let _ = tx.send(true);
+            ctx.env.get_undefined()
+        })?;
+
+        let listener = AbortListener {
+            signal: env.create_reference(signal)?,
+            listener: env.create_reference(listener)?,
+        };
+
+        if let Err(e) = listener.call_on_signal(env, "addEventListener") {
+            listener.release(*env)?;
+            return Err(e);
+        }
+
+        Ok((watcher, Some(listener)))
+    }
+
+    /// リスナーの登録を解除する
+    /// 同じ `AbortSignal` を使い回しても、リスナーが溜まらないようにする
+    pub fn remove(self, env: Env) -> napi::Result<()> {
+        let result = self.call_on_signal(&env, "removeEventListener");
+        self.release(env)?;
+
+        result
+    }
+
+    /// `signal.addEventListener("abort", listener)` などを呼ぶ
+    fn call_on_signal(&self, env: &Env, method: &str) -> napi::Result<()> {
+        let signal: JsObject = env.get_reference_value(&self.signal)?;
+        let listener: JsFunction = env.get_reference_value(&self.listener)?;
+        let method: JsFunction = signal.get_named_property(method)?;
+
+        method.call(
+            Some(&signal),
+            &[
+                env.create_string("abort")?.into_unknown(),
+                listener.into_unknown(),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn release(mut self, env: Env) -> napi::Result<()> {
+        self.signal.unref(env)?;
+        self.listener.unref(env)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,499 @@
+use std::{collections::HashMap, fmt};
+
+use hmac::{Hmac, Mac};
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use super::{
+    handshake::{Advertised, Capabilities},
+    secure::{SessionKeys, SESSION_KEY_LEN},
+    SYNC_REJECTED,
+};
+use crate::{error::Error, Result};
+
+/// ペアリングで共有する鍵の長さ (バイト)
+pub const PAIRING_KEY_LEN: usize = 32;
+
+/// チャレンジ (使い捨ての X25519 公開鍵) の長さ (バイト)
+const CHALLENGE_LEN: usize = 32;
+
+/// 証明 (HMAC-SHA256) の長さ (バイト)
+const PROOF_LEN: usize = 32;
+
+/// 指紋に使うハッシュの長さ (バイト)
+const FINGERPRINT_LEN: usize = 10;
+
+/// 鍵を共有していない相手に送る証明
+/// (以前のバージョンでペアリングした相手)
+const NO_PROOF: [u8; PROOF_LEN] = [0; PROOF_LEN];
+
+/// 相手のデバイスの UUID ごとの、ペアリングで共有した鍵
+pub type PairingKeys = HashMap<String, PairingKey>;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// ペアリング (初期設定) で相手と共有した鍵
+/// アプリとは 16 進数の文字列でやりとりする
+#[derive(Clone, PartialEq, Eq)]
+pub struct PairingKey([u8; PAIRING_KEY_LEN]);
+
+impl PairingKey {
+    /// 鍵交換の結果から、ペアリングの鍵を作る
+    /// `public_keys` はどちらの側でも同じになるよう、並べ替えてから使う
+    pub(crate) fn derive(shared_secret: &[u8], mut public_keys: [[u8; 32]; 2]) -> Self {
+        public_keys.sort();
+
+        let mut mac = HmacSha256::new_from_slice(shared_secret).unwrap();
+        mac.update(b"bluenote-pairing");
+        mac.update(&public_keys[0]);
+        mac.update(&public_keys[1]);
+
+        Self(mac.finalize().into_bytes().into())
+    }
+
+    /// 16 進数の文字列から読み込む
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let invalid = || Error::InvalidArgument("Invalid pairing key".to_owned());
+
+        if hex.len() != PAIRING_KEY_LEN * 2 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+
+        let mut key = [0; PAIRING_KEY_LEN];
+
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+
+        Ok(Self(key))
+    }
+
+    /// 16 進数の文字列にする
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// 鍵の指紋 (例: `1A2B-3C4D-5E6F-7A8B-9C0D`)
+    /// 両方のデバイスで同じになるので、画面に表示して同じ相手とペアリングしたか確かめられる
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(b"bluenote-fingerprint");
+        hasher.update(self.0);
+        let digest = hasher.finalize();
+
+        digest[..FINGERPRINT_LEN]
+            .chunks(2)
+            .map(|chunk| format!("{:02X}{:02X}", chunk[0], chunk[1]))
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    /// `role` の側が鍵を持っていることの証明
+    fn prove(&self, role: Role, transcript: &Transcript) -> [u8; PROOF_LEN] {
+        self.mac(role, transcript).finalize().into_bytes().into()
+    }
+
+    /// 相手から受信した証明を (一定時間で) 検証する
+    fn verify(&self, role: Role, transcript: &Transcript, proof: &[u8]) -> bool {
+        self.mac(role, transcript).verify_slice(proof).is_ok()
+    }
+
+    /// 初期設定で、`public_key` を送った側が同じ鍵を作れたことの確認
+    pub(crate) fn confirmation(&self, public_key: &[u8; 32]) -> [u8; PROOF_LEN] {
+        self.confirmation_mac(public_key)
+            .finalize()
+            .into_bytes()
+            .into()
+    }
+
+    /// 相手から受信した確認を (一定時間で) 検証する
+    pub(crate) fn verify_confirmation(&self, public_key: &[u8; 32], confirmation: &[u8]) -> bool {
+        self.confirmation_mac(public_key)
+            .verify_slice(confirmation)
+            .is_ok()
+    }
+
+    /// `role` の側が送信に使うセッション鍵
+    /// 鍵交換の結果とペアリングの鍵の両方から導出するので、どちらか一方だけでは求められない
+    fn session_key(
+        &self,
+        role: Role,
+        transcript: &Transcript,
+        shared_secret: &[u8],
+    ) -> [u8; SESSION_KEY_LEN] {
+        let mut mac = HmacSha256::new_from_slice(&self.0).unwrap();
+        mac.update(b"bluenote-session-key");
+        mac.update(shared_secret);
+        transcript.update(&mut mac, role);
+
+        mac.finalize().into_bytes().into()
+    }
+
+    fn confirmation_mac(&self, public_key: &[u8; 32]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.0).unwrap();
+        mac.update(b"bluenote-pairing-confirm");
+        mac.update(public_key);
+
+        mac
+    }
+
+    fn mac(&self, role: Role, transcript: &Transcript) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.0).unwrap();
+        transcript.update(&mut mac, role);
+
+        mac
+    }
+}
+
+// 鍵がログに出ないようにする
+impl fmt::Debug for PairingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PairingKey(..)")
+    }
+}
+
+/// 同期での役割
+/// 相手の証明をそのまま送り返して (リフレクション) 認証されないよう、証明に含める
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+impl Role {
+    fn label(self) -> &'static [u8] {
+        match self {
+            Self::Client => b"bluenote-sync-client",
+            Self::Server => b"bluenote-sync-server",
+        }
+    }
+
+    fn peer(self) -> Self {
+        match self {
+            Self::Client => Self::Server,
+            Self::Server => Self::Client,
+        }
+    }
+}
+
+/// 証明の対象 (両者の UUID とチャレンジ、両者が送った機能のフラグ)
+/// 送ったフラグをそのまま含めるので、途中で (片方でも両方でも) フラグを書き換えて暗号化を外させることはできない
+struct Transcript<'a> {
+    client_uuid: &'a str,
+    server_uuid: &'a str,
+    client_challenge: [u8; CHALLENGE_LEN],
+    server_challenge: [u8; CHALLENGE_LEN],
+    client_capabilities: Capabilities,
+    server_capabilities: Capabilities,
+}
+
+impl Transcript<'_> {
+    fn update(&self, mac: &mut HmacSha256, role: Role) {
+        mac.update(role.label());
+        mac.update(self.client_uuid.as_bytes());
+        mac.update(self.server_uuid.as_bytes());
+        mac.update(&self.client_challenge);
+        mac.update(&self.server_challenge);
+        mac.update(&self.client_capabilities.bits().to_le_bytes());
+        mac.update(&self.server_capabilities.bits().to_le_bytes());
+    }
+}
+
+/// ペアリングで共有した鍵を持っていることを、相手と互いに証明する
+///
+/// 両者がチャレンジ (使い捨ての X25519 公開鍵) を送り合い、両者の UUID・チャレンジに対する HMAC を送り合う
+/// `key` が `None` (鍵を共有せずにペアリングした相手) の場合は、相手の証明を確かめない
+/// 相手の証明が正しくなければ `Error::AuthenticationFailed` を返す
+///
+/// `advertised` はバージョンの交換で送り合ったフラグ
+/// 両者が使う機能に `Capabilities::ENCRYPT` が含まれていて鍵がある場合は、
+/// チャレンジの鍵交換とペアリングの鍵から、以降の通信を暗号化するセッション鍵を導出して返す
+pub async fn authenticate<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    role: Role,
+    key: Option<&PairingKey>,
+    advertised: Advertised,
+    my_uuid: &str,
+    their_uuid: &str,
+) -> Result<Option<SessionKeys>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let my_challenge = *PublicKey::from(&secret).as_bytes();
+
+    let their_challenge = exchange(reader, writer, &my_challenge).await?;
+
+    let transcript = match role {
+        Role::Client => Transcript {
+            client_uuid: my_uuid,
+            server_uuid: their_uuid,
+            client_challenge: my_challenge,
+            server_challenge: their_challenge,
+            client_capabilities: advertised.ours,
+            server_capabilities: advertised.theirs,
+        },
+        Role::Server => Transcript {
+            client_uuid: their_uuid,
+            server_uuid: my_uuid,
+            client_challenge: their_challenge,
+            server_challenge: my_challenge,
+            client_capabilities: advertised.theirs,
+            server_capabilities: advertised.ours,
+        },
+    };
+
+    let my_proof = match key {
+        Some(key) => key.prove(role, &transcript),
+        None => NO_PROOF,
+    };
+    let their_proof = exchange(reader, writer, &my_proof).await?;
+
+    let key = match key {
+        // 信頼を取り消したサーバは鍵を持っていないので証明を送らず、続けて同期を拒否 (`6`) する
+        // 拒否を受信できたら、認証の失敗ではなく、このデバイスの信頼が取り消されたとして返す
+        Some(_) if role == Role::Client && their_proof == NO_PROOF => {
+            return Err(match reader.read_u8().await {
+                Ok(SYNC_REJECTED) => Error::DeviceRevoked(my_uuid.to_owned()),
+                _ => Error::AuthenticationFailed(their_uuid.to_owned()),
+            });
+        }
+        Some(key) if !key.verify(role.peer(), &transcript, &their_proof) => {
+            return Err(Error::AuthenticationFailed(their_uuid.to_owned()));
+        }
+        Some(key) => key,
+        None => {
+            println!(
+                "Warning: {} was paired without a key. Skipping authentication.",
+                their_uuid
+            );
+            return Ok(None);
+        }
+    };
+
+    if !advertised.negotiated().contains(Capabilities::ENCRYPT) {
+        return Ok(None);
+    }
+
+    let shared_secret = secret.diffie_hellman(&PublicKey::from(their_challenge));
+
+    // 相手の公開鍵が不正 (小さな部分群の点など) で、共有した値が推測できる場合は使わない
+    if !shared_secret.was_contributory() {
+        return Err(Error::ProtocolViolation(
+            "Invalid public key for the session".to_owned(),
+        ));
+    }
+
+    Ok(Some(SessionKeys {
+        send: key.session_key(role, &transcript, shared_secret.as_bytes()),
+        receive: key.session_key(role.peer(), &transcript, shared_secret.as_bytes()),
+    }))
+}
+
+/// 32 バイトの値を送り合う
+pub(crate) async fn exchange<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    mine: &[u8; 32],
+) -> Result<[u8; 32]>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (send_result, theirs): (Result<()>, Result<[u8; 32]>) = futures::join!(
+        async {
+            writer.write_all(mine).await?;
+            writer.flush().await?;
+
+            Ok(())
+        },
+        async {
+            let mut theirs = [0; 32];
+            reader.read_exact(&mut theirs).await?;
+
+            Ok(theirs)
+        }
+    );
+
+    send_result?;
+    theirs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_UUID: &str = "22222222-2222-4222-8222-222222222222";
+    const SERVER_UUID: &str = "11111111-1111-4111-8111-111111111111";
+
+    fn key(byte: u8) -> PairingKey {
+        PairingKey([byte; PAIRING_KEY_LEN])
+    }
+
+    /// クライアントとサーバがそれぞれの鍵で認証し合う
+    /// 戻り値は (クライアントの結果, サーバの結果)
+    async fn authenticate_with(
+        client_key: Option<&PairingKey>,
+        server_key: Option<&PairingKey>,
+    ) -> (Result<Option<SessionKeys>>, Result<Option<SessionKeys>>) {
+        let all = advertised(Capabilities::ALL, Capabilities::ALL);
+
+        authenticate_with_capabilities(client_key, server_key, all, all).await
+    }
+
+    fn advertised(ours: Capabilities, theirs: Capabilities) -> Advertised {
+        Advertised { ours, theirs }
+    }
+
+    /// 両者が送り合ったフラグをそれぞれ指定して認証し合う (途中で機能のフラグが書き換えられた場合)
+    async fn authenticate_with_capabilities(
+        client_key: Option<&PairingKey>,
+        server_key: Option<&PairingKey>,
+        client_advertised: Advertised,
+        server_advertised: Advertised,
+    ) -> (Result<Option<SessionKeys>>, Result<Option<SessionKeys>>) {
+        let (client, server) = tokio::io::duplex(256);
+        let (mut client_reader, mut client_writer) = tokio::io::split(client);
+        let (mut server_reader, mut server_writer) = tokio::io::split(server);
+
+        tokio::join!(
+            authenticate(
+                &mut client_reader,
+                &mut client_writer,
+                Role::Client,
+                client_key,
+                client_advertised,
+                CLIENT_UUID,
+                SERVER_UUID
+            ),
+            authenticate(
+                &mut server_reader,
+                &mut server_writer,
+                Role::Server,
+                server_key,
+                server_advertised,
+                SERVER_UUID,
+                CLIENT_UUID
+            )
+        )
+    }
+
+    #[test]
+    fn hex_round_trip_keeps_the_key() {
+        let key = PairingKey::derive(&[7; 32], [[1; 32], [2; 32]]);
+
+        assert_eq!(PairingKey::from_hex(&key.to_hex()).unwrap(), key);
+        assert!(PairingKey::from_hex("not a key").is_err());
+        assert!(PairingKey::from_hex(&"zz".repeat(PAIRING_KEY_LEN)).is_err());
+    }
+
+    #[test]
+    fn fingerprint_is_short_and_stable() {
+        let fingerprint = key(1).fingerprint();
+
+        assert_eq!(fingerprint.len(), 24);
+        assert_eq!(fingerprint, key(1).fingerprint());
+        assert_ne!(fingerprint, key(2).fingerprint());
+    }
+
+    #[test]
+    fn derived_key_does_not_depend_on_the_order_of_public_keys() {
+        assert_eq!(
+            PairingKey::derive(&[7; 32], [[1; 32], [2; 32]]),
+            PairingKey::derive(&[7; 32], [[2; 32], [1; 32]])
+        );
+    }
+
+    #[tokio::test]
+    async fn peers_with_the_same_key_authenticate_each_other() {
+        let (client_result, server_result) = authenticate_with(Some(&key(1)), Some(&key(1))).await;
+        let client_keys = client_result.unwrap().unwrap();
+        let server_keys = server_result.unwrap().unwrap();
+
+        assert_eq!(client_keys.send, server_keys.receive);
+        assert_eq!(client_keys.receive, server_keys.send);
+        assert_ne!(client_keys.send, client_keys.receive);
+    }
+
+    #[tokio::test]
+    async fn session_keys_differ_between_sessions() {
+        let (first, _) = authenticate_with(Some(&key(1)), Some(&key(1))).await;
+        let (second, _) = authenticate_with(Some(&key(1)), Some(&key(1))).await;
+
+        assert_ne!(first.unwrap().unwrap(), second.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    async fn session_is_not_encrypted_without_the_capability() {
+        let capabilities = Capabilities::ALL.difference(Capabilities::ENCRYPT);
+        let (client_result, server_result) = authenticate_with_capabilities(
+            Some(&key(1)),
+            Some(&key(1)),
+            advertised(capabilities, capabilities),
+            advertised(capabilities, capabilities),
+        )
+        .await;
+
+        assert_eq!(client_result.unwrap(), None);
+        assert_eq!(server_result.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn downgraded_capabilities_are_rejected() {
+        // クライアントが送ったフラグだけ、サーバに届く前に ENCRYPT を消された
+        let downgraded = Capabilities::ALL.difference(Capabilities::ENCRYPT);
+        let (client_result, server_result) = authenticate_with_capabilities(
+            Some(&key(1)),
+            Some(&key(1)),
+            advertised(Capabilities::ALL, Capabilities::ALL),
+            advertised(Capabilities::ALL, downgraded),
+        )
+        .await;
+
+        assert!(matches!(client_result, Err(Error::AuthenticationFailed(_))));
+        assert!(matches!(server_result, Err(Error::AuthenticationFailed(_))));
+    }
+
+    #[tokio::test]
+    async fn capabilities_downgraded_in_both_directions_are_rejected() {
+        // 両方向のフラグから ENCRYPT を消された: 両者が使う機能は同じになるが、送ったフラグが食い違う
+        let downgraded = Capabilities::ALL.difference(Capabilities::ENCRYPT);
+        let (client_result, server_result) = authenticate_with_capabilities(
+            Some(&key(1)),
+            Some(&key(1)),
+            advertised(Capabilities::ALL, downgraded),
+            advertised(Capabilities::ALL, downgraded),
+        )
+        .await;
+
+        assert!(matches!(client_result, Err(Error::AuthenticationFailed(_))));
+        assert!(matches!(server_result, Err(Error::AuthenticationFailed(_))));
+    }
+
+    #[tokio::test]
+    async fn legacy_pairing_is_not_encrypted() {
+        let (client_result, server_result) = authenticate_with(None, None).await;
+
+        assert_eq!(client_result.unwrap(), None);
+        assert_eq!(server_result.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn peers_with_different_keys_reject_each_other() {
+        let (client_result, server_result) = authenticate_with(Some(&key(1)), Some(&key(2))).await;
+
+        assert!(matches!(client_result, Err(Error::AuthenticationFailed(_))));
+        assert!(matches!(server_result, Err(Error::AuthenticationFailed(_))));
+    }
+
+    #[tokio::test]
+    async fn peer_without_the_key_is_rejected() {
+        let (_, server_result) = authenticate_with(None, Some(&key(1))).await;
+
+        assert!(matches!(server_result, Err(Error::AuthenticationFailed(_))));
+    }
+}
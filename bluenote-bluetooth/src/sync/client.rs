@@ -1,242 +1,731 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
 
-use napi::{bindgen_prelude::AsyncTask, Env, JsString, JsUndefined, Task};
+use napi::{bindgen_prelude::AsyncTask, Env, JsObject, JsString, JsUndefined, Task};
 use napi_derive::napi;
 use tokio::{
-    sync::{broadcast, mpsc, Mutex},
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{mpsc, Mutex},
     task::JoinHandle,
 };
-use windows::{
-    core::{GUID, HSTRING},
-    Devices::{
-        Bluetooth::{BluetoothCacheMode, BluetoothDevice, Rfcomm::RfcommServiceId},
-        Enumeration::DeviceInformation,
-    },
-    Networking::Sockets::{SocketProtectionLevel, StreamSocket},
-    Storage::Streams::{ByteOrder, DataReader, DataWriter},
-};
-
-use crate::{error::Error, Result, RUNTIME, UUID_BLUENOTE_RFCOMM};
-
-use super::{SYNC_ALLOWED, SYNC_FAILED, SYNC_SUCCESS};
-
-/// Bluetooth ペアリング済み & Bluenote の UUID をもつデバイスを列挙
-pub async fn enumerate_sync_companions() -> windows::core::Result<Vec<String>> {
-    let mut device_ids = Vec::<String>::new();
-
-    let selector = BluetoothDevice::GetDeviceSelectorFromPairingState(true)?;
-    let devices = DeviceInformation::FindAllAsyncAqsFilter(&selector)?.await?;
-
-    for d in devices {
-        println!("Device: {}", d.Name()?.to_string());
-
-        let id = d.Id()?;
-        let device = BluetoothDevice::FromIdAsync(&id)?.await?;
-        let service_id = RfcommServiceId::FromUuid(GUID::from(UUID_BLUENOTE_RFCOMM))?;
-        let rfcomm_services = device
-            .GetRfcommServicesForIdWithCacheModeAsync(&service_id, BluetoothCacheMode::Uncached)?
-            .await?
-            .Services()?;
 
-        if rfcomm_services.Size()? == 0 {
-            println!("The device seems not to have Bluenote App");
-            continue;
-        }
-
-        device_ids.push(id.to_string());
-    }
+#[cfg(windows)]
+use crate::winrt::rfcomm::RfcommTransport;
+use crate::{error::Error, Result, RUNTIME};
+
+use super::{
+    abort::{AbortListener, AbortWatcher},
+    auth::{authenticate, PairingKeys, Role},
+    frame::{concat_chunks, ResponseFormat},
+    handshake::{exchange_version, Capabilities},
+    heartbeat::{idle_timeout, receive_within, PING_INTERVAL},
+    read_uuid,
+    secure::{EncryptedReader, EncryptedWriter},
+    server::UPDATE_SYNCED_AT_TIMEOUT_MS,
+    tcp,
+    transport::{BoxedReader, BoxedWriter, Transport},
+    Request, SYNC_ALLOWED, SYNC_FAILED, SYNC_REJECTED, SYNC_SUCCESS,
+};
 
-    Ok(device_ids)
+/// 同期相手への接続方法
+enum Companion {
+    /// RFCOMM (Windows のデバイス ID)
+    #[cfg(windows)]
+    Rfcomm(String),
+    /// TCP (host:port)
+    Tcp(String),
 }
 
-struct SyncClientState {
-    #[allow(dead_code)] // インスタンスを持っておいて、socket が drop して接続が切れないようにする
-    socket: Arc<Mutex<StreamSocket>>,
-    reader: Arc<Mutex<DataReader>>,
-    writer: Arc<Mutex<DataWriter>>,
-    tx_finish: Arc<mpsc::Sender<String>>,
-    tx_uuid: Arc<broadcast::Sender<String>>,
-    handle: JoinHandle<Result<()>>,
-}
+/// 接続が切れたときに、同期を再開してリクエストし直す回数の上限
+const MAX_RESUME_ATTEMPTS: usize = 3;
+
+/// 同期の成功を送った後、相手の ACK と切断を待つ時間
+/// 相手は ACK の前に同期時刻を保存する (JavaScript を呼ぶ) ので、そのタイムアウトの既定値に余裕を持たせる
+const CLOSE_TIMEOUT: Duration = Duration::from_millis(UPDATE_SYNCED_AT_TIMEOUT_MS as u64 + 5_000);
 
 #[napi]
 pub struct SyncClient {
-    state: Option<SyncClientState>,
+    /// 接続中のセッション
+    /// 接続が切れて同期を再開したときは、リクエストの途中でも新しいセッションに置き換える
+    session: Mutex<Option<Arc<ClientSession>>>,
+    /// 同期が有効なデバイスの UUID (`begin_sync` で指定され、再接続にも使う)
+    sync_enabled_uuids: Vec<String>,
+    /// 同期相手を認証するための、ペアリングで共有した鍵 (`begin_sync` で指定される)
+    pairing_keys: PairingKeys,
     my_uuid: String,
-    companion_device_id: String,
+    companion: Companion,
 }
 
-/// 同期クライアント
+/// 同期クライアント (RFCOMM)
+#[cfg(windows)]
 #[napi]
 impl SyncClient {
     /// `SyncClient` のインスタンスを生成する
     #[napi(factory)]
     pub fn create_instance(my_uuid: String, companion_device_id: String) -> Self {
         Self {
-            state: None,
+            session: Mutex::new(None),
+            sync_enabled_uuids: Vec::new(),
+            pairing_keys: PairingKeys::new(),
             my_uuid,
-            companion_device_id,
+            companion: Companion::Rfcomm(companion_device_id),
         }
     }
+}
 
-    /// 同期サーバに接続し、その接続の StreamSocket を返す
-    async fn connect(companion_device_id: &str) -> Result<StreamSocket> {
-        let device = BluetoothDevice::FromIdAsync(&HSTRING::from(companion_device_id))?.await?;
-        let service_id = RfcommServiceId::FromUuid(GUID::from(UUID_BLUENOTE_RFCOMM))?;
-        let rfcomm_services = device
-            .GetRfcommServicesForIdWithCacheModeAsync(&service_id, BluetoothCacheMode::Uncached)?
-            .await?
-            .Services()?;
-
-        if rfcomm_services.Size()? == 0 {
-            return Err(Error::SyncError(format!(
-                "The device seems not to have Bluenote App"
-            )));
+/// 同期クライアント
+#[napi]
+impl SyncClient {
+    /// TCP で同期サーバに接続する `SyncClient` のインスタンスを生成する
+    ///
+    /// ## 引数
+    ///
+    /// - `address` - 同期サーバのアドレス (host:port)
+    #[napi(factory)]
+    pub fn create_tcp_instance(my_uuid: String, address: String) -> Self {
+        Self {
+            session: Mutex::new(None),
+            sync_enabled_uuids: Vec::new(),
+            pairing_keys: PairingKeys::new(),
+            my_uuid,
+            companion: Companion::Tcp(address),
         }
+    }
 
-        println!("Connecting...");
+    /// 同期相手に接続し、同期を開始する
+    /// `resume` に同期 ID を指定すると、その同期の再開を要求する
+    async fn connect(&self, resume: Option<&str>) -> Result<ClientSession> {
+        match &self.companion {
+            #[cfg(windows)]
+            Companion::Rfcomm(device_id) => {
+                let transport = RfcommTransport::connect(device_id).await?;
+                ClientSession::begin(
+                    transport,
+                    &self.my_uuid,
+                    &self.sync_enabled_uuids,
+                    &self.pairing_keys,
+                    Capabilities::ALL,
+                    resume,
+                )
+                .await
+            }
+            Companion::Tcp(address) => {
+                let transport = tcp::connect(address).await?;
+                ClientSession::begin(
+                    transport,
+                    &self.my_uuid,
+                    &self.sync_enabled_uuids,
+                    &self.pairing_keys,
+                    Capabilities::ALL,
+                    resume,
+                )
+                .await
+            }
+        }
+    }
 
-        let service = rfcomm_services.GetAt(0)?;
-        let socket = StreamSocket::new()?;
+    pub(crate) async fn begin_sync_impl(&mut self, sync_enabled_uuids: &[String]) -> Result<()> {
+        // 信頼を取り消したデバイスとは同期しない
+        self.sync_enabled_uuids = sync_enabled_uuids
+            .iter()
+            .filter(|uuid| !crate::trust::is_revoked(uuid))
+            .cloned()
+            .collect();
 
-        socket
-            .ConnectWithProtectionLevelAsync(
-                &service.ConnectionHostName()?,
-                &service.ConnectionServiceName()?,
-                SocketProtectionLevel::BluetoothEncryptionWithAuthentication,
-            )?
-            .await?;
+        // 鍵を共有している相手は、鍵を持っていることを確認してから同期する
+        self.pairing_keys = self
+            .sync_enabled_uuids
+            .iter()
+            .filter_map(|uuid| Some((uuid.clone(), crate::trust::pairing_key(uuid)?)))
+            .collect();
 
-        println!("Connected!");
+        let session = self.connect(None).await?;
+        *self.session.get_mut() = Some(Arc::new(session));
 
-        Ok(socket)
+        Ok(())
     }
 
-    async fn begin_sync_impl(&mut self, sync_enabled_uuids: &Vec<String>) -> Result<()> {
-        let socket = Self::connect(&self.companion_device_id).await?;
+    async fn current_session(&self) -> Result<Arc<ClientSession>> {
+        self.session
+            .lock()
+            .await
+            .clone()
+            .ok_or_else(|| Error::Other("Not connected.".to_owned()))
+    }
 
-        let reader = DataReader::CreateDataReader(&socket.InputStream()?)?;
-        let writer = DataWriter::CreateDataWriter(&socket.OutputStream()?)?;
+    /// 接続が切れたセッション `lost` の同期を、新しい接続で再開する
+    /// 相手が同期を覚えていないなど、再開できなかった場合は `error` (接続が切れた原因) を返す
+    async fn resume_sync(&self, lost: &Arc<ClientSession>, error: Error) -> Result<()> {
+        let mut session = self.session.lock().await;
+
+        match &*session {
+            // ほかのリクエストがすでに再開している
+            Some(current) if !Arc::ptr_eq(current, lost) => return Ok(()),
+            // 同期が終了している
+            None => return Err(error),
+            _ => {}
+        }
 
-        reader.SetByteOrder(ByteOrder::LittleEndian)?;
-        writer.SetByteOrder(ByteOrder::LittleEndian)?;
+        let sync_id = match lost.sync_id() {
+            Some(sync_id) => sync_id,
+            None => return Err(error),
+        };
 
-        // 1. UUID を交換し、同期が有効な相手か確認 & 相手の同期の許可を得る
-        self.exchange_uuid(&sync_enabled_uuids, &reader, &writer)
-            .await?;
+        println!("Connection lost: {}. Resuming sync: {}", error, sync_id);
 
-        // 2. 同期が許可されたかどうかの確認
-        reader.LoadAsync(1)?.await?;
-        let response = reader.ReadByte()?;
+        let resumed = self.connect(Some(sync_id)).await?;
 
-        if response != SYNC_ALLOWED {
-            // todo: 自作の Error 型を使いたい
-            return Err(Error::SyncError(format!("Sync not allowed")));
+        if resumed.sync_id() != Some(sync_id) {
+            // 新しい同期になると、受信済みのデータと返却するデータの範囲が食い違うので続けない
+            let _ = resumed.end(false).await;
+            return Err(error);
         }
 
-        let (tx_uuid, _) = broadcast::channel(16);
-        let (tx_finish, rx_finish) = mpsc::channel::<String>(16);
+        *session = Some(Arc::new(resumed));
 
-        let socket = Arc::new(Mutex::new(socket));
-        let reader = Arc::new(Mutex::new(reader));
-        let writer = Arc::new(Mutex::new(writer));
-        let tx_finish = Arc::new(tx_finish);
-        let tx_uuid = Arc::new(tx_uuid);
+        Ok(())
+    }
 
-        let reader_response_receiver = Arc::clone(&reader);
-        let tx_uuid_response_receiver = Arc::clone(&tx_uuid);
-        let socket_closer = Arc::clone(&socket);
+    /// 同期を開始する
+    /// 信頼するデバイスの一覧 (`openTrustStore`) に鍵がある相手は、鍵を持っていることを確認できなければ同期しない
+    #[napi(ts_return_type = "Promise<void>")]
+    pub fn begin_sync(&mut self, sync_enabled_uuids: Vec<String>) -> AsyncTask<BeginSyncTask<'_>> {
+        AsyncTask::new(BeginSyncTask {
+            client: self,
+            sync_enabled_uuids,
+        })
+    }
 
-        // レスポンスを受信するタスクの起動
-        let handle: JoinHandle<Result<()>> = tokio::spawn(async move {
-            tokio::select! {
-                _ = Self::receive_response(
-                    reader_response_receiver,
-                    tx_uuid_response_receiver,
-                    rx_finish,
-                ) => {}
-                _ = async {
-                    tokio::time::sleep(std::time::Duration::from_secs(60)).await;
-                } => {
-                    socket_closer.lock().await.Close()?;
+    pub(crate) async fn request_data_impl(
+        &self,
+        request: &Request,
+        aborted: &AbortWatcher,
+    ) -> Result<String> {
+        let mut attempts = 0;
+
+        loop {
+            let session = self.current_session().await?;
+
+            match session.request_abortable(request, aborted).await {
+                // 接続が切れたら同期を再開して、このリクエストだけ送り直す
+                // (レスポンスを受信し終えたリクエストは送り直さない)
+                Err(e) if e.is_connection_lost() && attempts < MAX_RESUME_ATTEMPTS => {
+                    attempts += 1;
+                    self.resume_sync(&session, e).await?;
                 }
+                result => return result,
             }
-            Ok(())
-        });
+        }
+    }
 
-        self.state = Some(SyncClientState {
-            socket,
-            reader,
-            writer,
-            tx_finish,
-            tx_uuid,
-            handle,
-        });
+    /// スレッドの更新分をリクエストする
+    /// データは JSON で返される
+    /// `signal` が中断されると、リクエストを取り消して `CANCELLED` のエラーで reject される
+    #[napi(
+        ts_args_type = "signal?: AbortSignal",
+        ts_return_type = "Promise<string>"
+    )]
+    pub fn get_thread_updates(
+        &self,
+        env: Env,
+        signal: Option<JsObject>,
+    ) -> napi::Result<AsyncTask<RequestDataTask<'_>>> {
+        self.request_data(&env, Request::ThreadUpdates, signal)
+    }
 
-        Ok(())
+    /// 指定したスレッドのメモをすべてリクエストする
+    /// データは JSON で返される (すべてのチャンクを受信してからつなげるので、大きなスレッドは `streamAllNotesInThread` を使う)
+    #[napi(
+        ts_args_type = "threadId: string, signal?: AbortSignal",
+        ts_return_type = "Promise<string>"
+    )]
+    pub fn get_all_notes_in_thread(
+        &self,
+        env: Env,
+        thread_id: String,
+        signal: Option<JsObject>,
+    ) -> napi::Result<AsyncTask<RequestDataTask<'_>>> {
+        self.request_data(&env, Request::AllNotesInThread { thread_id }, signal)
     }
 
-    /// レスポンスを受信し、リクエスト待ちに対して通知を送る
-    async fn receive_response(
-        reader: Arc<Mutex<DataReader>>,
-        tx_uuid: Arc<broadcast::Sender<String>>,
-        mut rx_finish: mpsc::Receiver<String>,
-    ) -> Result<()> {
+    /// 指定したメモのツリーのメモをすべてリクエストする
+    /// データは JSON で返される (すべてのチャンクを受信してからつなげるので、大きなツリーは `streamAllNotesInTree` を使う)
+    #[napi(
+        ts_args_type = "parentId: string, signal?: AbortSignal",
+        ts_return_type = "Promise<string>"
+    )]
+    pub fn get_all_notes_in_tree(
+        &self,
+        env: Env,
+        parent_id: String,
+        signal: Option<JsObject>,
+    ) -> napi::Result<AsyncTask<RequestDataTask<'_>>> {
+        self.request_data(&env, Request::AllNotesInTree { parent_id }, signal)
+    }
+
+    /// 指定したスレッド直下のメモの更新分をリクエストする
+    /// データは JSON で返される
+    #[napi(
+        ts_args_type = "threadId: string, signal?: AbortSignal",
+        ts_return_type = "Promise<string>"
+    )]
+    pub fn get_note_updates_in_thread(
+        &self,
+        env: Env,
+        thread_id: String,
+        signal: Option<JsObject>,
+    ) -> napi::Result<AsyncTask<RequestDataTask<'_>>> {
+        self.request_data(&env, Request::NoteUpdatesInThread { thread_id }, signal)
+    }
+
+    /// 指定したメモのツリーのメモの更新分をリクエストする
+    /// データは JSON で返される
+    #[napi(
+        ts_args_type = "parentId: string, signal?: AbortSignal",
+        ts_return_type = "Promise<string>"
+    )]
+    pub fn get_note_updates_in_tree(
+        &self,
+        env: Env,
+        parent_id: String,
+        signal: Option<JsObject>,
+    ) -> napi::Result<AsyncTask<RequestDataTask<'_>>> {
+        self.request_data(&env, Request::NoteUpdatesInTree { parent_id }, signal)
+    }
+
+    fn request_data(
+        &self,
+        env: &Env,
+        request: Request,
+        signal: Option<JsObject>,
+    ) -> napi::Result<AsyncTask<RequestDataTask<'_>>> {
+        let (aborted, listener) = AbortListener::listen(env, signal)?;
+
+        Ok(AsyncTask::new(RequestDataTask {
+            client: self,
+            request,
+            aborted,
+            listener,
+        }))
+    }
+
+    pub(crate) async fn end_sync_impl(&mut self, success: bool) -> Result<()> {
+        // session を drop して接続を切る
+        match self.session.get_mut().take() {
+            Some(session) => session.end(success).await,
+            None => Err(Error::Other("Sync not started.".to_owned())),
+        }
+    }
+
+    /// 同期の成功・失敗を送信し、接続を終了する
+    /// 成功を送った後、相手が同期時刻を保存した ACK を返さずに切断すれば `DISCONNECTED`、
+    /// 時間内に ACK が届かなければ `TIMEOUT` (`phase` は `close`) で reject する
+    #[napi(ts_return_type = "Promise<void>")]
+    pub fn end_sync(&mut self, success: bool) -> AsyncTask<EndSyncTask<'_>> {
+        AsyncTask::new(EndSyncTask {
+            client: self,
+            success,
+        })
+    }
+}
+
+/// 同期クライアント (チャンクごとの受信)
+/// napi のバインディングを無効にしたビルド (`peer`) ではクラスを返せないので除く
+#[cfg(not(feature = "peer"))]
+#[napi]
+impl SyncClient {
+    async fn request_stream_impl(
+        &self,
+        request: &Request,
+        aborted: &AbortWatcher,
+    ) -> Result<ResponseStream> {
+        let mut attempts = 0;
+
         loop {
-            // UUID 取得
+            if aborted.is_aborted() {
+                return Err(Error::Cancelled);
+            }
+
+            let session = self.current_session().await?;
+            // 送信する前に接続が切れていた場合だけ、同期を再開して送り直す
+            // (受信の途中で切れた場合は、受信済みのチャンクと重複しないよう再開しない)
+            match session.request_stream(request).await {
+                Err(e) if e.is_connection_lost() && attempts < MAX_RESUME_ATTEMPTS => {
+                    attempts += 1;
+                    self.resume_sync(&session, e).await?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// 指定したスレッドのメモをすべて、チャンクごとにリクエストする
+    /// 大きなスレッドでも、全体を一度にメモリに載せずに受信できる
+    #[napi(
+        ts_args_type = "threadId: string, signal?: AbortSignal",
+        ts_return_type = "Promise<SyncResponseStream>"
+    )]
+    pub fn stream_all_notes_in_thread(
+        &self,
+        env: Env,
+        thread_id: String,
+        signal: Option<JsObject>,
+    ) -> napi::Result<AsyncTask<RequestStreamTask<'_>>> {
+        self.request_stream(&env, Request::AllNotesInThread { thread_id }, signal)
+    }
 
-            let mut buffer = vec![0u8; 36];
+    /// 指定したメモのツリーのメモをすべて、チャンクごとにリクエストする
+    #[napi(
+        ts_args_type = "parentId: string, signal?: AbortSignal",
+        ts_return_type = "Promise<SyncResponseStream>"
+    )]
+    pub fn stream_all_notes_in_tree(
+        &self,
+        env: Env,
+        parent_id: String,
+        signal: Option<JsObject>,
+    ) -> napi::Result<AsyncTask<RequestStreamTask<'_>>> {
+        self.request_stream(&env, Request::AllNotesInTree { parent_id }, signal)
+    }
+
+    fn request_stream(
+        &self,
+        env: &Env,
+        request: Request,
+        signal: Option<JsObject>,
+    ) -> napi::Result<AsyncTask<RequestStreamTask<'_>>> {
+        let (aborted, listener) = AbortListener::listen(env, signal)?;
+
+        Ok(AsyncTask::new(RequestStreamTask {
+            client: self,
+            request,
+            aborted,
+            listener,
+        }))
+    }
+}
 
-            {
-                let reader = reader.lock().await;
+/// チャンクごとに受信するレスポンス (JavaScript 向け)
+#[napi]
+pub struct SyncResponseStream {
+    stream: Arc<Mutex<ResponseStream>>,
+}
+
+#[napi]
+impl SyncResponseStream {
+    /// 次のチャンク (JSON の配列) を取得する
+    /// すべて受信し終わったら `null` を返す
+    /// `signal` が中断されると、リクエストを取り消して `CANCELLED` のエラーで reject される
+    #[napi(
+        ts_args_type = "signal?: AbortSignal",
+        ts_return_type = "Promise<string | null>"
+    )]
+    pub fn next(
+        &self,
+        env: Env,
+        signal: Option<JsObject>,
+    ) -> napi::Result<AsyncTask<NextChunkTask>> {
+        let (aborted, listener) = AbortListener::listen(&env, signal)?;
+
+        Ok(AsyncTask::new(NextChunkTask {
+            stream: Arc::clone(&self.stream),
+            aborted,
+            listener,
+        }))
+    }
+}
+
+/// 受信したレスポンスのチャンク (JSON, 最後のチャンクか)
+/// 相手がリクエストの処理に失敗した場合は `Error::RequestFailed`
+type Chunk = Result<(String, bool)>;
+
+/// 読み出されるのを待てるチャンクの数
+/// これを超えると、読み出されるまで受信を止める
+const CHUNK_BUFFER: usize = 4;
+
+/// レスポンス待ちのリクエスト
+/// レスポンスの UUID ごとに、リクエストした順に受信チャネルを並べておく
+/// 接続が切れたら `None` にする
+type PendingResponses = std::sync::Mutex<Option<HashMap<String, VecDeque<mpsc::Sender<Chunk>>>>>;
+
+/// 同期クライアントの本体
+/// 通信路 (`Transport`) の上でプロトコルを処理する
+pub struct ClientSession {
+    /// 同期 ID (相手が同期の再開に対応している場合のみ)
+    sync_id: Option<String>,
+    format: ResponseFormat,
+    reader: Arc<Mutex<BoxedReader>>,
+    writer: Arc<Mutex<BoxedWriter>>,
+    pending: Arc<PendingResponses>,
+    /// 同期の成功を送った後は、相手の ACK を受信したら `Ok(())` で終わる
+    handle: Mutex<JoinHandle<Result<()>>>,
+    /// ping を送り続けるタスク (相手が対応している場合のみ)
+    pinger: Option<JoinHandle<()>>,
+}
+
+/// チャンクに分けて受信するレスポンス
+/// 分割に対応していない相手からは、レスポンス全体が一つのチャンクとして返される
+pub struct ResponseStream {
+    rx: mpsc::Receiver<Chunk>,
+    finished: bool,
+    cancelled: bool,
+    /// 取り消すときに使う
+    response_id: String,
+    tx: mpsc::WeakSender<Chunk>,
+    format: ResponseFormat,
+    writer: Arc<Mutex<BoxedWriter>>,
+    pending: Arc<PendingResponses>,
+}
+
+impl ResponseStream {
+    /// 次のチャンク (JSON の配列) を受信する
+    /// すべて受信し終わったら `None` を返す
+    pub async fn next(&mut self) -> Result<Option<String>> {
+        if self.cancelled {
+            return Err(Error::Cancelled);
+        }
+        if self.finished {
+            return Ok(None);
+        }
+
+        match self.rx.recv().await {
+            Some(Ok((chunk, is_last))) => {
+                self.finished = is_last;
+                Ok(Some(chunk))
+            }
+            Some(Err(e)) => {
+                self.finished = true;
+                Err(e)
+            }
+            None => Err(Error::Disconnected),
+        }
+    }
 
-                reader.LoadAsync(36)?.await?;
-                reader.ReadBytes(&mut buffer)?;
+    /// 次のチャンクを受信する
+    /// 受信する前に `aborted` が完了したら、リクエストを取り消して `Error::Cancelled` を返す
+    pub async fn next_abortable(&mut self, aborted: &AbortWatcher) -> Result<Option<String>> {
+        let next = tokio::select! {
+            next = self.next() => Some(next),
+            () = aborted.aborted() => None,
+        };
+
+        match next {
+            Some(next) => next,
+            None => {
+                self.cancel().await?;
+                Err(Error::Cancelled)
             }
+        }
+    }
 
-            let uuid = match String::from_utf8(buffer) {
-                Ok(v) => Ok(v),
-                Err(e) => Err(Error::SyncError(format!("{}", e))),
-            }?;
+    /// リクエストを取り消す
+    /// 以降に届くレスポンスは受信して捨てる
+    /// 相手が対応していれば、相手にも処理の中止を要求する
+    pub async fn cancel(&mut self) -> Result<()> {
+        if self.finished || self.cancelled {
+            return Ok(());
+        }
 
-            println!("received response uuid: {}", uuid);
+        self.cancelled = true;
+        self.rx.close();
 
-            // レスポンスが来たという通知を送る
-            tx_uuid.send(uuid.to_owned()).unwrap(); // 一旦 unwrap
+        if !self.format.has_cancel() {
+            return Ok(());
+        }
 
-            // レスポンスに対する処理完了を待つ
-            loop {
-                if let Some(finish_id) = rx_finish.recv().await {
-                    if finish_id == uuid {
-                        break;
-                    }
-                }
+        // 同じ ID のリクエストがほかにもあると、そちらまで取り消されてしまうので要求しない
+        let only_this = {
+            let pending = self.pending.lock().unwrap();
+            let queue = pending
+                .as_ref()
+                .and_then(|pending| pending.get(&self.response_id));
+
+            match (queue, self.tx.upgrade()) {
+                (Some(queue), Some(tx)) => queue.len() == 1 && queue[0].same_channel(&tx),
+                _ => false,
+            }
+        };
+
+        if only_this {
+            let mut writer = self.writer.lock().await;
+
+            Request::Cancel {
+                request_id: self.response_id.clone(),
             }
+            .write(&mut *writer)
+            .await?;
+            writer.flush().await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl ClientSession {
+    /// UUID を交換して同期の許可を得た後、レスポンスの受信を開始する
+    /// `capabilities` はこちらが使ってよい機能 (実際に使うのは相手も対応しているものだけ)
+    /// `resume` に以前の同期 ID を指定すると、その同期の再開を要求する
+    /// (再開できたかどうかは、`sync_id` が同じかどうかでわかる)
+    pub async fn begin<T>(
+        transport: T,
+        my_uuid: &str,
+        sync_enabled_uuids: &[String],
+        pairing_keys: &PairingKeys,
+        capabilities: Capabilities,
+        resume: Option<&str>,
+    ) -> Result<Self>
+    where
+        T: Transport,
+    {
+        let (mut reader, mut writer) = transport.into_split();
+
+        // 0. プロトコルのバージョンを交換し、同じでなければ切断
+        let advertised = exchange_version(&mut reader, &mut writer, capabilities).await?;
+        let capabilities = advertised.negotiated();
+        let format = ResponseFormat::negotiate(capabilities);
 
-            println!("finish: {}", uuid);
+        // 1. UUID を交換し、同期が有効な相手か確認 & 相手の同期の許可を得る
+        let uuid =
+            Self::exchange_uuid(my_uuid, sync_enabled_uuids, &mut reader, &mut writer).await?;
+
+        // 2. ペアリングで共有した鍵で、相手を認証する
+        // 鍵を共有している相手なのに認証に対応していなければ、なりすましとみなす
+        let pairing_key = pairing_keys.get(&uuid);
+
+        let session_keys = if capabilities.contains(Capabilities::AUTH) {
+            authenticate(
+                &mut reader,
+                &mut writer,
+                Role::Client,
+                pairing_key,
+                advertised,
+                my_uuid,
+                &uuid,
+            )
+            .await?
+        } else if pairing_key.is_some() {
+            return Err(Error::AuthenticationFailed(uuid));
+        } else {
+            None
+        };
+
+        // 鍵を導出できた場合は、ここから先の通信をすべて暗号化する
+        if let Some(keys) = session_keys {
+            println!("Encrypting the sync session with {}", uuid);
+
+            reader = Box::new(EncryptedReader::new(reader, &keys.receive));
+            writer = Box::new(EncryptedWriter::new(writer, &keys.send));
         }
+
+        // 3. 同期が許可されたかどうかの確認
+        let response = reader.read_u8().await?;
+
+        match response {
+            SYNC_ALLOWED => {}
+            SYNC_REJECTED => {
+                return Err(Error::SyncRejected("Rejected by the companion".to_owned()));
+            }
+            _ => {
+                return Err(Error::ProtocolViolation(format!(
+                    "Unexpected response to the sync request: {}",
+                    response
+                )));
+            }
+        }
+
+        // 4. 相手が対応していれば、同期 ID を交換する
+        let sync_id = if capabilities.contains(Capabilities::RESUME) {
+            Some(Self::exchange_sync_id(resume, &mut reader, &mut writer).await?)
+        } else {
+            None
+        };
+
+        let reader = Arc::new(Mutex::new(reader));
+        let writer = Arc::new(Mutex::new(writer));
+        let pending: Arc<PendingResponses> = Arc::new(std::sync::Mutex::new(Some(HashMap::new())));
+
+        let reader_response_receiver = Arc::clone(&reader);
+        let pending_response_receiver = Arc::clone(&pending);
+        let idle_timeout = idle_timeout(capabilities);
+
+        // レスポンスを受信するタスクの起動
+        // 一定時間何も受信しなければ、接続が切れたとみなす
+        let handle: JoinHandle<Result<()>> = tokio::spawn(async move {
+            let result = Self::receive_response(
+                &reader_response_receiver,
+                &pending_response_receiver,
+                format,
+                idle_timeout,
+            )
+            .await;
+
+            // レスポンス待ちのリクエストに切断を通知する
+            pending_response_receiver.lock().unwrap().take();
+
+            result
+        });
+
+        // 相手が対応していれば、レスポンスを待っている間も接続が生きているとわかるよう ping を送る
+        let pinger = format
+            .has_heartbeat()
+            .then(|| tokio::spawn(Self::send_pings(Arc::clone(&writer))));
+
+        Ok(Self {
+            sync_id,
+            format,
+            reader,
+            writer,
+            pending,
+            handle: Mutex::new(handle),
+            pinger,
+        })
+    }
+
+    /// 同期 ID
+    /// 相手が同期の再開に対応していない場合は `None`
+    pub fn sync_id(&self) -> Option<&str> {
+        self.sync_id.as_deref()
+    }
+
+    /// 再開したい同期の ID (新しい同期なら空白) を送り、相手が決めた同期 ID を受信する
+    async fn exchange_sync_id(
+        resume: Option<&str>,
+        reader: &mut BoxedReader,
+        writer: &mut BoxedWriter,
+    ) -> Result<String> {
+        let requested = match resume {
+            Some(sync_id) => sync_id.to_owned(),
+            None => " ".repeat(36),
+        };
+
+        writer.write_all(requested.as_bytes()).await?;
+        writer.flush().await?;
+
+        read_uuid(reader).await
     }
 
     /// UUID を交換し、同期が有効な相手か確認 & 相手の同期の許可を得る
+    /// 戻り値は相手の UUID
     async fn exchange_uuid(
-        &self,
-        sync_enabled_uuids: &Vec<String>, // デバイスそんなに多くならないのでこれでいいっしょ、多分
-        reader: &DataReader,
-        writer: &DataWriter,
-    ) -> Result<()> {
+        my_uuid: &str,
+        sync_enabled_uuids: &[String], // デバイスそんなに多くならないのでこれでいいっしょ、多分
+        reader: &mut BoxedReader,
+        writer: &mut BoxedWriter,
+    ) -> Result<String> {
         let (send_result, uuid): (Result<()>, Result<String>) = futures::join!(
             async {
                 // 自身のデバイスIDを送信
-                writer.WriteString(&HSTRING::from(&self.my_uuid))?;
-                writer.StoreAsync()?.await?;
-                writer.FlushAsync()?.await?;
+                writer.write_all(my_uuid.as_bytes()).await?;
+                writer.flush().await?;
 
                 Ok(())
             },
             async {
                 // 相手のデバイスの UUID を受信
-                reader.LoadAsync(36)?.await?;
-                Ok(reader.ReadString(36)?.to_string())
+                read_uuid(reader).await
             }
         );
 
@@ -246,148 +735,230 @@ impl SyncClient {
         sync_enabled_uuids
             .iter()
             .find(|&v| v == &uuid)
-            .ok_or(Error::SyncError(format!("Sync not enabled: {}", &uuid)))?;
-
-        Ok(())
-    }
+            .ok_or(Error::SyncRejected(format!("Sync not enabled: {}", &uuid)))?;
 
-    /// 同期を開始する
-    #[napi(ts_return_type = "Promise<void>")]
-    pub fn begin_sync(&mut self, sync_enabled_uuids: Vec<String>) -> AsyncTask<BeginSyncTask> {
-        AsyncTask::new(BeginSyncTask {
-            client: self,
-            sync_enabled_uuids,
-        })
+        Ok(uuid)
     }
 
-    async fn request_data_impl(&self, request_id: u8, uuid: &Option<String>) -> Result<String> {
-        match &self.state {
-            Some(state) => {
-                println!("request id = {}", request_id);
-
-                // レスポンスを待つ用の受信チャネルを作成
-                let mut rx_uuid = state.tx_uuid.subscribe();
+    /// 定期的に ping を送る
+    /// 送れなくなったら終わる (切断はレスポンスの受信側で検知する)
+    async fn send_pings(writer: Arc<Mutex<BoxedWriter>>) {
+        loop {
+            tokio::time::sleep(PING_INTERVAL).await;
 
-                // リクエストの送信
-                {
-                    let writer = state.writer.lock().await;
+            let mut writer = writer.lock().await;
+            let result: tokio::io::Result<()> = async {
+                Request::Ping.write(&mut *writer).await?;
+                writer.flush().await
+            }
+            .await;
 
-                    // 1. リクエスト ID の送信
-                    writer.WriteByte(request_id)?;
+            if result.is_err() {
+                return;
+            }
+        }
+    }
 
-                    // （必要であれば）UUID の送信
-                    if let Some(uuid) = uuid {
-                        writer.WriteBytes(uuid.as_bytes())?;
-                    }
+    /// レスポンスを受信し、リクエスト待ちに対して通知を送る
+    /// データは、ここで JSON に変換してから渡す
+    async fn receive_response(
+        reader: &Mutex<BoxedReader>,
+        pending: &PendingResponses,
+        format: ResponseFormat,
+        idle_timeout: Duration,
+    ) -> Result<()> {
+        let mut reader = reader.lock().await;
 
-                    writer.StoreAsync()?.await?;
-                    writer.FlushAsync()?.await?;
-                }
+        loop {
+            let first = receive_within(idle_timeout, async { Ok(reader.read_u8().await?) }).await?;
 
-                println!("request sent");
-
-                // レスポンスを待つ
-                // スレッドの更新差分リクエストの時は UUID の代わりに 36 バイトの空白が送られる
-                let uuid = match uuid {
-                    Some(uuid) => uuid.to_owned(),
-                    None => " ".repeat(36).to_owned(),
-                };
-                {
-                    loop {
-                        if let Ok(response_id) = rx_uuid.recv().await {
-                            if response_id == uuid {
-                                break;
-                            }
-                        }
-                    }
-                }
+            // 同期の成功に対する ACK
+            // (フレームはリクエスト ID (UUID もしくは空白) で始まるので、区別できる)
+            if first == SYNC_SUCCESS {
+                return Ok(());
+            }
 
-                println!("response received");
+            let first = [first];
+            let mut frame_reader = first.as_slice().chain(&mut *reader);
+            let frame = receive_within(idle_timeout, format.read_frame(&mut frame_reader)).await?;
 
-                let reader = state.reader.lock().await;
+            if frame.is_pong() {
+                continue;
+            }
 
-                // 2. データサイズ (4バイト) を取得
-                reader.LoadAsync(4)?.await?;
-                let size = reader.ReadUInt32()?;
+            let uuid = frame.request_id.clone();
+            let is_last = frame.is_last();
 
-                // 3. JSON 文字列の読み込み
-                let mut buffer = vec![0u8; size as usize];
-                reader.LoadAsync(size)?.await?;
-                reader.ReadBytes(&mut buffer)?;
+            println!("received response uuid: {}", uuid);
 
-                // レスポンスの処理完了を通知
-                state.tx_finish.send(uuid).await.unwrap(); // 一旦 unwrap
+            // エラーのレスポンスは、そのリクエストだけを失敗させる
+            let chunk = if frame.is_error() {
+                Err(frame.into_error()?)
+            } else {
+                Ok((format.decode(frame)?, is_last))
+            };
+
+            // レスポンスを待っているリクエストの受信チャネル
+            // 最後のチャンクなら、待ちから外す
+            let tx = pending.lock().unwrap().as_mut().and_then(|pending| {
+                let queue = pending.get_mut(&uuid)?;
+
+                if is_last {
+                    queue.pop_front()
+                } else {
+                    queue.front().cloned()
+                }
+            });
 
-                match String::from_utf8(buffer) {
-                    Ok(v) => Ok(v),
-                    Err(e) => Err(Error::SyncError(format!("{}", e))),
+            match tx {
+                // 読み出されるのを待つ (受信したデータが溜まりすぎないように)
+                // 途中で読むのをやめられた場合は、残りのチャンクを捨てる
+                Some(tx) => {
+                    let _ = tx.send(chunk).await;
+                }
+                None => {
+                    println!("Warning: unexpected response: {}", uuid);
                 }
             }
-            None => Err(Error::SyncError(format!("Not connected."))),
         }
     }
 
-    /// 同期サーバにメモの更新をリクエストする
-    /// データは JSON で返される
-    #[napi(ts_return_type = "Promise<string>")]
-    pub fn request_data(&self, request_id: u8, uuid: Option<String>) -> AsyncTask<RequestDataTask> {
-        AsyncTask::new(RequestDataTask {
-            client: self,
-            request_id,
-            uuid,
-        })
+    /// 同期サーバにデータをリクエストし、レスポンスをすべて受信するのを待つ
+    /// データは (通信路の上での形式によらず) JSON で返される
+    pub async fn request(&self, request: &Request) -> Result<String> {
+        self.request_abortable(request, &AbortWatcher::default())
+            .await
     }
 
-    async fn end_sync_impl(&mut self, success: bool) -> Result<()> {
-        // state で持っている socket を drop して接続を切る
-        match self.state.take() {
-            Some(state) => {
-                // レスポンス受付タスクを終了
+    /// `request` と同じだが、`aborted` が完了したらリクエストを取り消して `Error::Cancelled` を返す
+    /// すべてのチャンクをメモリに載せてからつなげる (チャンクごとに受け取るには `request_stream` を使う)
+    pub async fn request_abortable(
+        &self,
+        request: &Request,
+        aborted: &AbortWatcher,
+    ) -> Result<String> {
+        if aborted.is_aborted() {
+            return Err(Error::Cancelled);
+        }
 
-                state.handle.abort();
+        let mut stream = self.request_stream(request).await?;
+        let mut chunks = Vec::new();
 
-                // 同期の成功 or 失敗を送信
-                {
-                    let writer = state.writer.lock().await;
+        while let Some(chunk) = stream.next_abortable(aborted).await? {
+            chunks.push(chunk);
+        }
 
-                    writer.WriteByte(if success { SYNC_SUCCESS } else { SYNC_FAILED })?;
-                    writer.StoreAsync()?.await?;
-                    writer.FlushAsync()?.await?;
-                }
+        println!("response received");
 
-                if success {
-                    // 相手がデータを読むまでに切断してしまうと、こちらから送った
-                    // 同期の成功可否が届かないことがあるため、相手からの ACK を
-                    // 待つという体で、相手が切断するのを待つ
-
-                    let _: Result<()> = async {
-                        let reader = state.reader.lock().await;
-                        reader.LoadAsync(1)?.await?;
-                        Ok(())
-                    }
-                    .await;
-                }
+        concat_chunks(chunks)
+    }
 
-                Ok(())
-            }
-            None => Err(Error::SyncError(format!("Sync not started."))),
+    /// 同期サーバにデータをリクエストし、レスポンスをチャンクごとに受信する
+    /// 読み出さずに放置すると、ほかのリクエストのレスポンスの受信も止まるので、
+    /// 使わなくなったら drop すること
+    pub async fn request_stream(&self, request: &Request) -> Result<ResponseStream> {
+        // 不正な UUID を送ると、相手側でリクエストの区切りがずれてしまうので先に弾く
+        request.validate()?;
+
+        println!("request = {:?}", request);
+
+        // レスポンスの UUID
+        // スレッドの更新差分リクエストの時は UUID の代わりに 36 バイトの空白が送られる
+        let response_id = request.response_id();
+
+        // レスポンスを待つ用の受信チャネルを作成
+        let (tx, rx) = mpsc::channel(CHUNK_BUFFER);
+        let weak_tx = tx.downgrade();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            let pending = pending.as_mut().ok_or(Error::Disconnected)?;
+
+            pending
+                .entry(response_id.clone())
+                .or_default()
+                .push_back(tx);
         }
+
+        // リクエストの送信
+        {
+            let mut writer = self.writer.lock().await;
+
+            request.write(&mut *writer).await?;
+            writer.flush().await?;
+        }
+
+        println!("request sent");
+
+        Ok(ResponseStream {
+            rx,
+            finished: false,
+            cancelled: false,
+            response_id,
+            tx: weak_tx,
+            format: self.format,
+            writer: Arc::clone(&self.writer),
+            pending: Arc::clone(&self.pending),
+        })
     }
 
     /// 同期の成功・失敗を送信し、接続を終了する
-    #[napi(ts_return_type = "Promise<void>")]
-    pub fn end_sync(&mut self, success: bool) -> AsyncTask<EndSyncTask> {
-        AsyncTask::new(EndSyncTask {
-            client: self,
-            success,
+    /// 成功を送った場合は相手の ACK (同期時刻を保存できたこと) を待ち、
+    /// `CLOSE_TIMEOUT` の間に受信できなければ `Error::Timeout`、ACK の前に切断されたら `Error::Disconnected` を返す
+    pub async fn end(&self, success: bool) -> Result<()> {
+        let mut handle = self.handle.lock().await;
+
+        // ping を終了 (レスポンス受付タスクは、成功を送った場合は ACK を受信するまで残す)
+        if let Some(pinger) = &self.pinger {
+            pinger.abort();
+        }
+        if !success {
+            handle.abort();
+        }
+
+        // 同期の成功 or 失敗を送信
+        {
+            let mut writer = self.writer.lock().await;
+
+            writer
+                .write_u8(if success { SYNC_SUCCESS } else { SYNC_FAILED })
+                .await?;
+            writer.flush().await?;
+        }
+
+        if !success {
+            return Ok(());
+        }
+
+        // レスポンス受付タスクが ACK を受信するのを待つ
+        // (ACK の前に届く、送信済みの ping への応答などはタスクが処理する)
+        let acknowledged = match tokio::time::timeout(CLOSE_TIMEOUT, &mut *handle).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) => Err(Error::Other(format!("Response receiver failed: {}", e))),
+            Err(_) => {
+                handle.abort();
+                Err(Error::Timeout { phase: "close" })
+            }
+        };
+        acknowledged?;
+
+        // 相手がデータを読むまでに切断してしまうと、こちらから送ったものが
+        // 届かないことがあるため、相手が切断するのを待つ
+        // ACK は受信済みで同期は成功しているので、待ちきれなくてもエラーにしない
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+            let mut reader = self.reader.lock().await;
+            tokio::io::copy(&mut *reader, &mut tokio::io::sink()).await
         })
+        .await;
+
+        Ok(())
     }
 }
 
-impl Drop for SyncClient {
+impl Drop for ClientSession {
     fn drop(&mut self) {
-        if let Some(state) = self.state.take() {
-            state.handle.abort();
+        self.handle.get_mut().abort();
+        if let Some(pinger) = &self.pinger {
+            pinger.abort();
         }
     }
 }
@@ -398,35 +969,110 @@ pub struct BeginSyncTask<'a> {
 }
 
 impl<'a> Task for BeginSyncTask<'a> {
-    type Output = ();
+    type Output = Result<()>;
     type JsValue = JsUndefined;
 
     fn compute(&mut self) -> napi::Result<Self::Output> {
-        Ok(RUNTIME.block_on(self.client.begin_sync_impl(&self.sync_enabled_uuids))?)
+        Ok(RUNTIME.block_on(self.client.begin_sync_impl(&self.sync_enabled_uuids)))
     }
 
-    fn resolve(&mut self, env: Env, _: Self::Output) -> napi::Result<Self::JsValue> {
+    fn resolve(&mut self, env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        output.map_err(|e| e.into_js_error(env))?;
         env.get_undefined()
     }
 }
 
 pub struct RequestDataTask<'a> {
     client: &'a SyncClient,
-    request_id: u8,
-    uuid: Option<String>,
+    request: Request,
+    aborted: AbortWatcher,
+    listener: Option<AbortListener>,
 }
 
 impl<'a> Task for RequestDataTask<'a> {
-    type Output = String;
+    type Output = Result<String>;
     type JsValue = JsString;
 
     fn compute(&mut self) -> napi::Result<Self::Output> {
-        Ok(RUNTIME.block_on(self.client.request_data_impl(self.request_id, &self.uuid))?)
+        Ok(RUNTIME.block_on(self.client.request_data_impl(&self.request, &self.aborted)))
     }
 
-    fn resolve(&mut self, env: Env, json: Self::Output) -> napi::Result<Self::JsValue> {
+    fn resolve(&mut self, env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        let json = output.map_err(|e| e.into_js_error(env))?;
         env.create_string(&json)
     }
+
+    fn finally(&mut self, env: Env) -> napi::Result<()> {
+        match self.listener.take() {
+            Some(listener) => listener.remove(env),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(not(feature = "peer"))]
+pub struct RequestStreamTask<'a> {
+    client: &'a SyncClient,
+    request: Request,
+    aborted: AbortWatcher,
+    listener: Option<AbortListener>,
+}
+
+#[cfg(not(feature = "peer"))]
+impl<'a> Task for RequestStreamTask<'a> {
+    type Output = Result<ResponseStream>;
+    type JsValue = SyncResponseStream;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        Ok(RUNTIME.block_on(
+            self.client
+                .request_stream_impl(&self.request, &self.aborted),
+        ))
+    }
+
+    fn resolve(&mut self, env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        let stream = output.map_err(|e| e.into_js_error(env))?;
+
+        Ok(SyncResponseStream {
+            stream: Arc::new(Mutex::new(stream)),
+        })
+    }
+
+    fn finally(&mut self, env: Env) -> napi::Result<()> {
+        match self.listener.take() {
+            Some(listener) => listener.remove(env),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct NextChunkTask {
+    stream: Arc<Mutex<ResponseStream>>,
+    aborted: AbortWatcher,
+    listener: Option<AbortListener>,
+}
+
+impl Task for NextChunkTask {
+    type Output = Result<Option<String>>;
+    type JsValue = Option<String>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        Ok(
+            RUNTIME
+                .block_on(async { self.stream.lock().await.next_abortable(&self.aborted).await }),
+        )
+    }
+
+    fn resolve(&mut self, env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        output.map_err(|e| e.into_js_error(env))
+    }
+
+    fn finally(&mut self, env: Env) -> napi::Result<()> {
+        match self.listener.take() {
+            Some(listener) => listener.remove(env),
+            None => Ok(()),
+        }
+    }
 }
 
 pub struct EndSyncTask<'a> {
@@ -435,14 +1081,15 @@ pub struct EndSyncTask<'a> {
 }
 
 impl<'a> Task for EndSyncTask<'a> {
-    type Output = ();
+    type Output = Result<()>;
     type JsValue = JsUndefined;
 
     fn compute(&mut self) -> napi::Result<Self::Output> {
-        Ok(RUNTIME.block_on(self.client.end_sync_impl(self.success))?)
+        Ok(RUNTIME.block_on(self.client.end_sync_impl(self.success)))
     }
 
-    fn resolve(&mut self, env: Env, _: Self::Output) -> napi::Result<Self::JsValue> {
+    fn resolve(&mut self, env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        output.map_err(|e| e.into_js_error(env))?;
         env.get_undefined()
     }
 }
@@ -0,0 +1,122 @@
+use std::io::{Read, Write};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+
+use super::{frame::FLAG_COMPRESSED, handshake::Capabilities};
+use crate::{error::Error, Result};
+
+/// これより小さいデータは圧縮しても小さくならないので、そのまま送る
+const MIN_COMPRESS_SIZE: usize = 256;
+
+/// レスポンスのデータの圧縮方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// 圧縮しない
+    None,
+    /// deflate で圧縮する (フレームごとにフラグで圧縮したかどうかを示す)
+    Deflate,
+}
+
+impl Compression {
+    /// 両者が対応している機能から圧縮方法を決める
+    pub fn negotiate(capabilities: Capabilities) -> Self {
+        if capabilities.contains(Capabilities::DEFLATE) {
+            Self::Deflate
+        } else {
+            Self::None
+        }
+    }
+
+    /// 送信するデータを圧縮する
+    /// 戻り値は (フレームのフラグ, データ)
+    /// 小さいデータや、圧縮しても小さくならないデータはそのまま返す
+    pub fn compress(self, data: Vec<u8>) -> Result<(u8, Vec<u8>)> {
+        if self == Self::None || data.len() < MIN_COMPRESS_SIZE {
+            return Ok((0, data));
+        }
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&data)?;
+        let compressed = encoder.finish()?;
+
+        if compressed.len() < data.len() {
+            Ok((FLAG_COMPRESSED, compressed))
+        } else {
+            Ok((0, data))
+        }
+    }
+
+    /// 受信したデータをフレームのフラグに従って展開する
+    /// 展開後のサイズが `max_size` を超える場合は `Error::ProtocolViolation` を返す
+    pub fn decompress(self, flags: u8, data: Vec<u8>, max_size: usize) -> Result<Vec<u8>> {
+        if flags & FLAG_COMPRESSED == 0 {
+            return Ok(data);
+        }
+
+        if self == Self::None {
+            return Err(Error::ProtocolViolation(
+                "Compressed frame without negotiating compression".to_owned(),
+            ));
+        }
+
+        let mut decompressed = Vec::new();
+        DeflateDecoder::new(&data[..])
+            .take((max_size as u64).saturating_add(1))
+            .read_to_end(&mut decompressed)
+            .map_err(|e| Error::ProtocolViolation(format!("Invalid compressed frame: {}", e)))?;
+
+        if decompressed.len() > max_size {
+            return Err(Error::ProtocolViolation(format!(
+                "Decompressed frame too large (max {} bytes)",
+                max_size
+            )));
+        }
+
+        Ok(decompressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn markdown() -> Vec<u8> {
+        "# 買い物\n\n- 牛乳\n- 卵\n- パン\n".repeat(20).into_bytes()
+    }
+
+    #[test]
+    fn large_data_is_compressed_and_restored() {
+        let (flags, compressed) = Compression::Deflate.compress(markdown()).unwrap();
+
+        assert_eq!(flags, FLAG_COMPRESSED);
+        assert!(compressed.len() < markdown().len());
+        assert_eq!(
+            Compression::Deflate
+                .decompress(flags, compressed, usize::MAX)
+                .unwrap(),
+            markdown()
+        );
+    }
+
+    #[test]
+    fn small_data_is_sent_as_is() {
+        let data = b"[]".to_vec();
+
+        assert_eq!(
+            Compression::Deflate.compress(data.clone()).unwrap(),
+            (0, data)
+        );
+    }
+
+    #[test]
+    fn nothing_is_compressed_without_negotiation() {
+        assert_eq!(
+            Compression::None.compress(markdown()).unwrap(),
+            (0, markdown())
+        );
+        assert!(matches!(
+            Compression::None.decompress(FLAG_COMPRESSED, markdown(), usize::MAX),
+            Err(Error::ProtocolViolation(_))
+        ));
+    }
+}
@@ -0,0 +1,102 @@
+use serde_json::Value;
+
+use super::handshake::Capabilities;
+use crate::{error::Error, Result};
+
+/// レスポンスのデータ (スレッド・メモの一覧) の送り方
+///
+/// JavaScript とのやりとりは常に JSON 文字列で行い、通信路の上でだけ変換する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// UTF-8 の JSON 文字列をそのまま送る
+    Json,
+    /// MessagePack に変換して送る
+    MessagePack,
+}
+
+impl Encoding {
+    /// 両者が対応している機能から使うエンコーディングを決める
+    /// どちらかが対応していない場合は JSON を使う
+    pub fn negotiate(capabilities: Capabilities) -> Self {
+        if capabilities.contains(Capabilities::MESSAGE_PACK) {
+            Self::MessagePack
+        } else {
+            Self::Json
+        }
+    }
+
+    /// JavaScript から受け取った JSON 文字列を送信するデータに変換する
+    pub fn encode(self, json: String) -> Result<Vec<u8>> {
+        match self {
+            Self::Json => Ok(json.into_bytes()),
+            Self::MessagePack => {
+                let value: Value = serde_json::from_str(&json)
+                    .map_err(|e| Error::Other(format!("Invalid JSON from the callback: {}", e)))?;
+
+                rmp_serde::to_vec(&value).map_err(|e| Error::Other(format!("{}", e)))
+            }
+        }
+    }
+
+    /// 受信したデータを JavaScript に渡す JSON 文字列に変換する
+    pub fn decode(self, data: Vec<u8>) -> Result<String> {
+        match self {
+            Self::Json => {
+                String::from_utf8(data).map_err(|e| Error::ProtocolViolation(format!("{}", e)))
+            }
+            Self::MessagePack => {
+                let value: Value = rmp_serde::from_slice(&data)
+                    .map_err(|e| Error::ProtocolViolation(format!("{}", e)))?;
+
+                Ok(value.to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTES: &str = r#"[{"id":"44444444-4444-4444-8444-444444444444","content":"メモ","parentId":null,"trash":false,"order":3}]"#;
+
+    #[test]
+    fn message_pack_is_negotiated_only_when_both_support_it() {
+        assert_eq!(
+            Encoding::negotiate(Capabilities::ALL.intersection(Capabilities::ALL)),
+            Encoding::MessagePack
+        );
+        assert_eq!(
+            Encoding::negotiate(Capabilities::ALL.intersection(Capabilities::empty())),
+            Encoding::Json
+        );
+    }
+
+    #[test]
+    fn round_trip_keeps_the_json_value() {
+        for encoding in [Encoding::Json, Encoding::MessagePack] {
+            let data = encoding.encode(NOTES.to_owned()).unwrap();
+            let json = encoding.decode(data).unwrap();
+
+            assert_eq!(
+                serde_json::from_str::<Value>(&json).unwrap(),
+                serde_json::from_str::<Value>(NOTES).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn message_pack_is_smaller_than_json() {
+        let data = Encoding::MessagePack.encode(NOTES.to_owned()).unwrap();
+
+        assert!(data.len() < NOTES.len());
+    }
+
+    #[test]
+    fn invalid_message_pack_is_a_protocol_violation() {
+        assert!(matches!(
+            Encoding::MessagePack.decode(vec![0xc1]),
+            Err(Error::ProtocolViolation(_))
+        ));
+    }
+}
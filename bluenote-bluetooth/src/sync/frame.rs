@@ -0,0 +1,516 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{
+    compression::Compression, encoding::Encoding, handshake::Capabilities, read_response_id,
+};
+use crate::{error::Error, Result};
+
+/// レスポンスのフレームのフラグ: データが deflate で圧縮されている
+pub const FLAG_COMPRESSED: u8 = 1 << 0;
+
+/// レスポンスのフレームのフラグ: 同じレスポンスの続きのチャンクがある
+pub const FLAG_MORE: u8 = 1 << 1;
+
+/// レスポンスのフレームのフラグ: リクエストの処理に失敗した
+/// データは `{"code": ..., "message": ..., "retryable": ...}` の JSON (エンコーディング・圧縮によらない)
+pub const FLAG_ERROR: u8 = 1 << 2;
+
+/// レスポンスのフレームのフラグ: ping への応答 (リクエスト ID は空白、データは空)
+pub const FLAG_PONG: u8 = 1 << 3;
+
+/// 受信するフレームのデータの最大サイズ (バイト) の既定値
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 64 * 1024 * 1024;
+
+/// 送受信するフレームのデータの最大サイズ (バイト)
+/// 圧縮されたデータは、展開した後のサイズにも適用する
+/// 変更は、以降に始まるセッションから適用される
+static MAX_FRAME_SIZE: AtomicU32 = AtomicU32::new(DEFAULT_MAX_FRAME_SIZE);
+
+/// 受信するフレームの最大サイズを変更する
+/// 分割されたレスポンスが受け取れなくならないよう、64 KiB 未満は指定できない
+pub fn set_max_frame_size(bytes: u32) -> Result<()> {
+    if (bytes as usize) < 2 * CHUNK_SIZE {
+        return Err(Error::InvalidArgument(format!(
+            "Max frame size must be at least {} bytes: {}",
+            2 * CHUNK_SIZE,
+            bytes
+        )));
+    }
+
+    MAX_FRAME_SIZE.store(bytes, Ordering::Relaxed);
+
+    Ok(())
+}
+
+/// レスポンスを分割する目安のサイズ (JSON のバイト数)
+/// これより大きいメモ一つはそのまま一つのチャンクになる
+const CHUNK_SIZE: usize = 32 * 1024;
+
+/// レスポンスのフレーム
+/// 分割されたレスポンスは、同じリクエスト ID のフレームが続けて送られる
+#[derive(Debug)]
+pub struct ResponseFrame {
+    pub request_id: String,
+    pub flags: u8,
+    pub data: Vec<u8>,
+}
+
+impl ResponseFrame {
+    /// レスポンスの最後のフレームか
+    pub fn is_last(&self) -> bool {
+        self.flags & FLAG_MORE == 0
+    }
+
+    /// ping への応答
+    pub fn pong() -> Self {
+        Self {
+            request_id: " ".repeat(36),
+            flags: FLAG_PONG,
+            data: Vec::new(),
+        }
+    }
+
+    /// ping への応答か
+    pub fn is_pong(&self) -> bool {
+        self.flags & FLAG_PONG != 0
+    }
+
+    /// エラーのレスポンスか
+    pub fn is_error(&self) -> bool {
+        self.flags & FLAG_ERROR != 0
+    }
+
+    /// エラーのレスポンスから、相手側でのエラーを取り出す
+    pub fn into_error(self) -> Result<Error> {
+        let value: Value = serde_json::from_slice(&self.data)
+            .map_err(|e| Error::ProtocolViolation(format!("Invalid error response: {}", e)))?;
+
+        match (value["code"].as_str(), value["message"].as_str()) {
+            (Some(code), Some(message)) => Ok(Error::RequestFailed {
+                code: code.to_owned(),
+                message: message.to_owned(),
+                retryable: value["retryable"].as_bool().unwrap_or(false),
+            }),
+            _ => Err(Error::ProtocolViolation(format!(
+                "Invalid error response: {}",
+                value
+            ))),
+        }
+    }
+}
+
+/// 相手と決めたレスポンスの送り方
+#[derive(Debug, Clone, Copy)]
+pub struct ResponseFormat {
+    encoding: Encoding,
+    compression: Compression,
+    chunked: bool,
+    error_frames: bool,
+    heartbeat: bool,
+    cancel: bool,
+    max_frame_size: u32,
+}
+
+impl ResponseFormat {
+    /// 両者が対応している機能から、レスポンスの送り方を決める
+    pub fn negotiate(capabilities: Capabilities) -> Self {
+        Self {
+            encoding: Encoding::negotiate(capabilities),
+            compression: Compression::negotiate(capabilities),
+            chunked: capabilities.contains(Capabilities::CHUNKED),
+            error_frames: capabilities.contains(Capabilities::ERROR_FRAMES),
+            heartbeat: capabilities.contains(Capabilities::HEARTBEAT),
+            // 取り消したリクエストにはエラーのレスポンスを返すので、両方必要
+            cancel: capabilities.contains(Capabilities::CANCEL)
+                && capabilities.contains(Capabilities::ERROR_FRAMES),
+            max_frame_size: MAX_FRAME_SIZE.load(Ordering::Relaxed),
+        }
+    }
+
+    /// フレームにフラグのバイトが付くか
+    /// フラグを使う機能をどれも使わない場合は、古い実装と同じくフラグなしで送る
+    fn has_flags(self) -> bool {
+        self.compression == Compression::Deflate
+            || self.chunked
+            || self.error_frames
+            || self.heartbeat
+    }
+
+    /// ping・pong を使うか
+    pub fn has_heartbeat(self) -> bool {
+        self.heartbeat
+    }
+
+    /// 処理中のリクエストを取り消せるか
+    pub fn has_cancel(self) -> bool {
+        self.cancel
+    }
+
+    /// リクエストの処理に失敗したとき、エラーのレスポンスを返せるか
+    /// 返せない場合は、古い実装と同じく切断するしかない
+    pub fn has_error_frames(self) -> bool {
+        self.error_frames
+    }
+
+    /// リクエストの処理に失敗したことを伝えるフレームを作る
+    pub fn error_frame(self, request_id: String, error: &Error) -> ResponseFrame {
+        let data = serde_json::json!({
+            "code": error.code(),
+            "message": error.to_string(),
+            "retryable": error.is_retryable(),
+        });
+
+        ResponseFrame {
+            request_id,
+            flags: FLAG_ERROR,
+            data: data.to_string().into_bytes(),
+        }
+    }
+
+    /// JavaScript から受け取った JSON を、送信するフレームに変換する
+    /// 分割できる場合は、JSON の配列をいくつかの配列に分けて、それぞれ変換 (と圧縮) する
+    /// (JSON 全体を受け取ってから分割するので、送信側のメモリは抑えられない。抑えられるのはフレームの大きさ)
+    /// 変換したデータが最大サイズを超える場合は、相手が受け取れないので `Error::InvalidArgument` を返す
+    pub fn to_frames(self, request_id: String, json: String) -> Result<Vec<ResponseFrame>> {
+        let chunks = if self.chunked {
+            split_into_chunks(json)?
+        } else {
+            vec![json]
+        };
+        let count = chunks.len();
+
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                // 受信側は展開した後のサイズにも最大サイズを適用するので、圧縮する前に確かめる
+                let encoded = self.encoding.encode(chunk)?;
+                self.check_frame_size(encoded.len())?;
+
+                let (flags, data) = self.compression.compress(encoded)?;
+                let more = if i + 1 < count { FLAG_MORE } else { 0 };
+
+                Ok(ResponseFrame {
+                    request_id: request_id.clone(),
+                    flags: flags | more,
+                    data,
+                })
+            })
+            .collect()
+    }
+
+    /// 受信したフレームを JavaScript に渡す JSON に変換する
+    pub fn decode(self, frame: ResponseFrame) -> Result<String> {
+        let data =
+            self.compression
+                .decompress(frame.flags, frame.data, self.max_frame_size as usize)?;
+
+        self.encoding.decode(data)
+    }
+
+    /// 送信するデータのサイズが最大サイズ以内か確かめる
+    fn check_frame_size(self, len: usize) -> Result<u32> {
+        match u32::try_from(len) {
+            Ok(size) if size <= self.max_frame_size => Ok(size),
+            _ => Err(Error::InvalidArgument(format!(
+                "Frame too large to send: {} bytes (max {})",
+                len, self.max_frame_size
+            ))),
+        }
+    }
+
+    /// フレームを書き込んで flush する
+    /// リクエスト ID、(フラグを使う場合は) フラグ 1 バイト、4 バイトのリトルエンディアンで
+    /// データのサイズを書き込み、以降データを書き込む
+    /// データが最大サイズを超える場合は、何も書き込まずに `Error::InvalidArgument` を返す
+    pub async fn write_frame<W>(self, frame: &ResponseFrame, writer: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let size = self.check_frame_size(frame.data.len())?;
+
+        writer.write_all(frame.request_id.as_bytes()).await?;
+        if self.has_flags() {
+            writer.write_u8(frame.flags).await?;
+        }
+        writer.write_u32_le(size).await?;
+        writer.write_all(&frame.data).await?;
+        writer.flush().await?;
+
+        Ok(())
+    }
+
+    /// フレームを一つ読み込む
+    /// データが最大サイズを超える場合は、読み込まずに `Error::ProtocolViolation` を返す
+    pub async fn read_frame<R>(self, reader: &mut R) -> Result<ResponseFrame>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let request_id = read_response_id(reader).await?;
+        let flags = if self.has_flags() {
+            reader.read_u8().await?
+        } else {
+            0
+        };
+
+        if flags & FLAG_MORE != 0 && !self.chunked {
+            return Err(Error::ProtocolViolation(
+                "Chunked response without negotiating it".to_owned(),
+            ));
+        }
+        if flags & FLAG_ERROR != 0 && (!self.error_frames || flags != FLAG_ERROR) {
+            return Err(Error::ProtocolViolation(format!(
+                "Unexpected error response (flags: {:#04x})",
+                flags
+            )));
+        }
+        if flags & FLAG_PONG != 0 && (!self.heartbeat || flags != FLAG_PONG) {
+            return Err(Error::ProtocolViolation(format!(
+                "Unexpected pong (flags: {:#04x})",
+                flags
+            )));
+        }
+
+        let size = reader.read_u32_le().await?;
+
+        if size > self.max_frame_size {
+            return Err(Error::ProtocolViolation(format!(
+                "Frame too large: {} bytes (max {})",
+                size, self.max_frame_size
+            )));
+        }
+
+        let mut data = vec![0u8; size as usize];
+        reader.read_exact(&mut data).await?;
+
+        Ok(ResponseFrame {
+            request_id,
+            flags,
+            data,
+        })
+    }
+}
+
+/// 大きな JSON の配列を、`CHUNK_SIZE` 程度の配列に分割する
+/// 配列でない場合は分割せずに返す
+fn split_into_chunks(json: String) -> Result<Vec<String>> {
+    if json.len() <= CHUNK_SIZE {
+        return Ok(vec![json]);
+    }
+
+    let items = match serde_json::from_str(&json) {
+        Ok(Value::Array(items)) => items,
+        Ok(_) => return Ok(vec![json]),
+        Err(e) => {
+            return Err(Error::Other(format!(
+                "Invalid JSON from the callback: {}",
+                e
+            )))
+        }
+    };
+
+    let mut chunks = Vec::new();
+    let mut chunk = String::from("[");
+
+    for item in items {
+        let item = item.to_string();
+
+        if chunk.len() > 1 && chunk.len() + item.len() + 2 > CHUNK_SIZE {
+            chunk.push(']');
+            chunks.push(std::mem::replace(&mut chunk, String::from("[")));
+        }
+        if chunk.len() > 1 {
+            chunk.push(',');
+        }
+        chunk.push_str(&item);
+    }
+
+    chunk.push(']');
+    chunks.push(chunk);
+
+    Ok(chunks)
+}
+
+/// 分割して受信した JSON の配列を一つにつなげる
+/// チャンクごとに受け取らないリクエスト (`SyncClient.streamAllNotesInThread`・`streamAllNotesInTree` 以外) で使う
+pub fn concat_chunks(chunks: Vec<String>) -> Result<String> {
+    if chunks.len() == 1 {
+        return Ok(chunks.into_iter().next().unwrap());
+    }
+
+    let mut items = Vec::new();
+
+    for chunk in chunks {
+        let chunk: Vec<Value> = serde_json::from_str(&chunk)
+            .map_err(|e| Error::ProtocolViolation(format!("Invalid chunk: {}", e)))?;
+        items.extend(chunk);
+    }
+
+    Ok(Value::Array(items).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `CHUNK_SIZE` を超える大きさのメモの配列
+    fn notes() -> String {
+        let notes: Vec<Value> = (0..100)
+            .map(|i| serde_json::json!({ "id": i, "content": "メモ".repeat(200) }))
+            .collect();
+
+        Value::Array(notes).to_string()
+    }
+
+    #[test]
+    fn large_arrays_are_split_into_bounded_chunks() {
+        let chunks = split_into_chunks(notes()).unwrap();
+
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= CHUNK_SIZE));
+        assert_eq!(concat_chunks(chunks).unwrap(), notes());
+    }
+
+    #[test]
+    fn small_data_and_non_arrays_are_not_split() {
+        assert_eq!(split_into_chunks("[]".to_owned()).unwrap(), vec!["[]"]);
+
+        let object = format!(r#"{{"content":"{}"}}"#, "a".repeat(CHUNK_SIZE));
+        assert_eq!(split_into_chunks(object.clone()).unwrap(), vec![object]);
+    }
+
+    #[tokio::test]
+    async fn only_the_last_frame_has_no_more_flag() {
+        let format = ResponseFormat::negotiate(Capabilities::ALL);
+        let frames = format.to_frames(" ".repeat(36), notes()).unwrap();
+
+        assert!(frames.len() > 1);
+        assert!(frames[..frames.len() - 1].iter().all(|f| !f.is_last()));
+        assert!(frames.last().unwrap().is_last());
+
+        let mut buffer = Vec::new();
+        for frame in &frames {
+            format.write_frame(frame, &mut buffer).await.unwrap();
+        }
+
+        let mut reader = &buffer[..];
+        let mut chunks = Vec::new();
+        for _ in 0..frames.len() {
+            let frame = format.read_frame(&mut reader).await.unwrap();
+            chunks.push(format.decode(frame).unwrap());
+        }
+
+        assert!(reader.is_empty());
+        assert_eq!(concat_chunks(chunks).unwrap(), notes());
+    }
+
+    #[tokio::test]
+    async fn frames_larger_than_the_limit_are_rejected() {
+        let format = ResponseFormat {
+            max_frame_size: 1024,
+            ..ResponseFormat::negotiate(Capabilities::ALL)
+        };
+
+        // サイズだけ大きいフレーム (データは読まずに弾く)
+        let mut buffer = " ".repeat(36).into_bytes();
+        buffer.push(0);
+        buffer.extend(u32::MAX.to_le_bytes());
+
+        assert!(matches!(
+            format.read_frame(&mut &buffer[..]).await,
+            Err(Error::ProtocolViolation(_))
+        ));
+
+        // 展開すると大きくなるフレーム
+        let frames = ResponseFormat::negotiate(Capabilities::ALL)
+            .to_frames(" ".repeat(36), format!("[\"{}\"]", "a".repeat(4096)))
+            .unwrap();
+        let mut buffer = Vec::new();
+        format.write_frame(&frames[0], &mut buffer).await.unwrap();
+        let frame = format.read_frame(&mut &buffer[..]).await.unwrap();
+
+        assert!(matches!(
+            format.decode(frame),
+            Err(Error::ProtocolViolation(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn frames_larger_than_the_limit_are_not_sent() {
+        let format = ResponseFormat {
+            max_frame_size: 1024,
+            ..ResponseFormat::negotiate(Capabilities::ALL)
+        };
+
+        // 分割できないデータは、圧縮すれば小さくなっても送らない
+        let object = format!(r#"{{"content":"{}"}}"#, "a".repeat(4096));
+        assert!(matches!(
+            format.to_frames(" ".repeat(36), object),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        let frame = ResponseFrame {
+            request_id: " ".repeat(36),
+            flags: 0,
+            data: vec![0; 1025],
+        };
+        let mut buffer = Vec::new();
+
+        assert!(matches!(
+            format.write_frame(&frame, &mut buffer).await,
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn invalid_response_ids_are_rejected() {
+        let format = ResponseFormat::negotiate(Capabilities::ALL);
+        let mut buffer = "not-a-uuid".repeat(4).as_bytes()[..36].to_vec();
+        buffer.extend([0, 0, 0, 0, 0]);
+
+        assert!(matches!(
+            format.read_frame(&mut &buffer[..]).await,
+            Err(Error::ProtocolViolation(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn error_frames_carry_the_code_and_message() {
+        let format = ResponseFormat::negotiate(Capabilities::ALL);
+        let frame = format.error_frame(" ".repeat(36), &Error::Timeout { phase: "now" });
+
+        let mut buffer = Vec::new();
+        format.write_frame(&frame, &mut buffer).await.unwrap();
+        let frame = format.read_frame(&mut &buffer[..]).await.unwrap();
+
+        assert!(frame.is_error());
+        assert!(frame.is_last());
+        assert!(matches!(
+            frame.into_error().unwrap(),
+            Error::RequestFailed { code, message, retryable: true }
+                if code == "TIMEOUT" && message == "Timed out: now"
+        ));
+    }
+
+    #[tokio::test]
+    async fn more_flag_without_negotiation_is_a_protocol_violation() {
+        let format = ResponseFormat::negotiate(Capabilities::DEFLATE);
+        let frame = ResponseFrame {
+            request_id: " ".repeat(36),
+            flags: FLAG_MORE,
+            data: b"[]".to_vec(),
+        };
+
+        let mut buffer = Vec::new();
+        format.write_frame(&frame, &mut buffer).await.unwrap();
+
+        assert!(matches!(
+            format.read_frame(&mut &buffer[..]).await,
+            Err(Error::ProtocolViolation(_))
+        ));
+    }
+}
@@ -0,0 +1,153 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{error::Error, Result};
+
+/// 同期プロトコルのバージョン
+/// docs/protocol_v2.md
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// 対応している機能のフラグ
+/// バージョンの交換と一緒に送り、両者が対応している機能だけを使う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// レスポンスのデータを JSON の代わりに MessagePack で送れる
+    pub const MESSAGE_PACK: Self = Self(1 << 0);
+
+    /// レスポンスのデータを deflate で圧縮して送れる
+    /// レスポンスのフレームに、圧縮したかどうかを示すフラグが付く
+    pub const DEFLATE: Self = Self(1 << 1);
+
+    /// 大きなレスポンスを分割して送れる
+    /// レスポンスのフレームに、続きがあるかどうかを示すフラグが付く
+    pub const CHUNKED: Self = Self(1 << 2);
+
+    /// リクエストの処理に失敗したとき、切断せずにエラーをレスポンスとして返せる
+    /// レスポンスのフレームに、エラーかどうかを示すフラグが付く
+    pub const ERROR_FRAMES: Self = Self(1 << 3);
+
+    /// クライアントが定期的に ping を送り、サーバが pong を返す
+    /// 一定時間何も受信しなければ、接続が切れたとみなす
+    pub const HEARTBEAT: Self = Self(1 << 4);
+
+    /// 接続が切れた同期を、同期 ID を指定して再開できる
+    /// 同期の許可の後に同期 ID を交換する
+    pub const RESUME: Self = Self(1 << 5);
+
+    /// 処理中のリクエストを取り消せる
+    /// 取り消されたリクエストには、エラーのレスポンスを返す (`ERROR_FRAMES` も必要)
+    pub const CANCEL: Self = Self(1 << 6);
+
+    /// ペアリングで共有した鍵で、互いに相手を認証する
+    /// UUID の交換の後にチャレンジと証明を交換する
+    pub const AUTH: Self = Self(1 << 7);
+
+    /// 認証の後の通信を、ペアリングで共有した鍵から導出した鍵で暗号化する
+    /// 両者が鍵を持っている場合だけ暗号化する (`AUTH` も必要)
+    pub const ENCRYPT: Self = Self(1 << 8);
+
+    /// このライブラリが対応しているすべての機能
+    pub const ALL: Self = Self(
+        Self::MESSAGE_PACK.0
+            | Self::DEFLATE.0
+            | Self::CHUNKED.0
+            | Self::ERROR_FRAMES.0
+            | Self::HEARTBEAT.0
+            | Self::RESUME.0
+            | Self::CANCEL.0
+            | Self::AUTH.0
+            | Self::ENCRYPT.0,
+    );
+
+    /// 何も対応していない
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// 送信するフラグの値
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// `other` の機能をすべて含んでいるか
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// 両者が対応している機能
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// `other` の機能を除いたもの
+    pub fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+/// バージョンの交換で送り合った、機能のフラグ
+/// 認証では両方をそのまま証明に含めるので、途中でどちらかを書き換えられれば気づける
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Advertised {
+    /// 自身が送ったフラグ
+    pub ours: Capabilities,
+    /// 相手から受信したフラグ
+    pub theirs: Capabilities,
+}
+
+impl Advertised {
+    /// 両者が対応している機能
+    pub fn negotiated(self) -> Capabilities {
+        self.ours.intersection(self.theirs)
+    }
+}
+
+/// プロトコルのバージョンと対応している機能を交換する
+///
+/// バージョンが異なる場合は `Error::VersionMismatch` を返す
+/// 戻り値は送り合ったフラグ (使う機能は `Advertised::negotiated`)
+pub async fn exchange_version<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    capabilities: Capabilities,
+) -> Result<Advertised>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (send_result, received): (Result<()>, Result<(u8, Capabilities)>) = futures::join!(
+        async {
+            // 自身のバージョンと対応機能を送信
+            writer.write_u8(PROTOCOL_VERSION).await?;
+            writer.write_u32_le(capabilities.0).await?;
+            writer.flush().await?;
+
+            Ok(())
+        },
+        async {
+            // 相手のバージョンと対応機能を受信
+            let version = reader.read_u8().await?;
+            let capabilities = reader.read_u32_le().await?;
+
+            Ok((version, Capabilities(capabilities)))
+        }
+    );
+
+    send_result?;
+    let (version, their_capabilities) = received?;
+
+    println!("Protocol version: {}", version);
+
+    if version != PROTOCOL_VERSION {
+        return Err(Error::VersionMismatch {
+            ours: PROTOCOL_VERSION,
+            theirs: version,
+        });
+    }
+
+    Ok(Advertised {
+        ours: capabilities,
+        theirs: their_capabilities,
+    })
+}
@@ -0,0 +1,35 @@
+use std::{future::Future, time::Duration};
+
+use super::handshake::Capabilities;
+use crate::{error::Error, Result};
+
+/// クライアントが ping を送る間隔
+pub const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// これだけの間相手から何も受信しなければ、接続が切れたとみなす
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// ping に対応していない相手の場合に、接続が切れたとみなすまでの時間
+/// (以前のクライアントが同期全体に設けていた制限時間と同じ)
+pub const LEGACY_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// 両者が対応している機能から、接続が切れたとみなすまでの時間を決める
+pub fn idle_timeout(capabilities: Capabilities) -> Duration {
+    if capabilities.contains(Capabilities::HEARTBEAT) {
+        IDLE_TIMEOUT
+    } else {
+        LEGACY_IDLE_TIMEOUT
+    }
+}
+
+/// 相手からの受信を待つ
+/// `timeout` の間に受信できなければ `Error::Timeout` を返す
+pub async fn receive_within<F, T>(timeout: Duration, receive: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    match tokio::time::timeout(timeout, receive).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::Timeout { phase: "idle" }),
+    }
+}
@@ -0,0 +1,362 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::Mutex,
+    time::Duration,
+};
+
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use super::Request;
+
+/// 接続が切れてから、同期を再開できる期間
+pub const RESUME_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// 再開のために覚えておく、取得し終えたリクエストのデータの合計の上限 (バイト)
+/// すべての同期を合わせた大きさで、超えた分は古いものから捨てる (捨てたリクエストは JavaScript から取得し直す)
+pub const MAX_COMPLETED_BYTES: usize = 8 * 1024 * 1024;
+
+/// 同期サーバで、再開できる同期の一覧
+pub static RESUMABLE_SYNCS: ResumableSyncs = ResumableSyncs::new();
+
+/// 再開するために覚えておく、同期の途中経過
+struct Checkpoint {
+    /// 相手のデバイスの UUID (ほかのデバイスからは再開できない)
+    uuid: String,
+    /// 返却するデータの範囲 (最初の接続で `now()` から取得した時刻)
+    updated_end: String,
+    /// データを取得し終えたリクエストと、そのデータ (JSON)
+    /// 再開した接続で同じリクエストを受信したら、JavaScript に問い合わせずにこれを返す
+    /// (`MAX_COMPLETED_BYTES` を超えた分は捨てるので、すべてのリクエストがあるとは限らない)
+    completed: HashMap<Request, String>,
+    /// この同期を処理している接続の数
+    connections: u32,
+    /// 接続がなくなった後、破棄する時刻
+    expires_at: Option<Instant>,
+}
+
+/// 同期 ID ごとの途中経過と、覚えているデータの大きさ
+struct Syncs {
+    checkpoints: BTreeMap<String, Checkpoint>,
+    /// 覚えているデータの (同期 ID, リクエスト) を古い順に並べたもの (上限を超えたときに捨てる順番)
+    completed_order: VecDeque<(String, Request)>,
+    /// 覚えているデータの合計の大きさ (バイト)
+    completed_bytes: usize,
+}
+
+impl Syncs {
+    /// 同期を忘れ、覚えていたデータを捨てる
+    fn remove(&mut self, id: &str) {
+        if let Some(checkpoint) = self.checkpoints.remove(id) {
+            self.completed_bytes -= checkpoint
+                .completed
+                .values()
+                .map(String::len)
+                .sum::<usize>();
+            self.completed_order.retain(|(sync_id, _)| sync_id != id);
+        }
+    }
+
+    fn remove_expired(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .checkpoints
+            .iter()
+            .filter(|(_, c)| c.expires_at.is_some_and(|expires_at| expires_at <= now))
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        for id in expired {
+            self.remove(&id);
+        }
+    }
+
+    /// 覚えているデータを 1 つ捨てる
+    fn forget_completed(&mut self, id: &str, request: &Request) {
+        let removed = self
+            .checkpoints
+            .get_mut(id)
+            .and_then(|checkpoint| checkpoint.completed.remove(request));
+
+        if let Some(data) = removed {
+            self.completed_bytes -= data.len();
+        }
+    }
+}
+
+/// 同期 ID ごとに、同期の途中経過を管理する
+pub struct ResumableSyncs {
+    syncs: Mutex<Syncs>,
+    /// 覚えておくデータの合計の上限 (バイト)
+    max_completed_bytes: usize,
+}
+
+impl ResumableSyncs {
+    pub(crate) const fn new() -> Self {
+        Self::with_max_completed_bytes(MAX_COMPLETED_BYTES)
+    }
+
+    pub(crate) const fn with_max_completed_bytes(max_completed_bytes: usize) -> Self {
+        Self {
+            syncs: Mutex::new(Syncs {
+                checkpoints: BTreeMap::new(),
+                completed_order: VecDeque::new(),
+                completed_bytes: 0,
+            }),
+            max_completed_bytes,
+        }
+    }
+
+    /// 新しい同期を登録する
+    pub fn start(&'static self, uuid: &str, updated_end: String) -> ResumableSync {
+        let id = Uuid::new_v4().to_string();
+        let mut syncs = self.syncs.lock().unwrap();
+
+        syncs.remove_expired();
+        syncs.checkpoints.insert(
+            id.clone(),
+            Checkpoint {
+                uuid: uuid.to_owned(),
+                updated_end: updated_end.clone(),
+                completed: HashMap::new(),
+                connections: 1,
+                expires_at: None,
+            },
+        );
+
+        ResumableSync {
+            id,
+            updated_end,
+            registry: self,
+            finished: false,
+        }
+    }
+
+    /// 接続が切れた同期を再開する
+    /// 同期 ID が見つからない・期限切れ・ほかのデバイスの同期の場合は `None` を返す
+    pub fn resume(&'static self, id: &str, uuid: &str) -> Option<ResumableSync> {
+        let mut syncs = self.syncs.lock().unwrap();
+
+        syncs.remove_expired();
+
+        let checkpoint = syncs.checkpoints.get_mut(id).filter(|c| c.uuid == uuid)?;
+        checkpoint.connections += 1;
+        checkpoint.expires_at = None;
+
+        Some(ResumableSync {
+            id: id.to_owned(),
+            updated_end: checkpoint.updated_end.clone(),
+            registry: self,
+            finished: false,
+        })
+    }
+
+    /// 接続が切れたので、期限付きで再開を待つ
+    fn suspend(&self, id: &str) {
+        if let Some(checkpoint) = self.syncs.lock().unwrap().checkpoints.get_mut(id) {
+            checkpoint.connections -= 1;
+
+            if checkpoint.connections == 0 {
+                checkpoint.expires_at = Some(Instant::now() + RESUME_TTL);
+            }
+        }
+    }
+
+    fn finish(&self, id: &str) {
+        self.syncs.lock().unwrap().remove(id);
+    }
+
+    fn completed(&self, id: &str, request: &Request) -> Option<String> {
+        let syncs = self.syncs.lock().unwrap();
+
+        syncs.checkpoints.get(id)?.completed.get(request).cloned()
+    }
+
+    /// 取得し終えたリクエストのデータを覚える
+    /// 上限を超えたら、すべての同期のデータのうち古いものから捨てる (上限より大きいデータは覚えない)
+    fn complete(&self, id: &str, request: Request, data: String) {
+        if data.len() > self.max_completed_bytes {
+            return;
+        }
+
+        let mut syncs = self.syncs.lock().unwrap();
+
+        if !syncs.checkpoints.contains_key(id) {
+            return;
+        }
+
+        syncs.forget_completed(id, &request);
+        syncs
+            .completed_order
+            .retain(|(sync_id, r)| !(sync_id == id && *r == request));
+
+        syncs.completed_bytes += data.len();
+        syncs
+            .completed_order
+            .push_back((id.to_owned(), request.clone()));
+        syncs
+            .checkpoints
+            .get_mut(id)
+            .unwrap()
+            .completed
+            .insert(request, data);
+
+        while syncs.completed_bytes > self.max_completed_bytes {
+            let Some((oldest_id, oldest)) = syncs.completed_order.pop_front() else {
+                break;
+            };
+            syncs.forget_completed(&oldest_id, &oldest);
+        }
+    }
+
+    /// 覚えているデータの合計の大きさ (バイト)
+    #[cfg(test)]
+    fn completed_bytes(&self) -> usize {
+        self.syncs.lock().unwrap().completed_bytes
+    }
+}
+
+/// 接続が処理している同期のハンドル
+/// 終了 (`finish`) せずに drop されると、一定期間再開を待つ
+pub struct ResumableSync {
+    id: String,
+    updated_end: String,
+    registry: &'static ResumableSyncs,
+    finished: bool,
+}
+
+impl ResumableSync {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn updated_end(&self) -> &str {
+        &self.updated_end
+    }
+
+    /// この同期で、データを取得し終えたリクエストならそのデータを返す
+    pub fn completed(&self, request: &Request) -> Option<String> {
+        self.registry.completed(&self.id, request)
+    }
+
+    /// リクエストのデータを取得し終えたことを記録する
+    pub fn complete(&self, request: Request, data: String) {
+        self.registry.complete(&self.id, request, data);
+    }
+
+    /// 同期が終わった (成功・失敗を受信した) ので、再開できないようにする
+    pub fn finish(mut self) {
+        self.registry.finish(&self.id);
+        self.finished = true;
+    }
+}
+
+impl Drop for ResumableSync {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.registry.suspend(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_UUID: &str = "22222222-2222-4222-8222-222222222222";
+    const OTHER_UUID: &str = "55555555-5555-4555-8555-555555555555";
+    const NOW: &str = "2023-01-01T00:00:00.000Z";
+
+    static SYNCS: ResumableSyncs = ResumableSyncs::new();
+
+    #[test]
+    fn dropped_sync_resumes_with_the_same_range() {
+        let id = SYNCS.start(CLIENT_UUID, NOW.to_owned()).id().to_owned();
+        let resumed = SYNCS.resume(&id, CLIENT_UUID).unwrap();
+
+        assert_eq!(resumed.updated_end(), NOW);
+    }
+
+    #[test]
+    fn completed_requests_are_remembered_across_connections() {
+        let sync = SYNCS.start(CLIENT_UUID, NOW.to_owned());
+        let id = sync.id().to_owned();
+        sync.complete(Request::ThreadUpdates, "[]".to_owned());
+        drop(sync);
+
+        let resumed = SYNCS.resume(&id, CLIENT_UUID).unwrap();
+        let thread_id = "33333333-3333-4333-8333-333333333333".to_owned();
+
+        assert_eq!(
+            resumed.completed(&Request::ThreadUpdates),
+            Some("[]".to_owned())
+        );
+        assert_eq!(
+            resumed.completed(&Request::AllNotesInThread { thread_id }),
+            None
+        );
+    }
+
+    #[test]
+    fn completed_data_is_capped_and_the_oldest_is_evicted() {
+        static SYNCS: ResumableSyncs = ResumableSyncs::with_max_completed_bytes(10);
+
+        let a = SYNCS.start(CLIENT_UUID, NOW.to_owned());
+        let b = SYNCS.start(CLIENT_UUID, NOW.to_owned());
+        let thread_id = "33333333-3333-4333-8333-333333333333".to_owned();
+        let notes = Request::AllNotesInThread { thread_id };
+
+        a.complete(Request::ThreadUpdates, "[1,2,3]".to_owned());
+        b.complete(notes.clone(), "[4,5,6]".to_owned());
+
+        // ほかの同期のデータでも、古いものから捨てる
+        assert_eq!(a.completed(&Request::ThreadUpdates), None);
+        assert_eq!(b.completed(&notes), Some("[4,5,6]".to_owned()));
+        assert_eq!(SYNCS.completed_bytes(), 7);
+
+        // 上限より大きいデータは覚えない
+        a.complete(Request::ThreadUpdates, "[1,2,3,4,5,6]".to_owned());
+        assert_eq!(a.completed(&Request::ThreadUpdates), None);
+        assert_eq!(SYNCS.completed_bytes(), 7);
+
+        // 終わった同期のデータは捨てる
+        b.finish();
+        assert_eq!(SYNCS.completed_bytes(), 0);
+    }
+
+    #[test]
+    fn other_devices_cannot_resume() {
+        let id = SYNCS.start(CLIENT_UUID, NOW.to_owned()).id().to_owned();
+
+        assert!(SYNCS.resume(&id, OTHER_UUID).is_none());
+    }
+
+    #[test]
+    fn finished_sync_cannot_be_resumed() {
+        let sync = SYNCS.start(CLIENT_UUID, NOW.to_owned());
+        let id = sync.id().to_owned();
+        sync.finish();
+
+        assert!(SYNCS.resume(&id, CLIENT_UUID).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sync_expires_after_the_last_connection_is_dropped() {
+        // ほかのテストと時計が異なるので、別の一覧を使う
+        static SYNCS: ResumableSyncs = ResumableSyncs::new();
+
+        let sync = SYNCS.start(CLIENT_UUID, NOW.to_owned());
+        let id = sync.id().to_owned();
+        let resumed = SYNCS.resume(&id, CLIENT_UUID).unwrap();
+        drop(sync);
+
+        // 再開した接続が生きている間は期限切れにならない
+        tokio::time::advance(RESUME_TTL * 2).await;
+        drop(resumed);
+        tokio::time::advance(RESUME_TTL / 2).await;
+        drop(SYNCS.resume(&id, CLIENT_UUID).unwrap());
+
+        tokio::time::advance(RESUME_TTL * 2).await;
+        assert!(SYNCS.resume(&id, CLIENT_UUID).is_none());
+    }
+}
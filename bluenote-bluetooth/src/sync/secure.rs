@@ -0,0 +1,325 @@
+use std::{
+    fmt, io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// セッション鍵の長さ (バイト)
+pub const SESSION_KEY_LEN: usize = 32;
+
+/// 1 つのレコードに入れる平文の最大の長さ (バイト)
+pub const MAX_RECORD_LEN: usize = 16 * 1024;
+
+/// 認証タグの長さ (バイト)
+const TAG_LEN: usize = 16;
+
+/// レコードの長さのヘッダの長さ (バイト)
+const HEADER_LEN: usize = 2;
+
+/// 認証の後に導出した、方向ごとのセッション鍵
+/// 送信と受信で別の鍵を使うので、カウンタのナンスが両方向で重なることはない
+#[derive(Clone, PartialEq, Eq)]
+pub struct SessionKeys {
+    pub(crate) send: [u8; SESSION_KEY_LEN],
+    pub(crate) receive: [u8; SESSION_KEY_LEN],
+}
+
+// 鍵がログに出ないようにする
+impl fmt::Debug for SessionKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionKeys(..)")
+    }
+}
+
+/// レコードごとのナンス (96 ビット、下位 64 ビットがカウンタ)
+/// 鍵ごとに 0 から数え、同じ鍵でナンスを使い回さない
+fn nonce(counter: &mut u64) -> io::Result<Nonce> {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+
+    *counter = counter
+        .checked_add(1)
+        .ok_or_else(|| io::Error::other("Record counter exhausted"))?;
+
+    Ok(*Nonce::from_slice(&nonce))
+}
+
+/// 暗号化された通信路から、復号したデータを読み込む
+///
+/// レコードは 2 バイトの暗号文の長さ (リトルエンディアン) と、ChaCha20-Poly1305 の暗号文 (認証タグ付き)
+/// 改ざんされたレコードや順番の入れ替わったレコードは、復号に失敗してエラーになる
+pub struct EncryptedReader<R> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    /// 受信中のレコード (ヘッダ + 暗号文)
+    record: Vec<u8>,
+    /// `record` のうち受信済みの長さ
+    filled: usize,
+    /// 復号済みでまだ読まれていないデータ
+    plaintext: Vec<u8>,
+    position: usize,
+}
+
+impl<R> EncryptedReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    pub fn new(inner: R, key: &[u8; SESSION_KEY_LEN]) -> Self {
+        Self {
+            inner,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            counter: 0,
+            record: vec![0; HEADER_LEN],
+            filled: 0,
+            plaintext: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// レコードを 1 つ受信して復号する
+    /// 相手がレコードの境目で切断した場合は `Ok(false)` を返す
+    fn poll_record(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        loop {
+            if self.filled == HEADER_LEN && self.record.len() == HEADER_LEN {
+                let len = u16::from_le_bytes([self.record[0], self.record[1]]) as usize;
+
+                if !(TAG_LEN..=MAX_RECORD_LEN + TAG_LEN).contains(&len) {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid record length: {}", len),
+                    )));
+                }
+
+                self.record.resize(HEADER_LEN + len, 0);
+            }
+
+            if self.filled == self.record.len() {
+                break;
+            }
+
+            let mut buf = ReadBuf::new(&mut self.record[self.filled..]);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf))?;
+
+            match buf.filled().len() {
+                0 if self.filled == 0 => return Poll::Ready(Ok(false)),
+                0 => return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+                n => self.filled += n,
+            }
+        }
+
+        let nonce = nonce(&mut self.counter)?;
+        self.plaintext = self
+            .cipher
+            .decrypt(&nonce, &self.record[HEADER_LEN..])
+            .map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "Failed to decrypt a record")
+            })?;
+        self.position = 0;
+
+        self.record.truncate(HEADER_LEN);
+        self.filled = 0;
+
+        Poll::Ready(Ok(true))
+    }
+}
+
+impl<R> AsyncRead for EncryptedReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while this.position == this.plaintext.len() {
+            if !ready!(this.poll_record(cx))? {
+                return Poll::Ready(Ok(()));
+            }
+        }
+
+        let len = buf.remaining().min(this.plaintext.len() - this.position);
+        buf.put_slice(&this.plaintext[this.position..this.position + len]);
+        this.position += len;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// 書き込んだデータを暗号化して、通信路に送信する
+///
+/// `MAX_RECORD_LEN` まで溜めるか flush されるまで、レコードにせずに溜めておく
+pub struct EncryptedWriter<W> {
+    inner: W,
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    /// まだ暗号化していないデータ
+    plaintext: Vec<u8>,
+    /// 送信中のレコード (ヘッダ + 暗号文)
+    record: Vec<u8>,
+    /// `record` のうち送信済みの長さ
+    written: usize,
+}
+
+impl<W> EncryptedWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    pub fn new(inner: W, key: &[u8; SESSION_KEY_LEN]) -> Self {
+        Self {
+            inner,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            counter: 0,
+            plaintext: Vec::new(),
+            record: Vec::new(),
+            written: 0,
+        }
+    }
+
+    /// 溜まっているデータをすべてレコードにして送信する
+    fn poll_send_records(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            if self.written < self.record.len() {
+                let n =
+                    ready!(Pin::new(&mut self.inner).poll_write(cx, &self.record[self.written..]))?;
+
+                if n == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+
+                self.written += n;
+                continue;
+            }
+
+            if self.plaintext.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+
+            let nonce = nonce(&mut self.counter)?;
+            let ciphertext = self
+                .cipher
+                .encrypt(&nonce, self.plaintext.as_slice())
+                .map_err(|_| io::Error::other("Failed to encrypt a record"))?;
+
+            self.record.clear();
+            self.record
+                .extend_from_slice(&(ciphertext.len() as u16).to_le_bytes());
+            self.record.extend_from_slice(&ciphertext);
+            self.written = 0;
+            self.plaintext.clear();
+        }
+    }
+}
+
+impl<W> AsyncWrite for EncryptedWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.plaintext.len() == MAX_RECORD_LEN {
+            ready!(this.poll_send_records(cx))?;
+        }
+
+        let len = buf.len().min(MAX_RECORD_LEN - this.plaintext.len());
+        this.plaintext.extend_from_slice(&buf[..len]);
+
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_send_records(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_send_records(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    const KEY: [u8; SESSION_KEY_LEN] = [1; SESSION_KEY_LEN];
+
+    /// `data` を暗号化した、通信路に流れるバイト列
+    async fn encrypt(data: &[u8]) -> Vec<u8> {
+        let mut writer = EncryptedWriter::new(Vec::new(), &KEY);
+        writer.write_all(data).await.unwrap();
+        writer.flush().await.unwrap();
+
+        writer.inner
+    }
+
+    #[tokio::test]
+    async fn data_larger_than_a_record_round_trips() {
+        let data: Vec<u8> = (0..MAX_RECORD_LEN * 2 + 100).map(|i| i as u8).collect();
+        let encrypted = encrypt(&data).await;
+
+        assert_eq!(encrypted.len(), data.len() + 3 * (HEADER_LEN + TAG_LEN));
+        assert!(!encrypted.windows(64).any(|window| window == &data[..64]));
+
+        let mut reader = EncryptedReader::new(encrypted.as_slice(), &KEY);
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).await.unwrap();
+
+        assert_eq!(decrypted, data);
+    }
+
+    #[tokio::test]
+    async fn tampered_record_is_rejected() {
+        let mut encrypted = encrypt(b"hello").await;
+        *encrypted.last_mut().unwrap() ^= 1;
+
+        let mut reader = EncryptedReader::new(encrypted.as_slice(), &KEY);
+        let error = reader.read_u8().await.unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn replayed_record_is_rejected() {
+        let record = encrypt(b"hello").await;
+        let replayed = [record.as_slice(), record.as_slice()].concat();
+
+        let mut reader = EncryptedReader::new(replayed.as_slice(), &KEY);
+        let mut hello = [0; 5];
+        reader.read_exact(&mut hello).await.unwrap();
+        let error = reader.read_u8().await.unwrap_err();
+
+        assert_eq!(&hello, b"hello");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn truncated_record_is_an_unexpected_eof() {
+        let encrypted = encrypt(b"hello").await;
+
+        let mut reader = EncryptedReader::new(&encrypted[..encrypted.len() - 1], &KEY);
+        let error = reader.read_u8().await.unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
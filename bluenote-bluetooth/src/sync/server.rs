@@ -1,129 +1,218 @@
+#[cfg(windows)]
+use crate::winrt::rfcomm::RfcommServer;
 use crate::{
-    sync::{async_reader::AsyncReader, async_writer::AsyncWriter},
-    NonBlockingThreadsafeFunctionWithReturn, Result, RUNTIME, UUID_BLUENOTE_RFCOMM,
+    error::Error,
+    sync::{
+        auth::{authenticate, PairingKey, Role},
+        frame::{ResponseFormat, ResponseFrame},
+        handshake::{exchange_version, Capabilities},
+        heartbeat::{idle_timeout, receive_within},
+        read_response_id, read_uuid,
+        resume::{ResumableSync, RESUMABLE_SYNCS},
+        secure::{EncryptedReader, EncryptedWriter},
+        session::{Session, SessionState, SESSIONS},
+        tcp,
+        transport::Transport,
+        Request,
+    },
+    NonBlockingThreadsafeFunctionWithReturn, Result, RUNTIME,
 };
 use async_trait::async_trait;
-use std::{sync::Mutex, time::Duration};
+use futures::{stream::FuturesUnordered, StreamExt};
+use napi::threadsafe_function::ThreadsafeFunction;
+use std::{collections::HashMap, sync::Mutex, time::Duration};
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    task::LocalSet,
-};
-use windows::{
-    core::GUID,
-    Devices::Bluetooth::Rfcomm::{RfcommServiceId, RfcommServiceProvider},
-    Foundation::TypedEventHandler,
-    Networking::Sockets::{
-        SocketProtectionLevel, StreamSocketListener,
-        StreamSocketListenerConnectionReceivedEventArgs,
-    },
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::{mpsc, oneshot},
+    task::{JoinHandle, LocalSet},
 };
 
 static SERVER_STATE: Mutex<Option<SyncServerState>> = Mutex::new(None);
+
+/// 同期時刻の保存 (`update_synced_at`) のタイムアウトの既定値 (ミリ秒)
+/// クライアントは、同期の成功を送った後、これより長く ACK を待つ
+pub const UPDATE_SYNCED_AT_TIMEOUT_MS: u32 = 10_000;
+/// JavaScript のコールバックの呼び出し
+/// 第 2 引数はタイムアウトの既定値 (ミリ秒)、`set_sync_callback_timeouts` で変更できる
 pub static SYNC_SERVICE: SyncServiceImpl = SyncServiceImpl {
-    on_sync_requested: NonBlockingThreadsafeFunctionWithReturn::new(),
-    on_now_requested: NonBlockingThreadsafeFunctionWithReturn::new(),
-    on_thread_updates_requested: NonBlockingThreadsafeFunctionWithReturn::new(),
-    on_all_notes_in_thread_requested: NonBlockingThreadsafeFunctionWithReturn::new(),
-    on_all_notes_in_tree_requested: NonBlockingThreadsafeFunctionWithReturn::new(),
-    on_note_updates_in_thread_requested: NonBlockingThreadsafeFunctionWithReturn::new(),
-    on_note_updates_in_tree_requested: NonBlockingThreadsafeFunctionWithReturn::new(),
-    on_update_synced_at_requested: NonBlockingThreadsafeFunctionWithReturn::new(),
-    on_my_uuid_requested: NonBlockingThreadsafeFunctionWithReturn::new(),
+    on_sync_requested: NonBlockingThreadsafeFunctionWithReturn::new("sync_requested", 5_000),
+    on_now_requested: NonBlockingThreadsafeFunctionWithReturn::new("now", 5_000),
+    on_thread_updates_requested: NonBlockingThreadsafeFunctionWithReturn::new(
+        "thread_updates",
+        10_000,
+    ),
+    on_all_notes_in_thread_requested: NonBlockingThreadsafeFunctionWithReturn::new(
+        "all_notes_in_thread",
+        30_000,
+    ),
+    on_all_notes_in_tree_requested: NonBlockingThreadsafeFunctionWithReturn::new(
+        "all_notes_in_tree",
+        30_000,
+    ),
+    on_note_updates_in_thread_requested: NonBlockingThreadsafeFunctionWithReturn::new(
+        "note_updates_in_thread",
+        10_000,
+    ),
+    on_note_updates_in_tree_requested: NonBlockingThreadsafeFunctionWithReturn::new(
+        "note_updates_in_tree",
+        10_000,
+    ),
+    on_update_synced_at_requested: NonBlockingThreadsafeFunctionWithReturn::new(
+        "update_synced_at",
+        UPDATE_SYNCED_AT_TIMEOUT_MS,
+    ),
+    on_my_uuid_requested: NonBlockingThreadsafeFunctionWithReturn::new("my_uuid", 5_000),
 };
 
+/// JavaScript のコールバックの呼び出しが、結果を待たずに中断されたことを知らせるコールバック
+/// 引数は (コールバックの名前, 呼び出しの ID)
+pub static ON_REQUEST_CANCELLED: Mutex<Option<ThreadsafeFunction<(&'static str, u32)>>> =
+    Mutex::new(None);
+
 struct SyncServerState {
-    #[allow(dead_code)] // drop してサーバが落ちると困るのでリスナのインスタンスを保持しておく
-    listener: StreamSocketListener,
-    provider: RfcommServiceProvider,
+    /// RFCOMM の接続を受け付けるサーバ
+    #[cfg(windows)]
+    rfcomm: RfcommServer,
+    /// TCP の接続を受け付けるタスク
+    tcp_acceptor: Option<JoinHandle<()>>,
 }
 
 fn check_server_state() -> Result<()> {
     let state = SERVER_STATE.lock().unwrap();
 
     match *state {
-        Some(_) => Err(crate::error::Error::SyncError(format!(
-            "Sync server already started"
-        ))),
+        Some(_) => Err(Error::Other("Sync server already started".to_owned())),
         None => Ok(()),
     }
 }
 
-pub async fn start() -> Result<()> {
+/// 同期サーバを起動する
+/// `tcp_address` を指定した場合は、RFCOMM に加えて TCP でも接続を受け付ける
+pub async fn start(tcp_address: Option<&str>) -> Result<()> {
     check_server_state()?;
 
-    let rfcomm_service_id = RfcommServiceId::FromUuid(GUID::from(UUID_BLUENOTE_RFCOMM))?;
-    let provider = RfcommServiceProvider::CreateAsync(&rfcomm_service_id)?.await?;
-    let listener = StreamSocketListener::new()?;
+    let tcp_listener = match tcp_address {
+        Some(address) => Some(tcp::listen(address).await?),
+        None => None,
+    };
 
-    listener.ConnectionReceived(&TypedEventHandler::new(|s, e| on_received(s, e)))?;
-    listener
-        .BindServiceNameWithProtectionLevelAsync(
-            &provider.ServiceId()?.AsString()?,
-            SocketProtectionLevel::BluetoothEncryptionWithAuthentication, /* Androidの設定と合わせる */
-        )?
-        .await?;
+    #[cfg(windows)]
+    let rfcomm = RfcommServer::start(spawn_serve).await?;
 
-    provider.StartAdvertisingWithRadioDiscoverability(&listener, true)?; // 必要
+    let tcp_acceptor = tcp_listener.map(|listener| RUNTIME.spawn(accept_tcp(listener)));
 
     let mut state = SERVER_STATE.lock().unwrap();
-    *state = Some(SyncServerState { listener, provider });
+    *state = Some(SyncServerState {
+        #[cfg(windows)]
+        rfcomm,
+        tcp_acceptor,
+    });
 
     Ok(())
 }
 
-pub fn stop() -> Result<()> {
-    let mut state = SERVER_STATE.lock().unwrap();
+/// 停止するときに、動いているセッションが終わるのを待つ時間
+/// 過ぎたら切断する
+const STOP_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 同期サーバを停止する
+/// 新しい接続の受け付けを止めた上で、`force` が `false` なら動いているセッションが終わるのを
+/// `STOP_DRAIN_TIMEOUT` まで待ち (過ぎたら切断する)、`true` ならセッションを切断してから終了を待つ
+pub async fn stop(force: bool) -> Result<()> {
+    let state = SERVER_STATE.lock().unwrap().take();
+
+    if let Some(state) = state {
+        if let Some(tcp_acceptor) = state.tcp_acceptor {
+            tcp_acceptor.abort();
+        }
+
+        #[cfg(windows)]
+        state.rfcomm.stop()?;
+    }
 
-    if let Some(state) = state.take() {
-        state.provider.StopAdvertising()?;
+    if force {
+        SESSIONS.close_all();
+        SESSIONS.wait_drained().await;
+    } else {
+        SESSIONS.drain(STOP_DRAIN_TIMEOUT).await;
     }
 
     Ok(())
 }
 
-fn on_received(
-    _: &Option<StreamSocketListener>,
-    e: &Option<StreamSocketListenerConnectionReceivedEventArgs>,
-) -> windows::core::Result<()> {
-    let socket = e.as_ref().unwrap().Socket()?;
+/// TCP の接続を受け付け続ける
+async fn accept_tcp(listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, address)) => {
+                println!("Connected to: {}", address);
+
+                spawn_serve(stream);
+            }
+            Err(e) => {
+                println!("Warning: failed to accept connection: {}", e);
+            }
+        }
+    }
+}
+
+/// 接続ごとにスレッドを立てて、同期サーバを動かす
+/// セッションはスレッドが終わるまで `SESSIONS` に登録される
+fn spawn_serve<T>(transport: T)
+where
+    T: Transport,
+{
+    let (session, close_requested) = SESSIONS.register(transport.peer_name());
 
     std::thread::spawn(move || {
         let local = LocalSet::new();
 
         local.spawn_local(async move {
-            let result = async {
-                let mut reader = AsyncReader::new(&socket.InputStream()?)?;
-                let mut writer = AsyncWriter::new(&socket.OutputStream()?)?;
-
-                serve(&mut reader, &mut writer, &SYNC_SERVICE).await
-            }
-            .await;
+            let result = tokio::select! {
+                result = serve_transport(
+                    transport,
+                    &SYNC_SERVICE,
+                    &session,
+                    Capabilities::ALL,
+                ) => result,
+                // 通信路ごと drop して切断する
+                _ = close_requested => Err(Error::Other(
+                    "Session closed by the server".to_owned(),
+                )),
+            };
 
             match result {
                 Ok(_) => {
-                    println!("Connection closed.");
+                    println!("Connection closed. (session {})", session.id());
                 }
                 Err(e) => {
-                    println!("Sync failed: {}", e);
+                    println!("Sync failed: {} (session {})", e, session.id());
                 }
             };
         });
 
         RUNTIME.block_on(local);
     });
-
-    Ok(())
 }
 
 /// JavaScript との通信の抽象化
 #[async_trait(?Send)]
-trait SyncService {
+pub trait SyncService {
     /// デバイスに対する同期を許可しているか
     async fn is_sync_allowed(&self, uuid: &str) -> Result<bool>;
 
     /// 自身のデバイス ID を取得
     async fn get_my_uuid(&self) -> Result<String>;
 
+    /// 信頼を取り消したデバイスか
+    /// 取り消したデバイスには、同期の許可を求めずに同期を拒否する
+    async fn is_revoked(&self, uuid: &str) -> Result<bool>;
+
+    /// デバイスとペアリングしたときに共有した鍵を取得
+    /// 鍵を共有していない (以前のバージョンでペアリングした) デバイスなら `None`
+    async fn get_pairing_key(&self, uuid: &str) -> Result<Option<PairingKey>>;
+
     /// 現在時刻を取得
     async fn now(&self) -> Result<String>;
 
@@ -160,47 +249,65 @@ trait SyncService {
     async fn update_synced_at(&self, uuid: &str, updated_end: &str) -> Result<()>;
 }
 
-/// ストリームから UUID 文字列を読み取る
-async fn read_uuid<R>(reader: &mut R) -> tokio::io::Result<String>
+/// レスポンスのフレームをすべて送信する
+/// 分割したレスポンスのフレームの間に、ほかのレスポンスのフレームは挟まない
+async fn write_response<W>(
+    frames: Vec<ResponseFrame>,
+    format: ResponseFormat,
+    writer: &mut W,
+) -> Result<()>
 where
-    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
 {
-    let mut buffer = vec![0u8; 36];
-
-    reader.read_exact(&mut buffer[..]).await?;
-
-    match String::from_utf8(buffer) {
-        Ok(v) => Ok(v),
-        Err(e) => Err(tokio::io::Error::new(tokio::io::ErrorKind::Interrupted, e)),
+    for frame in &frames {
+        format.write_frame(frame, writer).await?;
     }
+
+    Ok(())
 }
 
-/// データを送信して flush する
-/// 先頭に 4 バイト、リトルエンディアンでデータのサイズを書き込み、以降データを書き込む
-async fn write_data_and_flush<W>(
-    request_uuid: &String,
-    writer: &mut W,
-    data: &[u8],
-) -> tokio::io::Result<()>
+/// 通信路の上で同期サーバを動かす
+/// `capabilities` はこちらが使ってよい機能 (実際に使うのは相手も対応しているものだけ)
+pub async fn serve_transport<T, S>(
+    transport: T,
+    sync_service: &S,
+    session: &Session,
+    capabilities: Capabilities,
+) -> Result<()>
 where
-    W: AsyncWrite + Unpin,
+    T: Transport,
+    S: SyncService,
 {
-    let request_uuid = request_uuid.as_bytes();
-    writer.write_all(request_uuid).await?;
-    writer.write_u32_le(data.len() as u32).await?;
-    writer.write_all(data).await?;
-    writer.flush().await?;
-
-    Ok(())
+    let (mut reader, mut writer) = transport.into_split();
+
+    serve(
+        &mut reader,
+        &mut writer,
+        sync_service,
+        session,
+        capabilities,
+    )
+    .await
 }
 
 /// 同期サーバの実装本体
-async fn serve<R, W, S>(reader: &mut R, writer: &mut W, sync_service: &S) -> Result<()>
+async fn serve<R, W, S>(
+    reader: &mut R,
+    writer: &mut W,
+    sync_service: &S,
+    session: &Session,
+    capabilities: Capabilities,
+) -> Result<()>
 where
     R: AsyncRead + Unpin,
     W: AsyncWrite + Unpin,
     S: SyncService,
 {
+    // 0. プロトコルのバージョンの交換
+    // 異なる場合はここで切断する
+    let advertised = exchange_version(reader, writer, capabilities).await?;
+    let capabilities = advertised.negotiated();
+
     let my_uuid = sync_service.get_my_uuid().await?;
 
     // 1. UUID の交換
@@ -208,7 +315,7 @@ where
         async {
             // 自身のデバイスIDを送信
             let my_uuid = my_uuid.as_bytes();
-            writer.write_all(&my_uuid).await?;
+            writer.write_all(my_uuid).await?;
             writer.flush().await?;
 
             Ok(())
@@ -225,15 +332,95 @@ where
     let uuid = uuid?;
 
     println!("UUID: {}", uuid);
+    session.set_uuid(&uuid);
+
+    // 2. ペアリングで共有した鍵で、相手を認証する
+    // 鍵を共有している相手なのに認証に対応していなければ、なりすましとみなす
+    let pairing_key = sync_service.get_pairing_key(&uuid).await?;
+    let authenticated = if capabilities.contains(Capabilities::AUTH) {
+        authenticate(
+            reader,
+            writer,
+            Role::Server,
+            pairing_key.as_ref(),
+            advertised,
+            &my_uuid,
+            &uuid,
+        )
+        .await
+    } else if pairing_key.is_some() {
+        Err(Error::AuthenticationFailed(uuid.clone()))
+    } else {
+        Ok(None)
+    };
+
+    let session_keys = match authenticated {
+        Ok(session_keys) => session_keys,
+        Err(e) => {
+            writer.write_u8(crate::sync::SYNC_REJECTED).await?;
+            writer.flush().await?;
+
+            return Err(e);
+        }
+    };
+
+    // 鍵を導出できた場合は、ここから先の通信をすべて暗号化する
+    match session_keys {
+        Some(keys) => {
+            println!("Encrypting the sync session with {}", uuid);
+
+            let mut reader = EncryptedReader::new(reader, &keys.receive);
+            let mut writer = EncryptedWriter::new(writer, &keys.send);
+
+            serve_authenticated(
+                &mut reader,
+                &mut writer,
+                sync_service,
+                session,
+                capabilities,
+                &uuid,
+            )
+            .await
+        }
+        None => {
+            serve_authenticated(reader, writer, sync_service, session, capabilities, &uuid).await
+        }
+    }
+}
 
-    // 2. 同期設定を取得
+/// 認証の済んだ相手に、同期を許可してデータを返す
+async fn serve_authenticated<R, W, S>(
+    reader: &mut R,
+    writer: &mut W,
+    sync_service: &S,
+    session: &Session,
+    capabilities: Capabilities,
+    uuid: &str,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    S: SyncService,
+{
+    let format = ResponseFormat::negotiate(capabilities);
+
+    // 3. 同期設定を取得
+
+    // 信頼を取り消したデバイスは、アプリに問い合わせずに拒否する
+    // 認証の前に拒否すると、相手はチャレンジの受信中に切断されたとしかわからないので、認証の後のこの位置で拒否する
+    if sync_service.is_revoked(uuid).await? {
+        writer.write_u8(crate::sync::SYNC_REJECTED).await?;
+        writer.flush().await?;
+
+        return Err(Error::DeviceRevoked(uuid.to_owned()));
+    }
 
     // 登録されてないデバイス、もしくは同期がオフになっている相手なら同期を拒否
-    if !sync_service.is_sync_allowed(&uuid).await? {
+    if !sync_service.is_sync_allowed(uuid).await? {
         writer.write_u8(crate::sync::SYNC_REJECTED).await?;
         writer.flush().await?;
 
-        return Err(crate::error::Error::SyncError(format!("Sync rejected")));
+        return Err(Error::SyncRejected(uuid.to_owned()));
     } else {
         // 許可
         writer.write_u8(crate::sync::SYNC_ALLOWED).await?;
@@ -241,74 +428,290 @@ where
     }
 
     // 最終同期時刻、現在時刻を取得し、返却するデータの範囲を決定
+    // 接続が切れた同期の再開なら、最初の接続と同じ範囲を使う
+
+    let resumable = if capabilities.contains(Capabilities::RESUME) {
+        Some(exchange_sync_id(reader, writer, sync_service, uuid).await?)
+    } else {
+        None
+    };
+    let updated_end = match &resumable {
+        Some(resumable) => resumable.updated_end().to_owned(),
+        None => sync_service.now().await?,
+    };
 
-    let updated_end = sync_service.now().await?;
+    session.set_state(SessionState::Serving);
 
     // 4. 相手のリクエストに応じてデータを返す
-    loop {
-        let request_id = reader.read_u8().await?;
+    // リクエストの受信と、データの取得・返却を並行して行う (docs/protocol_v2.md)
+    let (tx_request, rx_request) = mpsc::unbounded_channel();
+
+    futures::try_join!(
+        receive_requests(reader, tx_request, idle_timeout(capabilities)),
+        respond_to_requests(
+            writer,
+            rx_request,
+            sync_service,
+            session,
+            format,
+            uuid,
+            &updated_end,
+            resumable.as_ref()
+        ),
+    )?;
+
+    // 同期の成功・失敗を受信して終わったので、再開できないようにする
+    // (途中で切れた場合は drop され、しばらく再開を待つ)
+    if let Some(resumable) = resumable {
+        resumable.finish();
+    }
 
-        match request_id {
-            // スレッドの更新を送信
-            crate::sync::REQUEST_THREAD_UPDATES => {
-                let updated = sync_service.get_thread_updates(&uuid, &updated_end).await?;
-                let request_uuid = " ".repeat(36);
+    Ok(())
+}
 
-                write_data_and_flush(&request_uuid, writer, updated.as_bytes()).await?;
-            }
-            // スレッド内のメモを送信
-            crate::sync::REQUEST_ALL_NOTES_IN_THREAD => {
-                let thread_id = read_uuid(reader).await?;
-                let notes = sync_service.get_all_notes_in_thread(&thread_id).await?;
+/// 相手から同期 ID を受信し、再開できる同期ならそれを、そうでなければ新しい同期を始める
+/// どちらの場合も、これから使う同期 ID を相手に送る
+async fn exchange_sync_id<R, W, S>(
+    reader: &mut R,
+    writer: &mut W,
+    sync_service: &S,
+    uuid: &str,
+) -> Result<ResumableSync>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    S: SyncService,
+{
+    // 新しい同期を始める場合は 36 バイトの空白が送られる
+    let requested = read_response_id(reader).await?;
 
-                write_data_and_flush(&thread_id, writer, notes.as_bytes()).await?;
-            }
-            crate::sync::REQUEST_ALL_NOTES_IN_TREE => {
-                let note_id = read_uuid(reader).await?;
-                let notes = sync_service.get_all_notes_in_tree(&note_id).await?;
+    let resumable = match RESUMABLE_SYNCS.resume(&requested, uuid) {
+        Some(resumable) => {
+            println!("Resuming sync: {}", resumable.id());
+            resumable
+        }
+        None => RESUMABLE_SYNCS.start(uuid, sync_service.now().await?),
+    };
 
-                write_data_and_flush(&note_id, writer, notes.as_bytes()).await?;
-            }
-            crate::sync::REQUEST_NOTE_UPDATES_IN_THREAD => {
-                let thread_id = read_uuid(reader).await?;
-                let notes = sync_service
-                    .get_note_updates_in_thread(&uuid, &thread_id, &updated_end)
-                    .await?;
+    writer.write_all(resumable.id().as_bytes()).await?;
+    writer.flush().await?;
 
-                write_data_and_flush(&thread_id, writer, notes.as_bytes()).await?;
-            }
-            crate::sync::REQUEST_NOTE_UPDATES_IN_TREE => {
-                let note_id = read_uuid(reader).await?;
-                let notes = sync_service
-                    .get_note_updates_in_tree(&uuid, &note_id, &updated_end)
-                    .await?;
+    Ok(resumable)
+}
 
-                write_data_and_flush(&note_id, writer, notes.as_bytes()).await?;
-            }
-            // 相手側で同期が正常に終了した
-            crate::sync::SYNC_SUCCESS => {
-                sync_service.update_synced_at(&uuid, &updated_end).await?;
+/// リクエストを受信し続ける
+/// 同期の終了 (成功・失敗) を受信したら終わる
+/// `idle_timeout` の間に何も受信しなければ、接続が切れたとみなす
+async fn receive_requests<R>(
+    reader: &mut R,
+    tx_request: mpsc::UnboundedSender<Request>,
+    idle_timeout: Duration,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        let request = receive_within(idle_timeout, Request::read(reader)).await?;
+        let is_finished = matches!(request, Request::SyncSuccess | Request::SyncFailed);
+
+        if tx_request.send(request).is_err() || is_finished {
+            return Ok(());
+        }
+    }
+}
+
+/// 受信したリクエストを並行して処理し、できあがった順にレスポンスを返す
+/// `resumable` (同期の再開に対応している場合) に、データを取得し終えたリクエストを記録する
+#[allow(clippy::too_many_arguments)]
+async fn respond_to_requests<W, S>(
+    writer: &mut W,
+    mut rx_request: mpsc::UnboundedReceiver<Request>,
+    sync_service: &S,
+    session: &Session,
+    format: ResponseFormat,
+    uuid: &str,
+    updated_end: &str,
+    resumable: Option<&ResumableSync>,
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    S: SyncService,
+{
+    let mut responses = FuturesUnordered::new();
+    // 処理中のリクエストを取り消すためのチャネル (レスポンスの ID ごと)
+    let mut cancellations: HashMap<String, Vec<oneshot::Sender<()>>> = HashMap::new();
 
-                // DB の更新が成功したことを示す ACK を返し
-                // 接続を終了
+    loop {
+        tokio::select! {
+            request = rx_request.recv() => match request {
+                // 相手側で同期が正常に終了した
+                Some(Request::SyncSuccess) => {
+                    session.set_state(SessionState::Finishing);
 
-                writer.write_u8(crate::sync::SYNC_SUCCESS).await?;
-                writer.flush().await?;
+                    // 処理中のリクエストのレスポンスをすべて返してから
+                    while let Some(response) = responses.next().await {
+                        write_response(response?, format, writer).await?;
+                    }
 
-                break;
-            }
-            // 相手側で同期が失敗した or EOF
-            crate::sync::SYNC_FAILED => {
-                // 接続を終了
-                break;
-            }
-            _ => {
-                println!("Unknown request: $requestId");
+                    sync_service.update_synced_at(uuid, updated_end).await?;
+
+                    // DB の更新が成功したことを示す ACK を返し
+                    // 接続を終了
+
+                    writer.write_u8(crate::sync::SYNC_SUCCESS).await?;
+                    writer.flush().await?;
+
+                    return Ok(());
+                }
+                // 相手側で同期が失敗した or EOF
+                Some(Request::SyncFailed) | None => {
+                    // 接続を終了
+                    return Ok(());
+                }
+                // 処理中のリクエストがあっても、すぐに応答する
+                Some(Request::Ping) if format.has_heartbeat() => {
+                    format.write_frame(&ResponseFrame::pong(), writer).await?;
+                }
+                Some(Request::Ping) => {
+                    return Err(Error::ProtocolViolation(
+                        "Ping without negotiating heartbeat".to_owned(),
+                    ));
+                }
+                // レスポンスを返し終えたリクエストの取り消しは無視する
+                Some(Request::Cancel { request_id }) if format.has_cancel() => {
+                    for cancel in cancellations.remove(&request_id).unwrap_or_default() {
+                        let _ = cancel.send(());
+                    }
+                }
+                Some(Request::Cancel { .. }) => {
+                    return Err(Error::ProtocolViolation(
+                        "Cancel without negotiating cancellation".to_owned(),
+                    ));
+                }
+                Some(request) => {
+                    let (cancel, cancelled) = oneshot::channel();
+
+                    // 処理が終わったリクエストの分を片付けてから登録する
+                    cancellations.retain(|_, cancels| {
+                        cancels.retain(|cancel| !cancel.is_closed());
+                        !cancels.is_empty()
+                    });
+                    cancellations
+                        .entry(request.response_id())
+                        .or_default()
+                        .push(cancel);
+
+                    responses.push(respond_to_request(
+                        request,
+                        cancelled,
+                        sync_service,
+                        format,
+                        uuid,
+                        updated_end,
+                        resumable,
+                    ));
+                }
+            },
+            Some(response) = responses.next() => {
+                write_response(response?, format, writer).await?;
             }
         }
     }
+}
 
-    Ok(())
+/// リクエストに応じたデータを取得し、送信するフレームに変換する
+/// 失敗した場合、相手が対応していればエラーのレスポンスを返す (対応していなければ切断する)
+/// `cancelled` を受信したら、データの取得を中断して `Error::Cancelled` のレスポンスを返す
+async fn respond_to_request<S>(
+    request: Request,
+    cancelled: oneshot::Receiver<()>,
+    sync_service: &S,
+    format: ResponseFormat,
+    uuid: &str,
+    updated_end: &str,
+    resumable: Option<&ResumableSync>,
+) -> Result<Vec<ResponseFrame>>
+where
+    S: SyncService,
+{
+    let request_id = request.response_id();
+    let result = tokio::select! {
+        result = fetch_data_once(&request, sync_service, uuid, updated_end, resumable) => result,
+        Ok(()) = cancelled => Err(Error::Cancelled),
+    }
+    .and_then(|data| format.to_frames(request_id.clone(), data));
+
+    match result {
+        Err(e) if format.has_error_frames() => {
+            println!("Request failed: {:?}: {}", request, e);
+            Ok(vec![format.error_frame(request_id, &e)])
+        }
+        result => result,
+    }
+}
+
+/// リクエストに応じたデータ (JSON) を取得し、`resumable` に記録する
+/// 再開した同期で、以前の接続でデータを取得し終えたリクエストなら、JavaScript に問い合わせずに同じデータを返す
+async fn fetch_data_once<S>(
+    request: &Request,
+    sync_service: &S,
+    uuid: &str,
+    updated_end: &str,
+    resumable: Option<&ResumableSync>,
+) -> Result<String>
+where
+    S: SyncService,
+{
+    if let Some(data) = resumable.and_then(|resumable| resumable.completed(request)) {
+        println!("Skipping completed request: {:?}", request);
+        return Ok(data);
+    }
+
+    let data = fetch_data(request, sync_service, uuid, updated_end).await?;
+
+    if let Some(resumable) = resumable {
+        resumable.complete(request.clone(), data.clone());
+    }
+
+    Ok(data)
+}
+
+/// リクエストに応じたデータ (JSON) を JavaScript から取得する
+async fn fetch_data<S>(
+    request: &Request,
+    sync_service: &S,
+    uuid: &str,
+    updated_end: &str,
+) -> Result<String>
+where
+    S: SyncService,
+{
+    let data = match request {
+        // スレッドの更新を送信
+        Request::ThreadUpdates => sync_service.get_thread_updates(uuid, updated_end).await?,
+        // スレッド内のメモを送信
+        Request::AllNotesInThread { thread_id } => {
+            sync_service.get_all_notes_in_thread(thread_id).await?
+        }
+        Request::AllNotesInTree { parent_id } => {
+            sync_service.get_all_notes_in_tree(parent_id).await?
+        }
+        Request::NoteUpdatesInThread { thread_id } => {
+            sync_service
+                .get_note_updates_in_thread(uuid, thread_id, updated_end)
+                .await?
+        }
+        Request::NoteUpdatesInTree { parent_id } => {
+            sync_service
+                .get_note_updates_in_tree(uuid, parent_id, updated_end)
+                .await?
+        }
+        Request::SyncSuccess | Request::SyncFailed | Request::Ping | Request::Cancel { .. } => {
+            unreachable!()
+        }
+    };
+
+    Ok(data)
 }
 
 /// JavaScript との通信部分の実装
@@ -365,8 +768,10 @@ pub struct RequestParamUpdateSyncedAt {
     pub updated_end: String,
 }
 
-impl SyncServiceImpl {
-    async fn is_sync_allowed_impl(&self, uuid: &str) -> Result<bool> {
+// タイムアウトは各コールバックに設定されている (`SYNC_SERVICE` を参照)
+#[async_trait(?Send)]
+impl SyncService for SyncServiceImpl {
+    async fn is_sync_allowed(&self, uuid: &str) -> Result<bool> {
         self.on_sync_requested
             .call(RequestParamSyncPermission {
                 uuid: uuid.to_owned(),
@@ -374,11 +779,11 @@ impl SyncServiceImpl {
             .await
     }
 
-    async fn now_impl(&self) -> Result<String> {
+    async fn now(&self) -> Result<String> {
         self.on_now_requested.call(()).await
     }
 
-    async fn get_thread_updates_impl(&self, uuid: &str, updated_end: &str) -> Result<String> {
+    async fn get_thread_updates(&self, uuid: &str, updated_end: &str) -> Result<String> {
         self.on_thread_updates_requested
             .call(RequestParamThreadUpdates {
                 uuid: uuid.to_owned(),
@@ -386,25 +791,6 @@ impl SyncServiceImpl {
             })
             .await
     }
-}
-
-#[async_trait(?Send)]
-impl SyncService for SyncServiceImpl {
-    async fn is_sync_allowed(&self, uuid: &str) -> Result<bool> {
-        tokio::time::timeout(Duration::from_secs(5), self.is_sync_allowed_impl(uuid)).await?
-    }
-
-    async fn now(&self) -> Result<String> {
-        tokio::time::timeout(Duration::from_secs(5), self.now_impl()).await?
-    }
-
-    async fn get_thread_updates(&self, uuid: &str, updated_end: &str) -> Result<String> {
-        tokio::time::timeout(
-            Duration::from_secs(10),
-            self.get_thread_updates_impl(uuid, updated_end),
-        )
-        .await?
-    }
 
     async fn get_all_notes_in_thread(&self, thread_id: &str) -> Result<String> {
         self.on_all_notes_in_thread_requested
@@ -453,18 +839,26 @@ impl SyncService for SyncServiceImpl {
     }
 
     async fn update_synced_at(&self, uuid: &str, updated_end: &str) -> Result<()> {
-        tokio::time::timeout(
-            Duration::from_secs(10),
-            self.on_update_synced_at_requested
-                .call(RequestParamUpdateSyncedAt {
-                    uuid: uuid.to_owned(),
-                    updated_end: updated_end.to_owned(),
-                }),
-        )
-        .await?
+        self.on_update_synced_at_requested
+            .call(RequestParamUpdateSyncedAt {
+                uuid: uuid.to_owned(),
+                updated_end: updated_end.to_owned(),
+            })
+            .await
     }
 
     async fn get_my_uuid(&self) -> Result<String> {
-        tokio::time::timeout(Duration::from_secs(5), self.on_my_uuid_requested.call(())).await?
+        self.on_my_uuid_requested.call(()).await
+    }
+
+    async fn is_revoked(&self, uuid: &str) -> Result<bool> {
+        Ok(crate::trust::is_revoked(uuid))
+    }
+
+    async fn get_pairing_key(&self, uuid: &str) -> Result<Option<PairingKey>> {
+        Ok(crate::trust::pairing_key(uuid))
     }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,1145 @@
+//! クライアントと `serve` をメモリ上のパイプでつないだ、プロトコル全体のテスト
+
+use std::{
+    cell::{Cell, RefCell},
+    future::Future,
+    time::Duration,
+};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+use super::*;
+use crate::sync::{
+    abort::AbortWatcher,
+    auth::PairingKeys,
+    client::{ClientSession, SyncClient},
+    handshake::PROTOCOL_VERSION,
+    heartbeat::{IDLE_TIMEOUT, PING_INTERVAL},
+    SYNC_ALLOWED,
+};
+
+const SERVER_UUID: &str = "11111111-1111-4111-8111-111111111111";
+const CLIENT_UUID: &str = "22222222-2222-4222-8222-222222222222";
+const THREAD_ID: &str = "33333333-3333-4333-8333-333333333333";
+const NOTE_ID: &str = "44444444-4444-4444-8444-444444444444";
+const NOW: &str = "2023-01-01T00:00:00.000Z";
+/// 2 回目以降に `now` が呼ばれたときに返す時刻
+const LATER: &str = "2023-01-01T00:05:00.000Z";
+
+/// `FakeSyncService` が返す JSON
+fn response(parts: &[&str]) -> String {
+    serde_json::to_string(parts).unwrap()
+}
+
+/// JavaScript の代わりに決まった値を返す `SyncService`
+struct FakeSyncService {
+    allowed: bool,
+    /// スレッドの更新分を返すまでの時間
+    thread_updates_delay: Duration,
+    /// スレッドの更新分の取得を (タイムアウトで) 失敗させる
+    thread_updates_fail: bool,
+    /// スレッド内のメモとして返す本文 (圧縮されるような大きいデータを返すため)
+    note_content: Option<String>,
+    /// 同期時刻を保存するまでの時間
+    update_synced_at_delay: Duration,
+    /// `update_synced_at` に渡された (UUID, 時刻)
+    synced_at: RefCell<Option<(String, String)>>,
+    /// `now` が呼ばれた回数
+    now_calls: Cell<usize>,
+    /// `get_thread_updates` が呼ばれた回数
+    thread_updates_calls: Cell<usize>,
+    /// クライアントとペアリングしたときに共有した鍵
+    pairing_key: Option<PairingKey>,
+    /// クライアントの信頼を取り消した
+    revoked: bool,
+    /// `is_sync_allowed` が呼ばれた回数
+    sync_allowed_calls: Cell<usize>,
+}
+
+impl FakeSyncService {
+    fn new() -> Self {
+        Self {
+            allowed: true,
+            thread_updates_delay: Duration::ZERO,
+            thread_updates_fail: false,
+            note_content: None,
+            update_synced_at_delay: Duration::ZERO,
+            synced_at: RefCell::new(None),
+            now_calls: Cell::new(0),
+            thread_updates_calls: Cell::new(0),
+            pairing_key: None,
+            revoked: false,
+            sync_allowed_calls: Cell::new(0),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl SyncService for FakeSyncService {
+    async fn is_sync_allowed(&self, uuid: &str) -> Result<bool> {
+        self.sync_allowed_calls
+            .set(self.sync_allowed_calls.get() + 1);
+        Ok(self.allowed && uuid == CLIENT_UUID)
+    }
+
+    async fn is_revoked(&self, uuid: &str) -> Result<bool> {
+        Ok(self.revoked && uuid == CLIENT_UUID)
+    }
+
+    async fn get_my_uuid(&self) -> Result<String> {
+        Ok(SERVER_UUID.to_owned())
+    }
+
+    async fn get_pairing_key(&self, uuid: &str) -> Result<Option<PairingKey>> {
+        Ok(self.pairing_key.clone().filter(|_| uuid == CLIENT_UUID))
+    }
+
+    async fn now(&self) -> Result<String> {
+        let calls = self.now_calls.get();
+        self.now_calls.set(calls + 1);
+
+        Ok(if calls == 0 { NOW } else { LATER }.to_owned())
+    }
+
+    async fn get_thread_updates(&self, uuid: &str, updated_end: &str) -> Result<String> {
+        self.thread_updates_calls
+            .set(self.thread_updates_calls.get() + 1);
+        tokio::time::sleep(self.thread_updates_delay).await;
+
+        if self.thread_updates_fail {
+            return Err(Error::Timeout {
+                phase: "thread_updates",
+            });
+        }
+        Ok(response(&["thread_updates", uuid, updated_end]))
+    }
+
+    async fn get_all_notes_in_thread(&self, thread_id: &str) -> Result<String> {
+        match &self.note_content {
+            Some(content) => Ok(response(&["all_notes_in_thread", thread_id, content])),
+            None => Ok(response(&["all_notes_in_thread", thread_id])),
+        }
+    }
+
+    async fn get_all_notes_in_tree(&self, parent_id: &str) -> Result<String> {
+        Ok(response(&["all_notes_in_tree", parent_id]))
+    }
+
+    async fn get_note_updates_in_thread(
+        &self,
+        _uuid: &str,
+        thread_id: &str,
+        _updated_end: &str,
+    ) -> Result<String> {
+        Ok(response(&["note_updates_in_thread", thread_id]))
+    }
+
+    async fn get_note_updates_in_tree(
+        &self,
+        _uuid: &str,
+        parent_id: &str,
+        _updated_end: &str,
+    ) -> Result<String> {
+        Ok(response(&["note_updates_in_tree", parent_id]))
+    }
+
+    async fn update_synced_at(&self, uuid: &str, updated_end: &str) -> Result<()> {
+        tokio::time::sleep(self.update_synced_at_delay).await;
+        *self.synced_at.borrow_mut() = Some((uuid.to_owned(), updated_end.to_owned()));
+        Ok(())
+    }
+}
+
+/// サーバとクライアントをメモリ上のパイプでつないで、両方を最後まで動かす
+/// 戻り値は (サーバの結果, クライアントの結果)
+async fn run<F, Fut, T>(service: &FakeSyncService, client: F) -> (Result<()>, T)
+where
+    F: FnOnce(DuplexStream) -> Fut,
+    Fut: Future<Output = T>,
+{
+    let (client_stream, server_stream) = tokio::io::duplex(4096);
+    let (session, _close_requested) = SESSIONS.register(server_stream.peer_name());
+
+    tokio::join!(
+        serve_transport(server_stream, service, &session, Capabilities::ALL),
+        client(client_stream)
+    )
+}
+
+async fn begin(stream: DuplexStream) -> Result<ClientSession> {
+    begin_with(stream, Capabilities::ALL).await
+}
+
+async fn begin_with(stream: DuplexStream, capabilities: Capabilities) -> Result<ClientSession> {
+    begin_paired(stream, capabilities, None).await
+}
+
+/// `pairing_key` をサーバとペアリングしたときの鍵として、同期を開始する
+async fn begin_paired(
+    stream: DuplexStream,
+    capabilities: Capabilities,
+    pairing_key: Option<&PairingKey>,
+) -> Result<ClientSession> {
+    let pairing_keys: PairingKeys = pairing_key
+        .map(|key| (SERVER_UUID.to_owned(), key.clone()))
+        .into_iter()
+        .collect();
+
+    ClientSession::begin(
+        stream,
+        CLIENT_UUID,
+        &[SERVER_UUID.to_owned()],
+        &pairing_keys,
+        capabilities,
+        None,
+    )
+    .await
+}
+
+fn pairing_key(byte: &str) -> PairingKey {
+    PairingKey::from_hex(&byte.repeat(32)).unwrap()
+}
+
+#[tokio::test]
+async fn sync_succeeds_and_saves_synced_at() {
+    let service = FakeSyncService::new();
+
+    let (server_result, client_result) = run(&service, |stream| async move {
+        let session = begin(stream).await?;
+
+        let thread_updates = session.request(&Request::ThreadUpdates).await?;
+        let notes = session
+            .request(&Request::AllNotesInThread {
+                thread_id: THREAD_ID.to_owned(),
+            })
+            .await?;
+
+        session.end(true).await?;
+
+        Ok::<_, Error>((thread_updates, notes))
+    })
+    .await;
+
+    server_result.unwrap();
+    let (thread_updates, notes) = client_result.unwrap();
+
+    assert_eq!(
+        thread_updates,
+        response(&["thread_updates", CLIENT_UUID, NOW])
+    );
+    assert_eq!(notes, response(&["all_notes_in_thread", THREAD_ID]));
+    assert_eq!(
+        *service.synced_at.borrow(),
+        Some((CLIENT_UUID.to_owned(), NOW.to_owned()))
+    );
+}
+
+#[tokio::test]
+async fn every_request_type_gets_its_response() {
+    let service = FakeSyncService::new();
+
+    let (server_result, client_result) = run(&service, |stream| async move {
+        let session = begin(stream).await?;
+
+        let requests = [
+            Request::AllNotesInTree {
+                parent_id: NOTE_ID.to_owned(),
+            },
+            Request::NoteUpdatesInThread {
+                thread_id: THREAD_ID.to_owned(),
+            },
+            Request::NoteUpdatesInTree {
+                parent_id: NOTE_ID.to_owned(),
+            },
+        ];
+        let mut responses = Vec::new();
+
+        for request in &requests {
+            responses.push(session.request(request).await?);
+        }
+
+        session.end(true).await?;
+
+        Ok::<_, Error>(responses)
+    })
+    .await;
+
+    server_result.unwrap();
+    assert_eq!(
+        client_result.unwrap(),
+        vec![
+            response(&["all_notes_in_tree", NOTE_ID]),
+            response(&["note_updates_in_thread", THREAD_ID]),
+            response(&["note_updates_in_tree", NOTE_ID]),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn responses_are_matched_when_returned_out_of_order() {
+    let service = FakeSyncService {
+        thread_updates_delay: Duration::from_millis(100),
+        ..FakeSyncService::new()
+    };
+
+    let (server_result, client_result) = run(&service, |stream| async move {
+        let session = begin(stream).await?;
+
+        let all_notes_in_thread = Request::AllNotesInThread {
+            thread_id: THREAD_ID.to_owned(),
+        };
+        let all_notes_in_tree = Request::AllNotesInTree {
+            parent_id: NOTE_ID.to_owned(),
+        };
+
+        // スレッドの更新分は遅れて返ってくる
+        let (thread_updates, notes_in_thread, notes_in_tree) = tokio::join!(
+            session.request(&Request::ThreadUpdates),
+            session.request(&all_notes_in_thread),
+            session.request(&all_notes_in_tree),
+        );
+
+        session.end(true).await?;
+
+        Ok::<_, Error>((thread_updates?, notes_in_thread?, notes_in_tree?))
+    })
+    .await;
+
+    server_result.unwrap();
+    let (thread_updates, notes_in_thread, notes_in_tree) = client_result.unwrap();
+
+    assert_eq!(
+        thread_updates,
+        response(&["thread_updates", CLIENT_UUID, NOW])
+    );
+    assert_eq!(
+        notes_in_thread,
+        response(&["all_notes_in_thread", THREAD_ID])
+    );
+    assert_eq!(notes_in_tree, response(&["all_notes_in_tree", NOTE_ID]));
+}
+
+#[tokio::test]
+async fn json_is_used_when_the_client_does_not_support_message_pack() {
+    let service = FakeSyncService::new();
+
+    let (server_result, client_result) = run(&service, |stream| async move {
+        let session = begin_with(stream, Capabilities::empty()).await?;
+
+        let thread_updates = session.request(&Request::ThreadUpdates).await?;
+        session.end(true).await?;
+
+        Ok::<_, Error>(thread_updates)
+    })
+    .await;
+
+    server_result.unwrap();
+    assert_eq!(
+        client_result.unwrap(),
+        response(&["thread_updates", CLIENT_UUID, NOW])
+    );
+}
+
+#[tokio::test]
+async fn large_responses_are_the_same_with_and_without_compression() {
+    let content = "# 買い物\n\n- 牛乳\n- 卵\n- パン\n".repeat(1000);
+    let service = FakeSyncService {
+        note_content: Some(content.clone()),
+        ..FakeSyncService::new()
+    };
+
+    for capabilities in [Capabilities::ALL, Capabilities::MESSAGE_PACK] {
+        let (server_result, client_result) = run(&service, |stream| async move {
+            let session = begin_with(stream, capabilities).await?;
+
+            let notes = session
+                .request(&Request::AllNotesInThread {
+                    thread_id: THREAD_ID.to_owned(),
+                })
+                .await?;
+            session.end(true).await?;
+
+            Ok::<_, Error>(notes)
+        })
+        .await;
+
+        server_result.unwrap();
+        assert_eq!(
+            client_result.unwrap(),
+            response(&["all_notes_in_thread", THREAD_ID, &content])
+        );
+    }
+}
+
+#[tokio::test]
+async fn large_responses_are_streamed_in_chunks_when_negotiated() {
+    let content = "# 買い物\n\n- 牛乳\n- 卵\n- パン\n".repeat(1000);
+    let service = FakeSyncService {
+        note_content: Some(content.clone()),
+        ..FakeSyncService::new()
+    };
+
+    for (capabilities, expected_chunks) in [
+        (Capabilities::ALL, 2),
+        (
+            Capabilities::MESSAGE_PACK.intersection(Capabilities::ALL),
+            1,
+        ),
+    ] {
+        let (server_result, client_result) = run(&service, |stream| async move {
+            let session = begin_with(stream, capabilities).await?;
+
+            let mut stream = session
+                .request_stream(&Request::AllNotesInThread {
+                    thread_id: THREAD_ID.to_owned(),
+                })
+                .await?;
+            let mut chunks = Vec::new();
+            while let Some(chunk) = stream.next().await? {
+                chunks.push(chunk);
+            }
+
+            session.end(true).await?;
+
+            Ok::<_, Error>(chunks)
+        })
+        .await;
+
+        server_result.unwrap();
+        let chunks = client_result.unwrap();
+
+        assert_eq!(chunks.len(), expected_chunks);
+        assert_eq!(
+            crate::sync::frame::concat_chunks(chunks).unwrap(),
+            response(&["all_notes_in_thread", THREAD_ID, &content])
+        );
+    }
+}
+
+#[tokio::test]
+async fn failed_request_gets_an_error_response_and_the_session_continues() {
+    let service = FakeSyncService {
+        thread_updates_fail: true,
+        ..FakeSyncService::new()
+    };
+
+    let (server_result, client_result) = run(&service, |stream| async move {
+        let session = begin(stream).await?;
+
+        let thread_updates = session.request(&Request::ThreadUpdates).await;
+        let notes = session
+            .request(&Request::AllNotesInThread {
+                thread_id: THREAD_ID.to_owned(),
+            })
+            .await?;
+
+        session.end(false).await?;
+
+        Ok::<_, Error>((thread_updates, notes))
+    })
+    .await;
+
+    server_result.unwrap();
+    let (thread_updates, notes) = client_result.unwrap();
+
+    assert!(matches!(
+        thread_updates,
+        Err(Error::RequestFailed { code, retryable: true, .. }) if code == "TIMEOUT"
+    ));
+    assert_eq!(notes, response(&["all_notes_in_thread", THREAD_ID]));
+}
+
+#[tokio::test]
+async fn failed_request_disconnects_without_error_frames() {
+    let service = FakeSyncService {
+        thread_updates_fail: true,
+        ..FakeSyncService::new()
+    };
+
+    let (server_result, client_result) = run(&service, |stream| async move {
+        let session = begin_with(stream, Capabilities::MESSAGE_PACK).await?;
+
+        session.request(&Request::ThreadUpdates).await
+    })
+    .await;
+
+    assert!(matches!(server_result, Err(Error::Timeout { .. })));
+    assert!(matches!(client_result, Err(Error::Disconnected)));
+}
+
+#[tokio::test]
+async fn failed_sync_does_not_save_synced_at() {
+    let service = FakeSyncService::new();
+
+    let (server_result, client_result) = run(&service, |stream| async move {
+        let session = begin(stream).await?;
+
+        session.request(&Request::ThreadUpdates).await?;
+        session.end(false).await
+    })
+    .await;
+
+    server_result.unwrap();
+    client_result.unwrap();
+    assert_eq!(*service.synced_at.borrow(), None);
+}
+
+#[tokio::test]
+async fn server_rejects_unknown_device() {
+    let service = FakeSyncService {
+        allowed: false,
+        ..FakeSyncService::new()
+    };
+
+    let (server_result, client_result) =
+        run(
+            &service,
+            |stream| async move { begin(stream).await.map(|_| ()) },
+        )
+        .await;
+
+    assert!(matches!(server_result, Err(Error::SyncRejected(_))));
+    assert!(matches!(client_result, Err(Error::SyncRejected(_))));
+}
+
+#[tokio::test]
+async fn client_rejects_device_not_enabled_for_sync() {
+    let service = FakeSyncService::new();
+
+    let (server_result, client_result) = run(&service, |stream| async move {
+        ClientSession::begin(
+            stream,
+            CLIENT_UUID,
+            &[],
+            &PairingKeys::new(),
+            Capabilities::ALL,
+            None,
+        )
+        .await
+        .map(|_| ())
+    })
+    .await;
+
+    // クライアントが切断するので、サーバ側は接続が切れたことになる
+    assert!(server_result.is_err());
+    assert!(matches!(client_result, Err(Error::SyncRejected(_))));
+}
+
+#[tokio::test]
+async fn server_disconnects_on_version_mismatch() {
+    let service = FakeSyncService::new();
+
+    let (server_result, client_result) = run(&service, |mut stream| async move {
+        stream.write_u8(PROTOCOL_VERSION + 1).await?;
+        stream.write_u32_le(0).await?;
+
+        let version = stream.read_u8().await?;
+        let _capabilities = stream.read_u32_le().await?;
+
+        Ok::<_, Error>(version)
+    })
+    .await;
+
+    assert!(matches!(
+        server_result,
+        Err(Error::VersionMismatch { ours, theirs })
+            if ours == PROTOCOL_VERSION && theirs == PROTOCOL_VERSION + 1
+    ));
+    assert_eq!(client_result.unwrap(), PROTOCOL_VERSION);
+}
+
+#[tokio::test]
+async fn server_rejects_invalid_uuid_before_asking_javascript() {
+    let service = FakeSyncService::new();
+
+    let (server_result, client_result) = run(&service, |mut stream| async move {
+        stream.write_u8(PROTOCOL_VERSION).await?;
+        stream.write_u32_le(0).await?;
+        stream.write_all(&[b'x'; 36]).await?;
+
+        // サーバからは、バージョン・対応機能・UUID まで送られて切断される
+        let mut buffer = Vec::new();
+        stream.read_to_end(&mut buffer).await?;
+
+        Ok::<_, Error>(buffer.len())
+    })
+    .await;
+
+    assert!(matches!(server_result, Err(Error::ProtocolViolation(_))));
+    assert_eq!(client_result.unwrap(), 1 + 4 + 36);
+}
+
+#[tokio::test]
+async fn server_aborts_on_unknown_request() {
+    let service = FakeSyncService::new();
+
+    let (server_result, client_result) = run(&service, |mut stream| async move {
+        stream.write_u8(PROTOCOL_VERSION).await?;
+        stream.write_u32_le(0).await?;
+        stream.write_all(CLIENT_UUID.as_bytes()).await?;
+        stream.write_u8(0xff).await?;
+
+        let mut buffer = Vec::new();
+        stream.read_to_end(&mut buffer).await?;
+
+        Ok::<_, Error>(buffer.len())
+    })
+    .await;
+
+    assert!(matches!(server_result, Err(Error::ProtocolViolation(_))));
+    // バージョン・対応機能・UUID・同期の許可
+    assert_eq!(client_result.unwrap(), 1 + 4 + 36 + 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn slow_responses_are_not_cut_off_while_pings_are_answered() {
+    let service = FakeSyncService {
+        thread_updates_delay: IDLE_TIMEOUT * 3,
+        ..FakeSyncService::new()
+    };
+
+    let (server_result, client_result) = run(&service, |stream| async move {
+        let session = begin(stream).await?;
+
+        let thread_updates = session.request(&Request::ThreadUpdates).await?;
+        session.end(true).await?;
+
+        Ok::<_, Error>(thread_updates)
+    })
+    .await;
+
+    server_result.unwrap();
+    assert_eq!(
+        client_result.unwrap(),
+        response(&["thread_updates", CLIENT_UUID, NOW])
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn server_disconnects_idle_client() {
+    let service = FakeSyncService::new();
+    let started = tokio::time::Instant::now();
+
+    let (server_result, client_result) = run(&service, |mut stream| async move {
+        stream.write_u8(PROTOCOL_VERSION).await?;
+        stream.write_u32_le(Capabilities::HEARTBEAT.bits()).await?;
+        stream.write_all(CLIENT_UUID.as_bytes()).await?;
+
+        // ping を送らずに黙る
+        let mut buffer = Vec::new();
+        stream.read_to_end(&mut buffer).await?;
+
+        Ok::<_, Error>(())
+    })
+    .await;
+
+    assert!(matches!(
+        server_result,
+        Err(Error::Timeout { phase: "idle" })
+    ));
+    client_result.unwrap();
+    assert!(started.elapsed() < IDLE_TIMEOUT + PING_INTERVAL);
+}
+
+#[tokio::test(start_paused = true)]
+async fn client_gives_up_on_silent_server() {
+    let (mut server_stream, client_stream) = tokio::io::duplex(4096);
+    let started = tokio::time::Instant::now();
+
+    let server = async move {
+        // ハンドシェイクだけして、以降は何も返さない
+        server_stream.write_u8(PROTOCOL_VERSION).await?;
+        server_stream
+            .write_u32_le(Capabilities::HEARTBEAT.bits())
+            .await?;
+        server_stream.write_all(SERVER_UUID.as_bytes()).await?;
+        server_stream.write_u8(SYNC_ALLOWED).await?;
+
+        let mut buffer = Vec::new();
+        server_stream.read_to_end(&mut buffer).await?;
+
+        Ok::<_, Error>(())
+    };
+    let client = async move {
+        let session = begin(client_stream).await?;
+        session.request(&Request::ThreadUpdates).await
+    };
+
+    let (_, client_result) = tokio::join!(server, client);
+
+    assert!(matches!(client_result, Err(Error::Disconnected)));
+    assert!(started.elapsed() < IDLE_TIMEOUT + PING_INTERVAL);
+}
+
+#[tokio::test(start_paused = true)]
+async fn client_stops_waiting_for_a_server_that_does_not_close() {
+    let (mut server_stream, client_stream) = tokio::io::duplex(4096);
+
+    let server = async move {
+        server_stream.write_u8(PROTOCOL_VERSION).await?;
+        server_stream.write_u32_le(0).await?;
+        server_stream.write_all(SERVER_UUID.as_bytes()).await?;
+        server_stream.write_u8(SYNC_ALLOWED).await?;
+
+        // 同期の成功を受け取っても切断しない
+        let mut buffer = Vec::new();
+        server_stream.read_to_end(&mut buffer).await?;
+
+        Ok::<_, Error>(())
+    };
+    let client = async move {
+        let session = begin(client_stream).await?;
+        session.end(true).await
+    };
+
+    let (_, client_result) = tokio::join!(server, client);
+
+    assert!(matches!(
+        client_result,
+        Err(Error::Timeout { phase: "close" })
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn client_waits_for_the_server_to_save_synced_at() {
+    let service = FakeSyncService {
+        update_synced_at_delay: Duration::from_secs(8),
+        ..FakeSyncService::new()
+    };
+
+    let (server_result, client_result) = run(&service, |stream| async move {
+        let session = begin(stream).await?;
+
+        session.request(&Request::ThreadUpdates).await?;
+        session.end(true).await
+    })
+    .await;
+
+    server_result.unwrap();
+    client_result.unwrap();
+    assert!(service.synced_at.borrow().is_some());
+}
+
+#[tokio::test]
+async fn client_reports_a_sync_the_server_did_not_acknowledge() {
+    let (mut server_stream, client_stream) = tokio::io::duplex(4096);
+
+    let server = async move {
+        server_stream.write_u8(PROTOCOL_VERSION).await?;
+        server_stream.write_u32_le(0).await?;
+        server_stream.write_all(SERVER_UUID.as_bytes()).await?;
+        server_stream.write_u8(SYNC_ALLOWED).await?;
+
+        // 同期の成功を受け取ったら、ACK を返さずに切断する (同期時刻の保存に失敗した)
+        server_stream.read_u8().await?;
+
+        Ok::<_, Error>(())
+    };
+    let client = async move {
+        let session = begin(client_stream).await?;
+        session.end(true).await
+    };
+
+    let (_, client_result) = tokio::join!(server, client);
+
+    assert!(matches!(client_result, Err(Error::Disconnected)));
+}
+
+#[tokio::test]
+async fn dropped_sync_is_resumed_with_the_same_range() {
+    let service = FakeSyncService::new();
+
+    // 同期を終えずに切断する
+    let (server_result, client_result) = run(&service, |stream| async move {
+        let session = begin(stream).await?;
+        session.request(&Request::ThreadUpdates).await?;
+
+        Ok::<_, Error>(session.sync_id().unwrap().to_owned())
+    })
+    .await;
+
+    assert!(matches!(server_result, Err(Error::Disconnected)));
+    let sync_id = client_result.unwrap();
+
+    let (server_result, client_result) = run(&service, |stream| async move {
+        let session = ClientSession::begin(
+            stream,
+            CLIENT_UUID,
+            &[SERVER_UUID.to_owned()],
+            &PairingKeys::new(),
+            Capabilities::ALL,
+            Some(&sync_id),
+        )
+        .await?;
+        assert_eq!(session.sync_id(), Some(sync_id.as_str()));
+
+        let thread_updates = session.request(&Request::ThreadUpdates).await?;
+        session.end(true).await?;
+
+        Ok::<_, Error>(thread_updates)
+    })
+    .await;
+
+    server_result.unwrap();
+    // 最初の接続と同じ範囲でデータを返し、同期時刻を保存する
+    assert_eq!(
+        client_result.unwrap(),
+        response(&["thread_updates", CLIENT_UUID, NOW])
+    );
+    // 最初の接続でデータを取得し終えたリクエストは、JavaScript に問い合わせない
+    assert_eq!(service.thread_updates_calls.get(), 1);
+    assert_eq!(
+        *service.synced_at.borrow(),
+        Some((CLIENT_UUID.to_owned(), NOW.to_owned()))
+    );
+}
+
+#[tokio::test]
+async fn unknown_sync_is_started_anew() {
+    const UNKNOWN_SYNC_ID: &str = "55555555-5555-4555-8555-555555555555";
+    let service = FakeSyncService::new();
+
+    let (server_result, client_result) = run(&service, |stream| async move {
+        let session = ClientSession::begin(
+            stream,
+            CLIENT_UUID,
+            &[SERVER_UUID.to_owned()],
+            &PairingKeys::new(),
+            Capabilities::ALL,
+            Some(UNKNOWN_SYNC_ID),
+        )
+        .await?;
+        let sync_id = session.sync_id().map(str::to_owned);
+        session.end(true).await?;
+
+        Ok::<_, Error>(sync_id)
+    })
+    .await;
+
+    server_result.unwrap();
+    let sync_id = client_result.unwrap().unwrap();
+    assert_ne!(sync_id, UNKNOWN_SYNC_ID);
+}
+
+#[tokio::test]
+async fn sync_client_resumes_when_the_connection_is_lost() {
+    let service = FakeSyncService {
+        thread_updates_delay: Duration::from_millis(200),
+        ..FakeSyncService::new()
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+
+    let server = async {
+        // 1 回目の接続は、レスポンスを返す前に切る
+        let (stream, _) = listener.accept().await?;
+        let (session, _close_requested) = SESSIONS.register("first".to_owned());
+        let first = serve_transport(stream, &service, &session, Capabilities::ALL);
+        assert!(tokio::time::timeout(Duration::from_millis(100), first)
+            .await
+            .is_err());
+
+        let (stream, _) = listener.accept().await?;
+        let (session, _close_requested) = SESSIONS.register("second".to_owned());
+        serve_transport(stream, &service, &session, Capabilities::ALL).await
+    };
+    let client = async {
+        let mut client = SyncClient::create_tcp_instance(CLIENT_UUID.to_owned(), address);
+        client.begin_sync_impl(&[SERVER_UUID.to_owned()]).await?;
+
+        let thread_updates = client
+            .request_data_impl(&Request::ThreadUpdates, &AbortWatcher::default())
+            .await?;
+        client.end_sync_impl(true).await?;
+
+        Ok::<_, Error>(thread_updates)
+    };
+
+    let (server_result, client_result) = tokio::join!(server, client);
+
+    server_result.unwrap();
+    assert_eq!(
+        client_result.unwrap(),
+        response(&["thread_updates", CLIENT_UUID, NOW])
+    );
+    assert_eq!(service.now_calls.get(), 1);
+}
+
+/// 遅いリクエストを途中で取り消し、続けて別のリクエストを送る
+/// 戻り値は (取り消したリクエストの結果, 続けたリクエストの結果)
+async fn cancel_slow_request(
+    stream: DuplexStream,
+    capabilities: Capabilities,
+) -> Result<(Result<String>, String)> {
+    let session = begin_with(stream, capabilities).await?;
+    let (tx, aborted) = AbortWatcher::channel(false);
+
+    let (thread_updates, _) = tokio::join!(
+        session.request_abortable(&Request::ThreadUpdates, &aborted),
+        async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            tx.send(true).unwrap();
+        }
+    );
+    let notes = session
+        .request(&Request::AllNotesInThread {
+            thread_id: THREAD_ID.to_owned(),
+        })
+        .await?;
+
+    session.end(false).await?;
+
+    Ok((thread_updates, notes))
+}
+
+#[tokio::test(start_paused = true)]
+async fn cancelled_request_stops_the_server_and_the_session_continues() {
+    let delay = PING_INTERVAL * 10;
+    let service = FakeSyncService {
+        thread_updates_delay: delay,
+        ..FakeSyncService::new()
+    };
+    let started = tokio::time::Instant::now();
+
+    let (server_result, client_result) = run(&service, |stream| {
+        cancel_slow_request(stream, Capabilities::ALL)
+    })
+    .await;
+
+    server_result.unwrap();
+    let (thread_updates, notes) = client_result.unwrap();
+
+    assert!(matches!(thread_updates, Err(Error::Cancelled)));
+    assert_eq!(notes, response(&["all_notes_in_thread", THREAD_ID]));
+    // サーバは取り消されたリクエストの処理を待たずに終わる
+    assert!(started.elapsed() < delay);
+}
+
+#[tokio::test(start_paused = true)]
+async fn request_is_cancelled_locally_without_cancel_frames() {
+    let delay = PING_INTERVAL * 10;
+    let service = FakeSyncService {
+        thread_updates_delay: delay,
+        ..FakeSyncService::new()
+    };
+
+    let (server_result, client_result) = run(&service, |stream| {
+        cancel_slow_request(stream, Capabilities::ALL.difference(Capabilities::CANCEL))
+    })
+    .await;
+
+    server_result.unwrap();
+    let (thread_updates, notes) = client_result.unwrap();
+
+    // サーバは最後まで処理するが、クライアントはそのレスポンスを捨てる
+    assert!(matches!(thread_updates, Err(Error::Cancelled)));
+    assert_eq!(notes, response(&["all_notes_in_thread", THREAD_ID]));
+}
+
+#[tokio::test]
+async fn paired_devices_authenticate_each_other_and_sync() {
+    let service = FakeSyncService {
+        pairing_key: Some(pairing_key("11")),
+        ..FakeSyncService::new()
+    };
+
+    let (server_result, client_result) = run(&service, |stream| async move {
+        let session = begin_paired(stream, Capabilities::ALL, Some(&pairing_key("11"))).await?;
+
+        let thread_updates = session.request(&Request::ThreadUpdates).await?;
+        session.end(true).await?;
+
+        Ok::<_, Error>(thread_updates)
+    })
+    .await;
+
+    server_result.unwrap();
+    assert_eq!(
+        client_result.unwrap(),
+        response(&["thread_updates", CLIENT_UUID, NOW])
+    );
+}
+
+#[tokio::test]
+async fn server_rejects_device_without_the_pairing_key() {
+    let service = FakeSyncService {
+        pairing_key: Some(pairing_key("11")),
+        ..FakeSyncService::new()
+    };
+
+    let (server_result, client_result) = run(&service, |stream| async move {
+        begin_paired(stream, Capabilities::ALL, None)
+            .await
+            .map(|_| ())
+    })
+    .await;
+
+    assert!(matches!(server_result, Err(Error::AuthenticationFailed(_))));
+    assert!(matches!(client_result, Err(Error::SyncRejected(_))));
+    assert_eq!(*service.synced_at.borrow(), None);
+}
+
+#[tokio::test]
+async fn server_rejects_paired_device_that_skips_authentication() {
+    let service = FakeSyncService {
+        pairing_key: Some(pairing_key("11")),
+        ..FakeSyncService::new()
+    };
+
+    let (server_result, client_result) = run(&service, |stream| async move {
+        begin_with(stream, Capabilities::ALL.difference(Capabilities::AUTH))
+            .await
+            .map(|_| ())
+    })
+    .await;
+
+    assert!(matches!(server_result, Err(Error::AuthenticationFailed(_))));
+    assert!(matches!(client_result, Err(Error::SyncRejected(_))));
+}
+
+#[tokio::test]
+async fn client_rejects_server_with_a_different_pairing_key() {
+    let service = FakeSyncService {
+        pairing_key: Some(pairing_key("22")),
+        ..FakeSyncService::new()
+    };
+
+    let (server_result, client_result) = run(&service, |stream| async move {
+        begin_paired(stream, Capabilities::ALL, Some(&pairing_key("11")))
+            .await
+            .map(|_| ())
+    })
+    .await;
+
+    assert!(matches!(server_result, Err(Error::AuthenticationFailed(_))));
+    assert!(matches!(
+        client_result,
+        Err(Error::AuthenticationFailed(uuid)) if uuid == SERVER_UUID
+    ));
+}
+
+#[tokio::test]
+async fn paired_devices_sync_large_responses_with_and_without_encryption() {
+    // 圧縮しないので、レスポンスが暗号化のレコードいくつかに分かれる
+    let content = "# 買い物\n\n- 牛乳\n- 卵\n- パン\n".repeat(1000);
+    let service = FakeSyncService {
+        note_content: Some(content.clone()),
+        pairing_key: Some(pairing_key("11")),
+        ..FakeSyncService::new()
+    };
+    let uncompressed = Capabilities::ALL.difference(Capabilities::DEFLATE);
+
+    for capabilities in [uncompressed, uncompressed.difference(Capabilities::ENCRYPT)] {
+        let (server_result, client_result) = run(&service, |stream| async move {
+            let session = begin_paired(stream, capabilities, Some(&pairing_key("11"))).await?;
+
+            let notes = session
+                .request(&Request::AllNotesInThread {
+                    thread_id: THREAD_ID.to_owned(),
+                })
+                .await?;
+            session.end(true).await?;
+
+            Ok::<_, Error>(notes)
+        })
+        .await;
+
+        server_result.unwrap();
+        assert_eq!(
+            client_result.unwrap(),
+            response(&["all_notes_in_thread", THREAD_ID, &content])
+        );
+    }
+}
+
+#[tokio::test]
+async fn encrypted_session_hides_the_traffic_after_authentication() {
+    let service = FakeSyncService {
+        pairing_key: Some(pairing_key("11")),
+        ..FakeSyncService::new()
+    };
+
+    // サーバとクライアントの間に入り、サーバから送られたバイト列を記録する
+    let (client_stream, proxy_client) = tokio::io::duplex(4096);
+    let (proxy_server, server_stream) = tokio::io::duplex(4096);
+    let (session, _close_requested) = SESSIONS.register(server_stream.peer_name());
+
+    let tap = async move {
+        let (mut from_client, mut to_client) = tokio::io::split(proxy_client);
+        let (mut from_server, mut to_server) = tokio::io::split(proxy_server);
+        let mut sent_by_server = Vec::new();
+
+        let upstream = tokio::io::copy(&mut from_client, &mut to_server);
+        let downstream = async {
+            let mut buf = [0; 1024];
+            loop {
+                let n = from_server.read(&mut buf).await?;
+                if n == 0 {
+                    return Ok::<_, std::io::Error>(());
+                }
+                sent_by_server.extend_from_slice(&buf[..n]);
+                to_client.write_all(&buf[..n]).await?;
+            }
+        };
+        // サーバが切断したら終わる
+        tokio::select! {
+            _ = upstream => {}
+            _ = downstream => {}
+        }
+
+        sent_by_server
+    };
+
+    let client = async move {
+        let session =
+            begin_paired(client_stream, Capabilities::ALL, Some(&pairing_key("11"))).await?;
+
+        let thread_updates = session.request(&Request::ThreadUpdates).await?;
+        session.end(true).await?;
+
+        Ok::<_, Error>(thread_updates)
+    };
+
+    let (server_result, client_result, sent_by_server) = tokio::join!(
+        serve_transport(server_stream, &service, &session, Capabilities::ALL),
+        client,
+        tap
+    );
+
+    server_result.unwrap();
+    assert_eq!(
+        client_result.unwrap(),
+        response(&["thread_updates", CLIENT_UUID, NOW])
+    );
+
+    // UUID は認証の前に送るので平文だが、レスポンスは暗号化されている
+    let contains = |needle: &[u8]| sent_by_server.windows(needle.len()).any(|w| w == needle);
+    assert!(contains(SERVER_UUID.as_bytes()));
+    assert!(!contains(b"thread_updates"));
+}
+
+#[tokio::test]
+async fn server_rejects_revoked_device_without_asking_javascript() {
+    // 信頼を取り消すと、サーバは鍵を捨てる (クライアントは鍵を持ったまま)
+    let service = FakeSyncService {
+        revoked: true,
+        ..FakeSyncService::new()
+    };
+
+    let (server_result, client_result) = run(&service, |stream| async move {
+        begin_paired(stream, Capabilities::ALL, Some(&pairing_key("11")))
+            .await
+            .map(|_| ())
+    })
+    .await;
+
+    assert!(matches!(
+        server_result,
+        Err(Error::DeviceRevoked(uuid)) if uuid == CLIENT_UUID
+    ));
+    assert!(matches!(
+        client_result,
+        Err(Error::DeviceRevoked(uuid)) if uuid == CLIENT_UUID
+    ));
+    assert_eq!(service.sync_allowed_calls.get(), 0);
+}
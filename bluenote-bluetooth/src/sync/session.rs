@@ -0,0 +1,225 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use tokio::sync::{oneshot, Notify};
+
+/// 同期サーバで動いているセッションの一覧
+pub static SESSIONS: SessionRegistry = SessionRegistry::new();
+
+/// セッションの状態
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionState {
+    /// バージョン・UUID の交換、同期の許可の確認中
+    Handshaking,
+    /// 相手のリクエストに応じてデータを返している
+    Serving,
+    /// 同期時刻を保存して、終了処理をしている
+    Finishing,
+    /// 切断を要求された
+    Closing,
+}
+
+impl SessionState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SessionState::Handshaking => "handshaking",
+            SessionState::Serving => "serving",
+            SessionState::Finishing => "finishing",
+            SessionState::Closing => "closing",
+        }
+    }
+}
+
+/// セッションの情報
+#[derive(Clone, Debug)]
+pub struct SessionInfo {
+    pub id: u32,
+    /// 相手のデバイスの UUID (交換前は `None`)
+    pub uuid: Option<String>,
+    /// 相手のデバイス名 (TCP の場合はアドレス)
+    pub device_name: String,
+    pub started_at: SystemTime,
+    pub state: SessionState,
+}
+
+struct SessionEntry {
+    info: SessionInfo,
+    /// 切断を要求するためのチャネル
+    close_sender: Option<oneshot::Sender<()>>,
+}
+
+/// 接続ごとのセッションを管理する
+pub struct SessionRegistry {
+    sessions: Mutex<BTreeMap<u32, SessionEntry>>,
+    next_id: AtomicU32,
+    /// セッションがすべて終了したときに通知する
+    drained: Notify,
+}
+
+impl SessionRegistry {
+    pub(crate) const fn new() -> Self {
+        Self {
+            sessions: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU32::new(0),
+            drained: Notify::const_new(),
+        }
+    }
+
+    /// セッションを登録する
+    /// 戻り値の `Session` が drop されると登録が解除される
+    /// `oneshot::Receiver` は `close_all` で切断を要求されたときに受信する
+    pub fn register(&'static self, device_name: String) -> (Session, oneshot::Receiver<()>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (close_sender, close_receiver) = oneshot::channel();
+
+        self.sessions.lock().unwrap().insert(
+            id,
+            SessionEntry {
+                info: SessionInfo {
+                    id,
+                    uuid: None,
+                    device_name,
+                    started_at: SystemTime::now(),
+                    state: SessionState::Handshaking,
+                },
+                close_sender: Some(close_sender),
+            },
+        );
+
+        (Session { id, registry: self }, close_receiver)
+    }
+
+    /// 現在のセッションの一覧を取得する
+    pub fn list(&self) -> Vec<SessionInfo> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .map(|entry| entry.info.clone())
+            .collect()
+    }
+
+    /// すべてのセッションに切断を要求する
+    pub fn close_all(&self) {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        for entry in sessions.values_mut() {
+            Self::close_entry(entry);
+        }
+    }
+
+    /// すべてのセッションが終了するまで待つ
+    pub async fn wait_drained(&self) {
+        loop {
+            // 通知を取りこぼさないように、確認する前に待ち受けを作っておく
+            let drained = self.drained.notified();
+
+            if self.sessions.lock().unwrap().is_empty() {
+                return;
+            }
+
+            drained.await;
+        }
+    }
+
+    /// すべてのセッションが終了するまで、`timeout` だけ待つ
+    /// 時間内に終わらなかったセッションには切断を要求し、終了を待つ
+    pub async fn drain(&self, timeout: Duration) {
+        if tokio::time::timeout(timeout, self.wait_drained())
+            .await
+            .is_err()
+        {
+            println!("Sessions did not finish in {:?}. Closing them.", timeout);
+            self.close_all();
+            self.wait_drained().await;
+        }
+    }
+
+    fn close_entry(entry: &mut SessionEntry) {
+        entry.info.state = SessionState::Closing;
+
+        if let Some(close_sender) = entry.close_sender.take() {
+            // セッションがすでに終わりかけている場合は受信側がいないが、問題ない
+            let _ = close_sender.send(());
+        }
+    }
+
+    fn update(&self, id: u32, f: impl FnOnce(&mut SessionInfo)) {
+        if let Some(entry) = self.sessions.lock().unwrap().get_mut(&id) {
+            f(&mut entry.info);
+        }
+    }
+
+    fn unregister(&self, id: u32) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.remove(&id);
+
+        if sessions.is_empty() {
+            self.drained.notify_waiters();
+        }
+    }
+}
+
+/// 登録済みのセッションのハンドル
+/// セッションを処理している側が状態を更新するのに使う
+pub struct Session {
+    id: u32,
+    registry: &'static SessionRegistry,
+}
+
+impl Session {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// 相手のデバイスの UUID を記録する
+    pub fn set_uuid(&self, uuid: &str) {
+        self.registry
+            .update(self.id, |info| info.uuid = Some(uuid.to_owned()));
+    }
+
+    /// 状態を更新する
+    /// 切断を要求された後は更新しない
+    pub fn set_state(&self, state: SessionState) {
+        self.registry.update(self.id, |info| {
+            if info.state != SessionState::Closing {
+                info.state = state;
+            }
+        });
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.registry.unregister(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static REGISTRY: SessionRegistry = SessionRegistry::new();
+
+    #[tokio::test(start_paused = true)]
+    async fn sessions_that_do_not_finish_are_closed_after_the_timeout() {
+        let (session, close_requested) = REGISTRY.register("slow".to_owned());
+
+        // 切断を要求されるまで終わらないセッション
+        let serving = tokio::spawn(async move {
+            let _ = close_requested.await;
+            drop(session);
+        });
+
+        REGISTRY.drain(Duration::from_secs(30)).await;
+
+        assert!(REGISTRY.list().is_empty());
+        serving.await.unwrap();
+    }
+}
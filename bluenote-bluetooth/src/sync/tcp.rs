@@ -0,0 +1,42 @@
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::Result;
+
+use super::transport::{BoxedReader, BoxedWriter, Transport};
+
+/// TCP による通信路
+/// LAN 内のデスクトップ同士の同期や、ローカルで動かした相手との同期に使う
+impl Transport for TcpStream {
+    fn into_split(self) -> (BoxedReader, BoxedWriter) {
+        let (reader, writer) = TcpStream::into_split(self);
+        (Box::new(reader), Box::new(writer))
+    }
+
+    fn peer_name(&self) -> String {
+        match self.peer_addr() {
+            Ok(address) => address.to_string(),
+            Err(_) => "unknown".to_owned(),
+        }
+    }
+}
+
+/// 同期サーバに接続する
+pub async fn connect(address: &str) -> Result<TcpStream> {
+    println!("Connecting to {}...", address);
+
+    let stream = TcpStream::connect(address).await?;
+    stream.set_nodelay(true)?;
+
+    println!("Connected!");
+
+    Ok(stream)
+}
+
+/// 指定したアドレスで接続の待ち受けを開始する
+pub async fn listen(address: &str) -> Result<TcpListener> {
+    let listener = TcpListener::bind(address).await?;
+
+    println!("Listening on {}", listener.local_addr()?);
+
+    Ok(listener)
+}
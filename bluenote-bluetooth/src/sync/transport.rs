@@ -0,0 +1,32 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// 通信路の読み込み側
+pub type BoxedReader = Box<dyn AsyncRead + Unpin + Send>;
+
+/// 通信路の書き込み側
+pub type BoxedWriter = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// 同期プロトコルを流す通信路の抽象化
+///
+/// 同期クライアント・サーバはどちらもこのトレイトを実装した通信路の上で動作する
+/// RFCOMM の `StreamSocket` はその実装の一つ
+pub trait Transport: Send + 'static {
+    /// 読み込み側と書き込み側に分割する
+    /// 接続は両方が drop されるまで維持される
+    fn into_split(self) -> (BoxedReader, BoxedWriter);
+
+    /// 接続相手の表示名 (デバイス名やアドレス)
+    fn peer_name(&self) -> String;
+}
+
+/// メモリ上のパイプ (主にテスト用)
+impl Transport for tokio::io::DuplexStream {
+    fn into_split(self) -> (BoxedReader, BoxedWriter) {
+        let (reader, writer) = tokio::io::split(self);
+        (Box::new(reader), Box::new(writer))
+    }
+
+    fn peer_name(&self) -> String {
+        "memory".to_owned()
+    }
+}
@@ -0,0 +1,46 @@
+use std::path::Path;
+
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::Result;
+
+use super::transport::{BoxedReader, BoxedWriter, Transport};
+
+/// Unix ドメインソケットによる通信路
+/// 同じマシン上で動かした相手 (bluenote-peer など) との同期に使う
+impl Transport for UnixStream {
+    fn into_split(self) -> (BoxedReader, BoxedWriter) {
+        let (reader, writer) = UnixStream::into_split(self);
+        (Box::new(reader), Box::new(writer))
+    }
+
+    fn peer_name(&self) -> String {
+        match self.peer_addr() {
+            Ok(address) => match address.as_pathname() {
+                Some(path) => path.display().to_string(),
+                None => "unix socket".to_owned(),
+            },
+            Err(_) => "unknown".to_owned(),
+        }
+    }
+}
+
+/// 同期サーバに接続する
+pub async fn connect(path: impl AsRef<Path>) -> Result<UnixStream> {
+    println!("Connecting to {}...", path.as_ref().display());
+
+    let stream = UnixStream::connect(path).await?;
+
+    println!("Connected!");
+
+    Ok(stream)
+}
+
+/// 指定したパスで接続の待ち受けを開始する
+pub fn listen(path: impl AsRef<Path>) -> Result<UnixListener> {
+    let listener = UnixListener::bind(&path)?;
+
+    println!("Listening on {}", path.as_ref().display());
+
+    Ok(listener)
+}
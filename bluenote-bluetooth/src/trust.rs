@@ -0,0 +1,321 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+
+use crate::{error::Error, sync::auth::PairingKey, Result};
+
+/// 信頼するデバイスの一覧
+/// `openTrustStore` で開くまでは `None` (鍵はアプリからのみ受け取る)
+pub static TRUST_STORE: Mutex<Option<TrustStore>> = Mutex::new(None);
+
+/// 保存するファイルの形式のバージョン
+const FORMAT_VERSION: u64 = 1;
+
+/// ペアリングした相手のデバイス
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrustedDevice {
+    pub uuid: String,
+    pub name: String,
+    /// 同期のときに相手を認証する鍵 (鍵の交換に対応していない相手や、取り消した相手は `None`)
+    pub pairing_key: Option<PairingKey>,
+    /// 相手のプラットフォーム・アプリのバージョン (初期設定で相手が送ってこなかった場合は `None`)
+    pub platform: Option<String>,
+    pub app_version: Option<String>,
+    pub paired_at: SystemTime,
+    /// 取り消したデバイスとは、再びペアリングするまで同期しない
+    pub revoked: bool,
+}
+
+impl TrustedDevice {
+    /// いまペアリングした相手
+    pub fn new(uuid: &str, name: &str, pairing_key: Option<PairingKey>) -> Self {
+        Self {
+            uuid: uuid.to_owned(),
+            name: name.to_owned(),
+            pairing_key,
+            platform: None,
+            app_version: None,
+            paired_at: SystemTime::now(),
+            revoked: false,
+        }
+    }
+}
+
+/// ペアリングした相手と、その鍵を保存するファイル
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "devices": [
+///     {
+///       "uuid": "...", "name": "...", "pairingKey": "16 進数 or null",
+///       "platform": "windows or null", "appVersion": "1.0.0 or null", "pairedAt": 0, "revoked": false
+///     }
+///   ]
+/// }
+/// ```
+///
+/// 鍵を平文で保存するので、ユーザのデータのディレクトリに置くこと
+pub struct TrustStore {
+    path: PathBuf,
+    devices: BTreeMap<String, TrustedDevice>,
+}
+
+impl TrustStore {
+    /// ファイルから読み込む
+    /// ファイルがなければ、空の一覧として開く (最初に保存するときに作る)
+    pub fn open(path: &Path) -> Result<Self> {
+        let json = match std::fs::read_to_string(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self {
+                    path: path.to_owned(),
+                    devices: BTreeMap::new(),
+                });
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let invalid =
+            |message: &str| Error::InvalidArgument(format!("{}: {}", path.display(), message));
+
+        let value: Value = serde_json::from_str(&json).map_err(|e| invalid(&e.to_string()))?;
+
+        if value["version"].as_u64() != Some(FORMAT_VERSION) {
+            return Err(invalid("Unsupported version"));
+        }
+
+        let devices = value["devices"]
+            .as_array()
+            .ok_or_else(|| invalid("`devices` is required"))?
+            .iter()
+            .map(|device| {
+                let uuid = device["uuid"]
+                    .as_str()
+                    .ok_or_else(|| invalid("`uuid` is required"))?
+                    .to_owned();
+                let pairing_key = device["pairingKey"]
+                    .as_str()
+                    .map(PairingKey::from_hex)
+                    .transpose()?;
+                let string = |key: &str| device[key].as_str().map(|v| v.to_owned());
+
+                Ok((
+                    uuid.clone(),
+                    TrustedDevice {
+                        uuid,
+                        name: string("name").unwrap_or_default(),
+                        pairing_key,
+                        platform: string("platform"),
+                        app_version: string("appVersion"),
+                        paired_at: UNIX_EPOCH
+                            + Duration::from_millis(device["pairedAt"].as_u64().unwrap_or(0)),
+                        revoked: device["revoked"].as_bool().unwrap_or(false),
+                    },
+                ))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            devices,
+        })
+    }
+
+    /// ペアリングした相手を信頼する
+    /// 以前に取り消したデバイスでも、ペアリングし直せば再び信頼する
+    pub fn trust(&mut self, device: TrustedDevice) -> Result<()> {
+        self.devices.insert(device.uuid.clone(), device);
+
+        self.save()
+    }
+
+    /// デバイスの信頼を取り消し、鍵を捨てる
+    /// 一覧にないデバイス (アプリだけが覚えているデバイス) も、取り消したものとして記録する
+    pub fn revoke(&mut self, uuid: &str) -> Result<()> {
+        let device = self
+            .devices
+            .entry(uuid.to_owned())
+            .or_insert_with(|| TrustedDevice {
+                paired_at: UNIX_EPOCH,
+                ..TrustedDevice::new(uuid, "", None)
+            });
+
+        device.pairing_key = None;
+        device.revoked = true;
+
+        self.save()
+    }
+
+    pub fn is_revoked(&self, uuid: &str) -> bool {
+        self.devices.get(uuid).is_some_and(|device| device.revoked)
+    }
+
+    /// 相手と共有した鍵 (取り消したデバイスの鍵は返さない)
+    pub fn pairing_key(&self, uuid: &str) -> Option<PairingKey> {
+        self.devices
+            .get(uuid)
+            .and_then(|device| device.pairing_key.clone())
+    }
+
+    pub fn list(&self) -> Vec<TrustedDevice> {
+        self.devices.values().cloned().collect()
+    }
+
+    /// 一時ファイルに書いてから置き換え、書き込みの途中で終了しても壊れないようにする
+    fn save(&self) -> Result<()> {
+        let devices: Vec<Value> = self
+            .devices
+            .values()
+            .map(|device| {
+                json!({
+                    "uuid": device.uuid,
+                    "name": device.name,
+                    "pairingKey": device.pairing_key.as_ref().map(PairingKey::to_hex),
+                    "platform": device.platform,
+                    "appVersion": device.app_version,
+                    "pairedAt": device
+                        .paired_at
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_millis() as u64)
+                        .unwrap_or(0),
+                    "revoked": device.revoked,
+                })
+            })
+            .collect();
+        let json = json!({ "version": FORMAT_VERSION, "devices": devices });
+
+        let temp_path = self.path.with_extension("tmp");
+        write_private(&temp_path, json.to_string().as_bytes())?;
+        std::fs::rename(&temp_path, &self.path)?;
+
+        Ok(())
+    }
+}
+
+/// 自分だけが読み書きできるファイルとして書き込む
+fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+/// 開いている一覧で `f` を呼ぶ (開いていなければ `None`)
+fn with_trust_store<T>(f: impl FnOnce(&mut TrustStore) -> T) -> Option<T> {
+    TRUST_STORE.lock().unwrap().as_mut().map(f)
+}
+
+/// ペアリングした相手を、開いている一覧に保存する
+pub fn remember(device: TrustedDevice) -> Result<()> {
+    with_trust_store(|store| store.trust(device)).unwrap_or(Ok(()))
+}
+
+/// 開いている一覧で、信頼を取り消したデバイスか
+pub fn is_revoked(uuid: &str) -> bool {
+    with_trust_store(|store| store.is_revoked(uuid)).unwrap_or(false)
+}
+
+/// 開いている一覧に保存した、相手と共有した鍵
+pub fn pairing_key(uuid: &str) -> Option<PairingKey> {
+    with_trust_store(|store| store.pairing_key(uuid)).flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: &str = "22222222-2222-4222-8222-222222222222";
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bluenote-trust-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        dir.join(name)
+    }
+
+    fn key() -> PairingKey {
+        PairingKey::from_hex(&"ab".repeat(32)).unwrap()
+    }
+
+    #[test]
+    fn trusted_devices_are_persisted() {
+        let path = temp_path("trusted_devices.json");
+
+        let mut store = TrustStore::open(&path).unwrap();
+        store
+            .trust(TrustedDevice {
+                platform: Some("android".to_owned()),
+                ..TrustedDevice::new(UUID, "Pixel", Some(key()))
+            })
+            .unwrap();
+
+        let reopened = TrustStore::open(&path).unwrap();
+        let devices = reopened.list();
+
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].name, "Pixel");
+        assert_eq!(devices[0].platform.as_deref(), Some("android"));
+        assert_eq!(devices[0].app_version, None);
+        assert_eq!(reopened.pairing_key(UUID), Some(key()));
+        assert!(!reopened.is_revoked(UUID));
+    }
+
+    #[test]
+    fn revoked_device_loses_its_key_until_paired_again() {
+        let path = temp_path("trusted_devices.json");
+
+        let mut store = TrustStore::open(&path).unwrap();
+        store
+            .trust(TrustedDevice::new(UUID, "Pixel", Some(key())))
+            .unwrap();
+        store.revoke(UUID).unwrap();
+
+        let mut reopened = TrustStore::open(&path).unwrap();
+        assert!(reopened.is_revoked(UUID));
+        assert_eq!(reopened.pairing_key(UUID), None);
+
+        reopened
+            .trust(TrustedDevice::new(UUID, "Pixel", Some(key())))
+            .unwrap();
+        assert!(!reopened.is_revoked(UUID));
+    }
+
+    #[test]
+    fn unknown_device_can_be_revoked() {
+        let path = temp_path("trusted_devices.json");
+
+        let mut store = TrustStore::open(&path).unwrap();
+        store.revoke(UUID).unwrap();
+
+        assert!(TrustStore::open(&path).unwrap().is_revoked(UUID));
+    }
+
+    #[test]
+    fn broken_file_is_not_overwritten() {
+        let path = temp_path("trusted_devices.json");
+        std::fs::write(&path, "{").unwrap();
+
+        assert!(matches!(
+            TrustStore::open(&path),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+}
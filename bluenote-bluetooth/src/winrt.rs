@@ -0,0 +1,11 @@
+//! Windows (WinRT) の Bluetooth API を使う部分
+//!
+//! RFCOMM による通信路、デバイスのスキャン、ペアリングはここにまとめ、
+//! それ以外 (プロトコルやエラーなど) はどのプラットフォームでもビルドできるようにする
+
+pub mod async_reader;
+pub mod async_writer;
+pub mod init_client;
+pub mod init_server;
+pub mod rfcomm;
+pub mod scanner;
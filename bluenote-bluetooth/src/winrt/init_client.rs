@@ -14,7 +14,11 @@ use windows::{
     Networking::Sockets::{SocketProtectionLevel, StreamSocket},
 };
 
-use crate::{error::Error, init, Result, RUNTIME, UUID_BLUENOTE_RFCOMM_INIT};
+use crate::{
+    error::Error, init, sync::transport::Transport, Result, RUNTIME, UUID_BLUENOTE_RFCOMM_INIT,
+};
+
+use super::rfcomm::RfcommTransport;
 
 pub static ACCEPT_SENDER: Mutex<Option<oneshot::Sender<bool>>> = Mutex::new(None);
 pub static ON_PAIRING_REQUESTED: Mutex<Option<ThreadsafeFunction<RequestParamPairing>>> =
@@ -38,7 +42,11 @@ pub struct RequestParamPairing {
     pub pin: String,
 }
 
-pub async fn init(windows_device_id: &str, device_uuid: &str) -> Result<String> {
+pub async fn init(
+    windows_device_id: &str,
+    device_uuid: &str,
+    my_hello: &init::Hello,
+) -> Result<init::Paired> {
     let bluetooth_device = BluetoothDevice::FromIdAsync(&HSTRING::from(windows_device_id))?.await?;
     let rfcomm_services = bluetooth_device
         .GetRfcommServicesForIdWithCacheModeAsync(
@@ -49,9 +57,9 @@ pub async fn init(windows_device_id: &str, device_uuid: &str) -> Result<String>
 
     // 特定のサービスIDをもっているデバイスのみ対象
     if rfcomm_services.Services()?.Size()? == 0 {
-        return Err(Error::SyncError(format!(
-            "Device seems not to have Bluenote app"
-        )));
+        return Err(Error::PeerNotFound(
+            "Device seems not to have Bluenote app".to_owned(),
+        ));
     }
 
     let pairing = bluetooth_device.DeviceInformation()?.Pairing()?;
@@ -69,7 +77,7 @@ pub async fn init(windows_device_id: &str, device_uuid: &str) -> Result<String>
 
     println!("{}", device_name);
 
-    let uuid = {
+    let paired = {
         let service = rfcomm_services.Services()?.GetAt(0)?;
         let socket = StreamSocket::new()?;
 
@@ -83,13 +91,24 @@ pub async fn init(windows_device_id: &str, device_uuid: &str) -> Result<String>
 
         println!("Connected!");
 
-        init::exchange_uuid(device_uuid, &socket.InputStream()?, &socket.OutputStream()?).await?
+        let (mut reader, mut writer) = RfcommTransport::new(socket)?.into_split();
+
+        init::exchange_uuid(
+            device_uuid,
+            my_hello,
+            init::PairingMethod::Plain,
+            &mut reader,
+            &mut writer,
+        )
+        .await?
     };
 
     println!("Connection closed.");
-    println!("UUID: {}", uuid);
+    println!("UUID: {}", paired.uuid);
+
+    crate::trust::remember(paired.to_trusted_device(&device_name))?;
 
-    Ok(uuid)
+    Ok(paired)
 }
 
 async fn pair(bluetooth_device: &BluetoothDevice) -> Result<()> {
@@ -111,7 +130,7 @@ async fn pair(bluetooth_device: &BluetoothDevice) -> Result<()> {
 
     match status {
         DevicePairingResultStatus::Paired => Ok(()),
-        _ => Err(Error::SyncError(format!("Pairing failed: {:?}", status))),
+        _ => Err(Error::Other(format!("Pairing failed: {:?}", status))),
     }
 }
 
@@ -15,20 +15,25 @@ use windows::{
     },
 };
 
-use crate::{init::exchange_uuid, Result, RUNTIME, UUID_BLUENOTE_RFCOMM_INIT};
+use crate::{
+    init::{exchange_uuid, notify_paired, notify_pairing_failed, Hello, PairingMethod},
+    sync::transport::Transport,
+    Result, RUNTIME, UUID_BLUENOTE_RFCOMM_INIT,
+};
+
+use super::rfcomm::RfcommTransport;
 
 static INIT_SERVER_STATE: Mutex<Option<InitServerState>> = Mutex::new(None);
-pub static ON_UUID_EXCHANGED: Mutex<Option<ThreadsafeFunction<(String, String)>>> =
-    Mutex::new(None);
 pub static ON_STATE_CHANGED: Mutex<Option<ThreadsafeFunction<bool>>> = Mutex::new(None);
 
 struct InitServerState {
     my_device_uuid: String,
+    my_hello: Hello,
     listener: StreamSocketListener,
     provider: RfcommServiceProvider,
 }
 
-pub async fn start(device_uuid: String) -> Result<()> {
+pub async fn start(device_uuid: String, my_hello: Hello) -> Result<()> {
     let mut inner = INIT_SERVER_STATE.lock().unwrap();
 
     if inner.is_some() {
@@ -41,6 +46,7 @@ pub async fn start(device_uuid: String) -> Result<()> {
 
     *inner = Some(InitServerState {
         my_device_uuid: device_uuid,
+        my_hello,
         listener,
         provider,
     });
@@ -109,45 +115,45 @@ fn on_received(
             .DisplayName()?
     );
 
-    let my_uuid = INIT_SERVER_STATE
-        .lock()
-        .unwrap()
-        .as_ref()
-        .unwrap()
-        .my_device_uuid
-        .to_owned();
+    let (my_uuid, my_hello) = {
+        let state = INIT_SERVER_STATE.lock().unwrap();
+        let state = state.as_ref().unwrap();
+
+        (state.my_device_uuid.to_owned(), state.my_hello.clone())
+    };
     let socket = e.as_ref().unwrap().Socket()?;
 
     std::thread::spawn(move || {
         let local = LocalSet::new();
 
-        local.spawn_local(async move {
+        let result = RUNTIME.block_on(local.run_until(async move {
             let device =
                 BluetoothDevice::FromHostNameAsync(&socket.Information()?.RemoteHostName()?)?
                     .await?;
             let device_name = device.Name()?.to_string();
 
-            let uuid = {
-                let uuid = exchange_uuid(&my_uuid, &socket.InputStream()?, &socket.OutputStream()?)
-                    .await?;
-                drop(socket);
-                uuid
+            let paired = {
+                let (mut reader, mut writer) = RfcommTransport::new(socket)?.into_split();
+                // トークンを発行していれば、そのコードを持つ相手だけとペアリングする
+                exchange_uuid(
+                    &my_uuid,
+                    &my_hello,
+                    PairingMethod::IssuedToken { required: false },
+                    &mut reader,
+                    &mut writer,
+                )
+                .await?
             };
 
             println!("Connection closed.");
-            println!("UUID: {}", uuid);
-
-            if let Some(on_uuid_exchanged) = ON_UUID_EXCHANGED.lock().unwrap().as_ref() {
-                on_uuid_exchanged.call(
-                    Ok((device_name, uuid)),
-                    napi::threadsafe_function::ThreadsafeFunctionCallMode::NonBlocking,
-                );
-            }
+            println!("UUID: {}", paired.uuid);
 
-            Ok::<(), windows::core::Error>(())
-        });
+            notify_paired(device_name, paired)
+        }));
 
-        RUNTIME.block_on(local);
+        if let Err(e) = result {
+            notify_pairing_failed(e);
+        }
     });
 
     Ok(())
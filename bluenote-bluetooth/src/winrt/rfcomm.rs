@@ -0,0 +1,217 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use windows::{
+    core::{GUID, HSTRING},
+    Devices::{
+        Bluetooth::{
+            BluetoothCacheMode, BluetoothDevice,
+            Rfcomm::{RfcommServiceId, RfcommServiceProvider},
+        },
+        Enumeration::DeviceInformation,
+    },
+    Foundation::TypedEventHandler,
+    Networking::Sockets::{
+        SocketProtectionLevel, StreamSocket, StreamSocketListener,
+        StreamSocketListenerConnectionReceivedEventArgs,
+    },
+};
+
+use crate::{
+    error::Error,
+    sync::transport::{BoxedReader, BoxedWriter, Transport},
+    Result, UUID_BLUENOTE_RFCOMM,
+};
+
+use super::{async_reader::AsyncReader, async_writer::AsyncWriter};
+
+/// Bluetooth ペアリング済み & Bluenote の UUID をもつデバイスを列挙
+pub async fn enumerate_sync_companions() -> windows::core::Result<Vec<String>> {
+    let mut device_ids = Vec::<String>::new();
+
+    let selector = BluetoothDevice::GetDeviceSelectorFromPairingState(true)?;
+    let devices = DeviceInformation::FindAllAsyncAqsFilter(&selector)?.await?;
+
+    for d in devices {
+        println!("Device: {}", d.Name()?.to_string());
+
+        let id = d.Id()?;
+        let device = BluetoothDevice::FromIdAsync(&id)?.await?;
+        let service_id = RfcommServiceId::FromUuid(GUID::from(UUID_BLUENOTE_RFCOMM))?;
+        let rfcomm_services = device
+            .GetRfcommServicesForIdWithCacheModeAsync(&service_id, BluetoothCacheMode::Uncached)?
+            .await?
+            .Services()?;
+
+        if rfcomm_services.Size()? == 0 {
+            println!("The device seems not to have Bluenote App");
+            continue;
+        }
+
+        device_ids.push(id.to_string());
+    }
+
+    Ok(device_ids)
+}
+
+/// RFCOMM で同期の接続を受け付けるサーバ
+pub struct RfcommServer {
+    #[allow(dead_code)] // drop してサーバが落ちると困るのでリスナのインスタンスを保持しておく
+    listener: StreamSocketListener,
+    provider: RfcommServiceProvider,
+}
+
+impl RfcommServer {
+    /// 同期サービスの広告を開始し、接続があるたびに `on_connected` を呼ぶ
+    pub async fn start(on_connected: fn(RfcommTransport)) -> Result<Self> {
+        let rfcomm_service_id = RfcommServiceId::FromUuid(GUID::from(UUID_BLUENOTE_RFCOMM))?;
+        let provider = RfcommServiceProvider::CreateAsync(&rfcomm_service_id)?.await?;
+        let listener = StreamSocketListener::new()?;
+
+        listener.ConnectionReceived(&TypedEventHandler::new(
+            move |_: &Option<StreamSocketListener>,
+                  e: &Option<StreamSocketListenerConnectionReceivedEventArgs>| {
+                let socket = e.as_ref().unwrap().Socket()?;
+
+                on_connected(RfcommTransport::new(socket)?);
+
+                Ok(())
+            },
+        ))?;
+        listener
+            .BindServiceNameWithProtectionLevelAsync(
+                &provider.ServiceId()?.AsString()?,
+                SocketProtectionLevel::BluetoothEncryptionWithAuthentication, /* Androidの設定と合わせる */
+            )?
+            .await?;
+
+        provider.StartAdvertisingWithRadioDiscoverability(&listener, true)?; // 必要
+
+        Ok(Self { listener, provider })
+    }
+
+    /// 広告を停止する
+    pub fn stop(&self) -> Result<()> {
+        self.provider.StopAdvertising()?;
+        Ok(())
+    }
+}
+
+/// RFCOMM の `StreamSocket` による通信路
+pub struct RfcommTransport {
+    socket: StreamSocket,
+    reader: AsyncReader,
+    writer: AsyncWriter,
+}
+
+impl RfcommTransport {
+    /// 同期サーバに接続する
+    pub async fn connect(companion_device_id: &str) -> Result<Self> {
+        let device = BluetoothDevice::FromIdAsync(&HSTRING::from(companion_device_id))?.await?;
+        let service_id = RfcommServiceId::FromUuid(GUID::from(UUID_BLUENOTE_RFCOMM))?;
+        let rfcomm_services = device
+            .GetRfcommServicesForIdWithCacheModeAsync(&service_id, BluetoothCacheMode::Uncached)?
+            .await?
+            .Services()?;
+
+        if rfcomm_services.Size()? == 0 {
+            return Err(Error::PeerNotFound(
+                "The device seems not to have Bluenote App".to_owned(),
+            ));
+        }
+
+        println!("Connecting...");
+
+        let service = rfcomm_services.GetAt(0)?;
+        let socket = StreamSocket::new()?;
+
+        socket
+            .ConnectWithProtectionLevelAsync(
+                &service.ConnectionHostName()?,
+                &service.ConnectionServiceName()?,
+                SocketProtectionLevel::BluetoothEncryptionWithAuthentication,
+            )?
+            .await?;
+
+        println!("Connected!");
+
+        Ok(Self::new(socket)?)
+    }
+
+    /// 接続済みの `StreamSocket` から通信路を作成する
+    pub fn new(socket: StreamSocket) -> windows::core::Result<Self> {
+        let reader = AsyncReader::new(&socket.InputStream()?)?;
+        let writer = AsyncWriter::new(&socket.OutputStream()?)?;
+
+        Ok(Self {
+            socket,
+            reader,
+            writer,
+        })
+    }
+}
+
+impl Transport for RfcommTransport {
+    fn into_split(self) -> (BoxedReader, BoxedWriter) {
+        (
+            Box::new(SocketHolder {
+                inner: self.reader,
+                _socket: self.socket.clone(),
+            }),
+            Box::new(SocketHolder {
+                inner: self.writer,
+                _socket: self.socket,
+            }),
+        )
+    }
+
+    fn peer_name(&self) -> String {
+        let name = self
+            .socket
+            .Information()
+            .and_then(|info| info.RemoteHostName())
+            .and_then(|host_name| host_name.DisplayName());
+
+        match name {
+            Ok(name) => name.to_string(),
+            Err(_) => "unknown".to_owned(),
+        }
+    }
+}
+
+/// 読み書きしている間 socket が drop して接続が切れないように、インスタンスを持っておく
+struct SocketHolder<T> {
+    inner: T,
+    _socket: StreamSocket,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for SocketHolder<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for SocketHolder<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<tokio::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
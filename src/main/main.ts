@@ -1,4 +1,5 @@
 import { app, BrowserWindow, ipcMain, shell } from 'electron'
+import os from 'os'
 import path from 'path'
 import * as bluetooth from 'bluenote-bluetooth'
 import { IpcNotificationChannel, IpcInvokeChannel } from '../preload/channel'
@@ -19,7 +20,22 @@ const noteService = new NoteService(prisma)
 const syncService = new SyncService(prisma)
 const settingsService = new SettingsService(app.getPath('userData'))
 
+// Bluetooth を使わずに (LAN 内で) 初期設定するときに待ち受けるポート
+// ワンタイムのコードを持つ相手だけを受け付ける
+const INIT_TCP_PORT = 47801
+
+// 初期設定で相手に送る、この端末の情報
+const myDeviceInfo: bluetooth.MyDeviceInfo = {
+  displayName: os.hostname(),
+  appVersion: app.getVersion(),
+}
+
 const createWindow = async () => {
+  // ペアリングで共有した鍵は bluenote-bluetooth が保存する
+  bluetooth.openTrustStore(
+    path.join(app.getPath('userData'), 'trusted_devices.json')
+  )
+
   const windowProps = await settingsService.getWindowProps()
 
   const window = new BrowserWindow({
@@ -65,22 +81,35 @@ const createWindow = async () => {
     )
   })
 
-  bluetooth.setOnSyncRequested((_, uuid) => {
+  bluetooth.setOnSyncRequested((_, token, uuid) => {
     console.log('Sync requested: ' + uuid)
-    bluetooth.respondToSyncRequest(true)
+    bluetooth.respondToSyncRequest(token, true)
   })
 
-  bluetooth.setOnMyUuidRequested(async (_) => {
+  bluetooth.setOnMyUuidRequested(async (_, token) => {
     const uuid = await deviceService.getMyUuid()
-    bluetooth.respondToMyUuidRequest(uuid)
+    bluetooth.respondToMyUuidRequest(token, uuid)
   })
 
-  bluetooth.setOnNowRequested((_) => {
+  bluetooth.setOnNowRequested((_, token) => {
     console.log('Now requested')
-    bluetooth.respondToNowRequest(new Date().toUTCString())
+    bluetooth.respondToNowRequest(token, new Date().toUTCString())
   })
 
-  bluetooth.setOnThreadUpdatesRequested(async (_, uuid, updatedEnd) => {
+  // 同期相手に取り消されたリクエスト ("名前:token")
+  // 取り消されたリクエストには応答を返さなくてよい
+  const cancelledCalls = new Set<string>()
+
+  function isCancelled(name: string, token: number) {
+    return cancelledCalls.delete(`${name}:${token}`)
+  }
+
+  bluetooth.setOnSyncRequestCancelled((_, name, token) => {
+    console.log(`Sync request cancelled: ${name}, ${token}`)
+    cancelledCalls.add(`${name}:${token}`)
+  })
+
+  bluetooth.setOnThreadUpdatesRequested(async (_, token, uuid, updatedEnd) => {
     console.log(`Thread updates requested: ${uuid}, ${updatedEnd}`)
 
     const companion = await deviceService.find(uuid)
@@ -94,28 +123,34 @@ const createWindow = async () => {
       new Date(updatedEnd)
     )
 
+    if (isCancelled('thread_updates', token)) return
+
     const json = JSON.stringify(updated)
-    bluetooth.respondToThreadUpdatesRequest(json)
+    bluetooth.respondToThreadUpdatesRequest(token, json)
   })
 
-  bluetooth.setOnAllNotesInThreadRequested(async (_, threadId) => {
+  bluetooth.setOnAllNotesInThreadRequested(async (_, token, threadId) => {
     console.log('all notes in thread requested')
 
     const threads = await syncService.getAllNotesInThread(threadId)
+    if (isCancelled('all_notes_in_thread', token)) return
+
     const json = JSON.stringify(threads)
-    bluetooth.respondToAllNotesInThreadRequest(json)
+    bluetooth.respondToAllNotesInThreadRequest(token, json)
   })
 
-  bluetooth.setOnAllNotesInTreeRequested(async (_, parentId) => {
+  bluetooth.setOnAllNotesInTreeRequested(async (_, token, parentId) => {
     console.log('All notes in tree requested')
 
     const threads = await syncService.getAllNotesInTree(parentId)
+    if (isCancelled('all_notes_in_tree', token)) return
+
     const json = JSON.stringify(threads)
-    bluetooth.respondToAllNotesInTreeRequest(json)
+    bluetooth.respondToAllNotesInTreeRequest(token, json)
   })
 
   bluetooth.setOnNoteUpdatesInThreadRequested(
-    async (_, uuid, threadId, updatedEnd) => {
+    async (_, token, uuid, threadId, updatedEnd) => {
       console.log('Note updates in thread requested')
 
       const companion = await deviceService.find(uuid)
@@ -130,13 +165,15 @@ const createWindow = async () => {
         new Date(updatedEnd)
       )
 
+      if (isCancelled('note_updates_in_thread', token)) return
+
       const json = JSON.stringify(updated)
-      bluetooth.respondToNoteUpdatesInThreadRequest(json)
+      bluetooth.respondToNoteUpdatesInThreadRequest(token, json)
     }
   )
 
   bluetooth.setOnNoteUpdatesInTreeRequested(
-    async (_, uuid, parentId, updatedEnd) => {
+    async (_, token, uuid, parentId, updatedEnd) => {
       console.log('Note updates in tree requested')
 
       const companion = await deviceService.find(uuid)
@@ -151,12 +188,14 @@ const createWindow = async () => {
         new Date(updatedEnd)
       )
 
+      if (isCancelled('note_updates_in_tree', token)) return
+
       const json = JSON.stringify(updated)
-      bluetooth.respondToNoteUpdatesInTreeRequest(json)
+      bluetooth.respondToNoteUpdatesInTreeRequest(token, json)
     }
   )
 
-  bluetooth.setOnUpdateSyncedAtRequested(async (_, uuid, updatedEnd) => {
+  bluetooth.setOnUpdateSyncedAtRequested(async (_, token, uuid, updatedEnd) => {
     console.log('Update synced at requested')
 
     const companion = await deviceService.find(uuid)
@@ -167,11 +206,17 @@ const createWindow = async () => {
 
     await deviceService.updateSyncedAt(companion, new Date(updatedEnd))
 
-    bluetooth.respondToUpdateSyncedAtRequest()
+    bluetooth.respondToUpdateSyncedAtRequest(token)
   })
 
-  bluetooth.setOnUuidExchanged(async (_, name, uuid) => {
-    await deviceService.enableSyncWith(uuid, name)
+  bluetooth.setOnUuidExchanged(async (err, name, uuid, fingerprint, hello) => {
+    if (err) {
+      console.error('Pairing failed:', err)
+      return
+    }
+
+    console.log(`Paired with ${name}: ${fingerprint ?? 'no key'}`, hello)
+    await deviceService.enableSyncWith(uuid, hello?.displayName || name)
   })
 
   bluetooth.setOnScanStateChanged((_, isScanning) => {
@@ -204,25 +249,72 @@ const createWindow = async () => {
       _: Electron.IpcMainInvokeEvent,
       deviceUuid: string
     ) => {
+      // 再びペアリングするまで、相手からの同期も受け付けない
+      bluetooth.revokeDevice(deviceUuid)
       await deviceService.disableSyncWith(deviceUuid)
     },
+    [IpcInvokeChannel.GetTrustedDevices]: () => {
+      return bluetooth.listTrustedDevices()
+    },
     [IpcInvokeChannel.InitSync]: async (
       _: Electron.IpcMainInvokeEvent,
       windowsDeviceId: string
     ) => {
       console.log('request: ' + windowsDeviceId)
       const myUuid = await deviceService.getMyUuid()
-      const uuid = await bluetooth.initClient(windowsDeviceId, myUuid)
-      console.log(`Exchanged UUID: ${uuid}`)
+      const { uuid, fingerprint, hello } = await bluetooth.initClient(
+        windowsDeviceId,
+        myUuid,
+        myDeviceInfo
+      )
+      console.log(`Exchanged UUID: ${uuid} (${fingerprint ?? 'no key'})`, hello)
 
-      await deviceService.enableSyncWith(uuid, 'TODO: READABLE NAME')
+      await deviceService.enableSyncWith(
+        uuid,
+        hello?.displayName || 'TODO: READABLE NAME'
+      )
     },
     [IpcInvokeChannel.StartInitServer]: async () => {
-      bluetooth.startInitServer(await deviceService.getMyUuid())
+      bluetooth.startInitServer(await deviceService.getMyUuid(), myDeviceInfo)
     },
     [IpcInvokeChannel.StopInitServer]: () => {
       bluetooth.stopInitServer()
     },
+    [IpcInvokeChannel.CreatePairingCode]: async () => {
+      await bluetooth.startTcpInitServer(
+        `0.0.0.0:${INIT_TCP_PORT}`,
+        await deviceService.getMyUuid(),
+        myDeviceInfo
+      )
+
+      // 相手に入力してもらう (QR コードにする) この端末のアドレス
+      const addresses = Object.values(os.networkInterfaces())
+        .flatMap((infos) => infos ?? [])
+        .filter((info) => info.family === 'IPv4' && !info.internal)
+        .map((info) => `${info.address}:${INIT_TCP_PORT}`)
+
+      return { ...bluetooth.createPairingToken(), addresses }
+    },
+    [IpcInvokeChannel.CancelPairingCode]: () => {
+      bluetooth.cancelPairingToken()
+      bluetooth.stopTcpInitServer()
+    },
+    [IpcInvokeChannel.InitSyncWithCode]: async (
+      _: Electron.IpcMainInvokeEvent,
+      address: string,
+      code: string
+    ) => {
+      const myUuid = await deviceService.getMyUuid()
+      const { uuid, fingerprint, hello } = await bluetooth.initTcpClient(
+        address,
+        myUuid,
+        code,
+        myDeviceInfo
+      )
+      console.log(`Exchanged UUID: ${uuid} (${fingerprint ?? 'no key'})`, hello)
+
+      await deviceService.enableSyncWith(uuid, hello?.displayName || address)
+    },
     [IpcInvokeChannel.RespondToBondRequest]: (
       _: Electron.IpcMainInvokeEvent,
       accept: boolean
@@ -365,6 +457,10 @@ const createWindow = async () => {
       await sync()
     },
 
+    [IpcInvokeChannel.CancelSync]: async () => {
+      syncAbortController?.abort()
+    },
+
     [IpcInvokeChannel.GetSettings]: async () => {
       return await settingsService.getSettings()
     },
@@ -383,9 +479,28 @@ const createWindow = async () => {
     ipcMain.handle(channel, IpcHandlers[channel])
   }
 
+  // 実行中の同期を中断するためのもの
+  let syncAbortController: AbortController | null = null
+
   async function sync() {
     // 対象のデバイスに接続し、プロトコルのバージョンや同期の許可のチェックを行う
 
+    const controller = new AbortController()
+    syncAbortController = controller
+
+    try {
+      await syncWithCompanions(controller.signal)
+    } catch (e) {
+      // 中断された場合はエラーにしない
+      if (!controller.signal.aborted) throw e
+    } finally {
+      syncAbortController = null
+    }
+
+    console.log(controller.signal.aborted ? 'sync cancelled.' : 'sync finished.')
+  }
+
+  async function syncWithCompanions(signal: AbortSignal) {
     const deviceIds = await bluetooth.enumerateSyncCompanions()
     const myUuid = await deviceService.getMyUuid()
     const syncEnabledUuids = (
@@ -393,13 +508,15 @@ const createWindow = async () => {
     ).map((x) => x.id)
 
     for (const deviceId of deviceIds) {
+      if (signal.aborted) return
+
       const syncClient = bluetooth.SyncClient.createInstance(myUuid, deviceId)
       let success = false
 
       try {
         await syncClient.beginSync(syncEnabledUuids)
 
-        const companion = new SyncCompanion(syncClient)
+        const companion = new SyncCompanion(syncClient, signal)
         const d = await diff(companion, threadService, noteService, new Date())
 
         console.log('diff', d)
@@ -411,8 +528,6 @@ const createWindow = async () => {
         await syncClient.endSync(success)
       }
     }
-
-    console.log('sync finished.')
   }
 
   // データ同期サーバ起動
@@ -431,7 +546,8 @@ app.whenReady().then(async () => {
 
 app.on('before-quit', async () => {
   console.log('quitting...')
-  bluetooth.stopSyncServer()
+  // 終了時は同期中のセッションも切断する
+  await bluetooth.stopSyncServer(true)
   await prisma.$disconnect()
 })
 
@@ -1,6 +1,6 @@
 import { Thread } from '../../common/thread'
 import { Note } from '../../common/note'
-import { SyncClient } from 'bluenote-bluetooth'
+import { SyncClient, SyncResponseStream } from 'bluenote-bluetooth'
 
 function toThread(
   fromJson: Omit<Thread, 'updatedAt' | 'createdAt' | 'modifiedAt'> & {
@@ -32,6 +32,23 @@ function toNote(
   }
 }
 
+// チャンクごとに受信したメモを変換してつなげる
+// 大きなスレッドでも、JSON 全体を一つの文字列として受け取らずに済む
+async function readNotes(
+  stream: SyncResponseStream,
+  signal?: AbortSignal
+): Promise<Note[]> {
+  const notes: Note[] = []
+
+  let json: string | null
+
+  while ((json = await stream.next(signal)) !== null) {
+    notes.push(...JSON.parse(json).map((x: any) => toNote(x)))
+  }
+
+  return notes
+}
+
 export interface ISyncCompanion {
   // スレッドの更新状況を取得
   getThreadUpdates(): Promise<Thread[]>
@@ -51,33 +68,46 @@ export interface ISyncCompanion {
 
 export class SyncCompanion implements ISyncCompanion {
   private readonly syncClient: SyncClient
+  // 中断されたら、実行中のリクエストを取り消す
+  private readonly signal?: AbortSignal
 
-  constructor(syncClient: SyncClient) {
+  constructor(syncClient: SyncClient, signal?: AbortSignal) {
     this.syncClient = syncClient
+    this.signal = signal
   }
 
   public async getThreadUpdates(): Promise<Thread[]> {
-    const json = await this.syncClient.requestData(0)
+    const json = await this.syncClient.getThreadUpdates(this.signal)
     return JSON.parse(json).map((x: any) => toThread(x))
   }
 
   public async getAllNotesInThread(thread: Thread): Promise<Note[]> {
-    const json = await this.syncClient.requestData(1, thread.id)
-    return JSON.parse(json).map((x: any) => toNote(x))
+    return readNotes(
+      await this.syncClient.streamAllNotesInThread(thread.id, this.signal),
+      this.signal
+    )
   }
 
   public async getAllNotesInNote(note: Note): Promise<Note[]> {
-    const json = await this.syncClient.requestData(2, note.id)
-    return JSON.parse(json).map((x: any) => toNote(x))
+    return readNotes(
+      await this.syncClient.streamAllNotesInTree(note.id, this.signal),
+      this.signal
+    )
   }
 
   public async getNoteUpdatesInThread(thread: Thread): Promise<Note[]> {
-    const json = await this.syncClient.requestData(3, thread.id)
+    const json = await this.syncClient.getNoteUpdatesInThread(
+      thread.id,
+      this.signal
+    )
     return JSON.parse(json).map((x: any) => toNote(x))
   }
 
   public async getNoteUpdatesInTree(note: Note): Promise<Note[]> {
-    const json = await this.syncClient.requestData(4, note.id)
+    const json = await this.syncClient.getNoteUpdatesInTree(
+      note.id,
+      this.signal
+    )
     return JSON.parse(json).map((x: any) => toNote(x))
   }
 }
@@ -1,6 +1,7 @@
 import { ipcRenderer } from 'electron'
 import { IpcInvokeChannel } from './channel'
 import { Device, Note, Thread } from '@prisma/client'
+import type { TrustedDeviceInfo } from 'bluenote-bluetooth'
 import {
   NoteWithChildrenCount,
   NoteWithThreadName,
@@ -138,12 +139,26 @@ export const api = {
     await ipcRenderer.invoke(IpcInvokeChannel.DisableSync, deviceUuid)
   },
 
+  /**
+   * ペアリングした端末の一覧 (鍵の指紋を含む)
+   */
+  async getTrustedDevices(): Promise<TrustedDeviceInfo[]> {
+    return await ipcRenderer.invoke(IpcInvokeChannel.GetTrustedDevices)
+  },
+
   /**
    * 同期を開始する
    */
   async sync() {
     await ipcRenderer.invoke(IpcInvokeChannel.Sync)
   },
+
+  /**
+   * 実行中の同期を中断する
+   */
+  async cancelSync() {
+    await ipcRenderer.invoke(IpcInvokeChannel.CancelSync)
+  },
 }
 
 export type Api = typeof api
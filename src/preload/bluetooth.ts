@@ -1,4 +1,5 @@
 import { ipcRenderer } from 'electron'
+import type { PairingTokenInfo } from 'bluenote-bluetooth'
 import { IpcNotificationChannel, IpcInvokeChannel } from './channel'
 
 type BluetoothDevice = {
@@ -70,6 +71,26 @@ export const bluetooth = {
     ipcRenderer.invoke(IpcInvokeChannel.StopInitServer)
   },
 
+  /**
+   * Bluetooth を使わずに初期設定するためのワンタイムのコードを発行し、LAN 内からの接続を受け付ける
+   * コードは、最初に鍵の交換を始めた接続で使い切る
+   */
+  async createPairingCode(): Promise<
+    PairingTokenInfo & { addresses: string[] }
+  > {
+    return await ipcRenderer.invoke(IpcInvokeChannel.CreatePairingCode)
+  },
+  cancelPairingCode() {
+    ipcRenderer.invoke(IpcInvokeChannel.CancelPairingCode)
+  },
+  /**
+   * 相手の端末が表示したコードを使って、LAN 内の相手と初期設定する
+   * @param address 相手のアドレス (例: `192.168.0.10:47801`)
+   */
+  async initSyncWithCode(address: string, code: string) {
+    await ipcRenderer.invoke(IpcInvokeChannel.InitSyncWithCode, address, code)
+  },
+
   addOnInitServerStateChanged(callback: OnInitServerStateChanged) {
     callbacksInitServerStateChanged.push(callback)
   },
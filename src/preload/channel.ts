@@ -18,13 +18,18 @@ const _NewIpcChannel = {
   InitSync: 'init-sync',
   StartInitServer: 'start-init-server',
   StopInitServer: 'stop-init-server',
+  CreatePairingCode: 'create-pairing-code',
+  CancelPairingCode: 'cancel-pairing-code',
+  InitSyncWithCode: 'init-sync-with-code',
 
   // sync
   Sync: 'sync',
+  CancelSync: 'cancel-sync',
 
   // device
   GetSyncEnabledDevices: 'get-sync-enabled-devices',
   DisableSync: 'disable-sync',
+  GetTrustedDevices: 'get-trusted-devices',
 
   // thread
   GetAllThreads: 'get-all-threads',
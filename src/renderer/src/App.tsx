@@ -82,7 +82,11 @@ function App() {
   }
 
   async function onSyncClicked() {
-    if (isSyncing) return
+    // 同期中に押されたら同期を中断する
+    if (isSyncing) {
+      await window.api.cancelSync()
+      return
+    }
 
     try {
       setIsSyncing(true)
@@ -144,7 +148,6 @@ function App() {
         <button
           className="electron-no-drag [&:not([disabled])]:hover:dark:bg-midnight-800 rounded-md p-2"
           onClick={onSyncClicked}
-          disabled={isSyncing}
         >
           <SyncIcon isRotating={isSyncing} />
         </button>
@@ -51,6 +51,27 @@ function useInitServerEffect(
   }, [isRunning])
 }
 
+function usePairingCodeEffect(
+  isIssued: boolean,
+  onIssued: (pairingCode: {
+    code: string
+    expiresAt: number
+    addresses: string[]
+  }) => void
+) {
+  useEffect(() => {
+    if (!isIssued) {
+      return
+    }
+
+    window.bluetooth.createPairingCode().then(onIssued)
+
+    return () => {
+      window.bluetooth.cancelPairingCode()
+    }
+  }, [isIssued])
+}
+
 function Button({
   children,
   onClick,
@@ -183,12 +204,24 @@ function Appearance() {
 function DataSync({ onAddSyncDevice }: { onAddSyncDevice: () => void }) {
   const [hasErrorOccured, setHasErrorOccured] = useState(false)
   const [devices, setDevices] = useState<Device[]>([])
+  // 端末の UUID ごとの、ペアリングで共有した鍵の指紋
+  const [fingerprints, setFingerprints] = useState<Map<string, string>>(
+    new Map()
+  )
 
   useEffect(() => {
     async function getSyncDevices() {
       try {
         const devices = await window.api.getSyncEnabledDevices()
+        const trustedDevices = await window.api.getTrustedDevices()
         setDevices(devices)
+        setFingerprints(
+          new Map(
+            trustedDevices.flatMap((d) =>
+              d.fingerprint != null ? [[d.uuid, d.fingerprint]] : []
+            )
+          )
+        )
       } catch (e) {
         setHasErrorOccured(true)
       }
@@ -250,6 +283,11 @@ function DataSync({ onAddSyncDevice }: { onAddSyncDevice: () => void }) {
             onDeleteClick={() => onDeviceDeleteClicked(device)}
           >
             {device.name}
+            {fingerprints.has(device.id) && (
+              <span className="ml-2 font-mono text-xs opacity-60">
+                {fingerprints.get(device.id)}
+              </span>
+            )}
           </DeviceListItemWithDelete>
         ))}
       </DeviceList>
@@ -269,6 +307,14 @@ function AddSyncDevice() {
     }[]
   >([])
   const [hasErrorOccured, setHasErrorOccured] = useState(false)
+  const [isPairingCodeIssued, setIsPairingCodeIssued] = useState(false)
+  const [pairingCode, setPairingCode] = useState<{
+    code: string
+    expiresAt: number
+    addresses: string[]
+  } | null>(null)
+  const [companionAddress, setCompanionAddress] = useState('')
+  const [companionCode, setCompanionCode] = useState('')
   const [bondState, setBondState] = useState<{
     resolve: (accept: boolean) => void
     reject: () => void
@@ -299,6 +345,15 @@ function AddSyncDevice() {
     }
   }
 
+  async function onInitSyncWithCode() {
+    try {
+      await window.bluetooth.initSyncWithCode(companionAddress, companionCode)
+      setCompanionCode('')
+    } catch (e) {
+      setHasErrorOccured(true)
+    }
+  }
+
   function onBondRequested(deviceName: string, pin: string): Promise<boolean> {
     // 前回のリクエストが残っている場合は reject
     if (bondState != null) {
@@ -321,6 +376,7 @@ function AddSyncDevice() {
 
   useBluetoothScanEffect(blState.isScanning, onScanStateChanged, onDeviceFound)
   useInitServerEffect(blState.isInitServerRunning, onInitServerStateChanged)
+  usePairingCodeEffect(isPairingCodeIssued, setPairingCode)
   useEffect(() => {
     window.bluetooth.setOnBondRequested(onBondRequested)
     return () => {
@@ -348,6 +404,62 @@ function AddSyncDevice() {
         </Button>
       </SettingsItem>
 
+      <SettingsItem
+        title="コードで受付"
+        description={
+          <>
+            Bluetooth を使わずに、同じネットワークのデバイスから同期設定のリクエストを受け付けます。
+            <br />
+            相手のデバイスで、以下のアドレスと 1 回限りのコードを入力してください。
+            {isPairingCodeIssued && pairingCode != null && (
+              <>
+                <br />
+                {pairingCode.addresses.join(', ')}
+                <br />
+                <span className="font-mono text-base">
+                  {pairingCode.code.slice(0, 4)} {pairingCode.code.slice(4)}
+                </span>
+                （{new Date(pairingCode.expiresAt).toLocaleTimeString()}
+                まで）
+              </>
+            )}
+          </>
+        }
+      >
+        <Button
+          onClick={() => {
+            setPairingCode(null)
+            setIsPairingCodeIssued(!isPairingCodeIssued)
+          }}
+        >
+          コード{isPairingCodeIssued ? '取消' : '発行'}
+        </Button>
+      </SettingsItem>
+
+      <SettingsItem
+        title="コードで追加"
+        description={
+          <>
+            相手のデバイスに表示されたアドレスとコードを入力してください。
+            <br />
+            <input
+              className="dark:bg-midnight-700 mr-2 mt-1 w-40 rounded px-2"
+              placeholder="192.168.0.10:47801"
+              value={companionAddress}
+              onChange={(e) => setCompanionAddress(e.target.value)}
+            />
+            <input
+              className="dark:bg-midnight-700 mt-1 w-28 rounded px-2"
+              placeholder="1234 5678"
+              value={companionCode}
+              onChange={(e) => setCompanionCode(e.target.value)}
+            />
+          </>
+        }
+      >
+        <Button onClick={onInitSyncWithCode}>追加</Button>
+      </SettingsItem>
+
       <SettingsItem
         title="デバイスのスキャン"
         description={